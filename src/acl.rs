@@ -0,0 +1,89 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+
+/*
+    POSIX ACLs are stored by the kernel as extended attributes, so they're copied as-is,
+    without needing to parse them, eg: with `getfacl`/`setfacl`
+*/
+
+// The access ACL applies to files and directories, the default ACL is inherited by new entries
+const ACL_XATTR_NAMES: [&str; 2] = ["system.posix_acl_access", "system.posix_acl_default"];
+
+pub fn copy_acls(source_path: &Path, target_path: &Path) -> Result<()> {
+    for acl_name in ACL_XATTR_NAMES {
+        let acl = xattr::get(source_path, acl_name)
+            .with_context(|| format!("failed to read ACLs of {source_path:?}"))?;
+
+        if let Some(acl) = acl {
+            xattr::set(target_path, acl_name, &acl)
+                .with_context(|| format!("failed to write ACLs to {target_path:?}"))?;
+        }
+    }
+
+    Ok(())
+}
+
+// Missing ACL support on either side shouldn't fail the snapshot
+pub fn copy_acls_or_warn(source_path: &Path, target_path: &Path) {
+    if let Err(e) = copy_acls(source_path, target_path) {
+        log::warn!("Skipping ACLs: {e:#}");
+    }
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    // A minimal access ACL in the kernel's format, granting "user:1000:r--"
+    fn create_test_acl() -> Vec<u8> {
+        let entries: [(u16, u16, u32); 5] = [
+            (0x01, 6, u32::MAX), // user::rw-
+            (0x02, 4, 1000),     // user:1000:r--
+            (0x04, 4, u32::MAX), // group::r--
+            (0x10, 4, u32::MAX), // mask::r--
+            (0x20, 4, u32::MAX), // other::r--
+        ];
+
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            acl.extend(tag.to_le_bytes());
+            acl.extend(perm.to_le_bytes());
+            acl.extend(id.to_le_bytes());
+        }
+        acl
+    }
+
+    #[test]
+    fn test_copy_acls() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&test_path)?;
+        fs::write(test_path.join("source"), "foo")?;
+        fs::write(test_path.join("target"), "foo")?;
+
+        // Not every filesystem supports ACLs, and there's nothing to test without them
+        if xattr::set(
+            test_path.join("source"),
+            ACL_XATTR_NAMES[0],
+            &create_test_acl(),
+        )
+        .is_err()
+        {
+            fs::remove_dir_all(&test_path)?;
+            return Ok(());
+        }
+
+        let copy_result = copy_acls(&test_path.join("source"), &test_path.join("target"));
+        let target_acl = xattr::get(test_path.join("target"), ACL_XATTR_NAMES[0]);
+        fs::remove_dir_all(&test_path)?;
+
+        copy_result?;
+        assert_eq!(target_acl?, Some(create_test_acl()));
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use anyhow::{Context, Result};
+use flate2::read::MultiGzDecoder;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+use crate::volume;
+
+/*
+    Reading Tarball snapshots back, eg: to digest them. The compression is detected from each
+    tarball's leading bytes, rather than assumed from its `tarball_extension`.
+*/
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+// An uncompressed tar has no magic at its start, only in its first header
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+#[derive(Debug, PartialEq)]
+enum ArchiveCompression {
+    Gzip,
+    None,
+}
+
+pub fn open_tarball(snapshot_path: &Path) -> Result<tar::Archive<Box<dyn Read>>> {
+    let mut reader = open_tarball_parts(snapshot_path)?;
+
+    // Read ahead far enough to see a tar header, then put those bytes back in front
+    let mut leading_bytes = vec![];
+    (&mut reader)
+        .take((TAR_MAGIC_OFFSET + TAR_MAGIC.len()) as u64)
+        .read_to_end(&mut leading_bytes)
+        .with_context(|| format!("failed to read tarball {snapshot_path:?}"))?;
+    let compression = detect_compression(&leading_bytes)
+        .with_context(|| format!("failed to read tarball {snapshot_path:?}"))?;
+    log::debug!("Reading {snapshot_path:?} as {compression:?} compressed");
+
+    let reader: Box<dyn Read> = Box::new(Cursor::new(leading_bytes).chain(reader));
+    Ok(tar::Archive::new(match compression {
+        // Several gzip members, eg: after `compression_time_budget` lowered the level partway
+        ArchiveCompression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        ArchiveCompression::None => reader,
+    }))
+}
+
+fn detect_compression(leading_bytes: &[u8]) -> Result<ArchiveCompression> {
+    if leading_bytes.starts_with(GZIP_MAGIC) {
+        Ok(ArchiveCompression::Gzip)
+    } else if leading_bytes.get(TAR_MAGIC_OFFSET..) == Some(TAR_MAGIC) {
+        Ok(ArchiveCompression::None)
+    } else if leading_bytes.starts_with(ZSTD_MAGIC) {
+        anyhow::bail!("zstd compression isn't supported by this build")
+    } else {
+        anyhow::bail!("unrecognised compression, expected gzip or an uncompressed tar")
+    }
+}
+
+// A split tarball is read as one stream, from each of its volumes in turn
+fn open_tarball_parts(snapshot_path: &Path) -> Result<Box<dyn Read>> {
+    let mut reader: Box<dyn Read> = Box::new(io::empty());
+    for snapshot_part in volume::get_snapshot_parts(snapshot_path) {
+        let part_file = fs::File::open(&snapshot_part)
+            .with_context(|| format!("failed to read {snapshot_part:?}"))?;
+        reader = Box::new(reader.chain(part_file));
+    }
+
+    Ok(reader)
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+
+    #[test]
+    fn test_open_tarball_detects_compression() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&test_path)?;
+
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_ustar();
+        header.set_size(3);
+        header.set_cksum();
+        builder.append_data(&mut header, "foo", &b"foo"[..])?;
+        let tar_bytes = builder.into_inner()?;
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&tar_bytes)?;
+        let gzip_bytes = encoder.finish()?;
+
+        let mut entry_paths = vec![];
+        for (name, bytes) in [("plain.tar", &tar_bytes), ("gzipped.tgz", &gzip_bytes)] {
+            fs::write(test_path.join(name), bytes)?;
+            for entry in open_tarball(&test_path.join(name))?.entries()? {
+                entry_paths.push(entry?.path()?.display().to_string());
+            }
+        }
+        fs::write(test_path.join("zstd.tzst"), [ZSTD_MAGIC, &[0; 16]].concat())?;
+        let zstd_result = open_tarball(&test_path.join("zstd.tzst"));
+
+        fs::remove_dir_all(&test_path)?;
+        assert_eq!(entry_paths, vec!["foo", "foo"]);
+        assert!(zstd_result.is_err_and(|e| format!("{e:#}").contains("zstd")));
+        Ok(())
+    }
+}
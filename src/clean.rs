@@ -1,75 +1,279 @@
 use anyhow::{Context, Result};
 use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
 
 use crate::PirouetteDirEntry;
 use crate::PirouetteRetentionTarget;
+use crate::audit;
 use crate::configuration::Config;
+use crate::configuration::ConfigOptsCleanupOrder;
+use crate::configuration::ConfigOptsThinning;
+use crate::configuration::ConfigRetentionLimit;
 use crate::dry_run;
+use crate::index;
+use crate::label;
+use crate::layout;
+use crate::promote;
+use crate::volume;
+
+// Returns the paths of every snapshot part deleted, or which would have been in a dry run.
+// With a `promotion_target`, some expired snapshots may be promoted into it instead.
+pub fn clean_snapshots(
+    config: &Config,
+    retention_target: &PirouetteRetentionTarget,
+    promotion_target: Option<&PirouetteRetentionTarget>,
+) -> Result<Vec<PathBuf>> {
+    if config.options.append_only {
+        log::info!("Cleanup is disabled by append_only, keeping every {retention_target} snapshot");
+        return Ok(vec![]);
+    }
 
-pub fn clean_snapshots(config: &Config, retention_target: &PirouetteRetentionTarget) -> Result<()> {
     log::info!(
         "Checking {:?} for expired snapshots",
         retention_target.period
     );
-    let entries = get_directory_entries(retention_target);
+    let entries = with_cleanup_times(
+        config,
+        get_unlabelled_entries(layout::read_snapshot_entries(retention_target)?),
+    );
 
     let current_snapshot_count = entries.len();
     log::info!(
         "Currently {current_snapshot_count} snapshots, want to keep {}",
-        retention_target.max_count
+        retention_target.limit
     );
 
+    let (expired_snapshot_count, thinning) = match retention_target.limit {
+        ConfigRetentionLimit::Count(max_count) => (
+            current_snapshot_count.saturating_sub(max_count),
+            &config.options.thinning,
+        ),
+        // A size budget can only be met by removing the oldest (and so usually largest) first
+        ConfigRetentionLimit::Size(max_size) => (
+            get_oversize_snapshot_count(&entries, max_size),
+            &ConfigOptsThinning::Truncate,
+        ),
+    };
+
     // Are we under the configured retention threshold?
-    if current_snapshot_count <= retention_target.max_count {
-        return Ok(());
+    if expired_snapshot_count == 0 {
+        return Ok(vec![]);
     }
 
-    // If so, we need to delete the excess
-    let expired_snapshot_count = current_snapshot_count - retention_target.max_count;
+    // If not, we need to delete the excess
     log::info!("Deleting {expired_snapshot_count} expired snapshots");
 
-    if let Ok(expired_snapshots) = get_expired_snapshots(entries, expired_snapshot_count) {
+    if let Ok(expired_snapshots) = get_expired_snapshots(entries, expired_snapshot_count, thinning)
+    {
+        let expired_count = expired_snapshots.len();
+        let (expired_snapshots, is_promotion_dry_run) = match promotion_target {
+            Some(promotion_target) => (
+                promote::promote_snapshots(
+                    config,
+                    retention_target,
+                    promotion_target,
+                    expired_snapshots,
+                )?,
+                promotion_target.dry_run,
+            ),
+            None => (expired_snapshots, false),
+        };
+        // Each deleted snapshot leaves this period, but a promoted one only if it was really moved
+        let promoted_count = match is_promotion_dry_run {
+            true => 0,
+            false => expired_count - expired_snapshots.len(),
+        };
+        let expected_count = current_snapshot_count - expired_snapshots.len() - promoted_count;
+        let expired_paths = expired_snapshots
+            .iter()
+            .flat_map(|snapshot| volume::get_snapshot_parts(&snapshot.path))
+            .collect();
         dry_run!(
-            config.options.dry_run,
+            retention_target.dry_run,
             format!("snapshots will not be deleted"),
             {
-                delete_snapshots(expired_snapshots);
-                // This function doesn't fail, but dry_run!() expects a Result<>
-                Ok::<(), anyhow::Error>(())
+                delete_snapshots(config, expired_snapshots);
+                match config.options.verify_cleanup {
+                    true => check_cleaned_snapshot_count(retention_target, expected_count),
+                    false => Ok(()),
+                }
             }
-        )
+        )?;
+        Ok(expired_paths)
     } else {
         log::warn!("Failed to calculate expired snapshots");
-        Ok(())
+        Ok(vec![])
     }
 }
 
-fn get_directory_entries(target: &PirouetteRetentionTarget) -> Vec<PirouetteDirEntry> {
-    let entries = match fs::read_dir(&target.path) {
-        Ok(entries) => entries,
-        Err(_) => {
-            log::warn!("failed to read {:?} contents", &target.path);
-            return vec![];
-        }
+// With `verify_cleanup`, re-read the period after cleanup, since `delete_snapshots` only logs
+// its errors, eg: a snapshot which couldn't be deleted would otherwise go unnoticed
+fn check_cleaned_snapshot_count(
+    retention_target: &PirouetteRetentionTarget,
+    expected_count: usize,
+) -> Result<()> {
+    let remaining_count = layout::read_snapshot_entries(retention_target)?
+        .iter()
+        .filter(|entry| !label::is_labelled(&entry.path))
+        .count();
+
+    if remaining_count > expected_count {
+        anyhow::bail!(
+            "{retention_target} still has {remaining_count} snapshots after cleanup, expected {expected_count}, so some couldn't be deleted"
+        );
+    }
+    Ok(())
+}
+
+// With `clean_before_snapshot`, make room for the next snapshot first, by keeping one fewer.
+// At least one snapshot is always kept, so a period is never emptied before its replacement exists.
+// Size limits are left until afterwards, since the new snapshot's size isn't known yet.
+pub fn clean_before_snapshot(
+    config: &Config,
+    retention_target: &PirouetteRetentionTarget,
+    promotion_target: Option<&PirouetteRetentionTarget>,
+) -> Result<Vec<PathBuf>> {
+    let ConfigRetentionLimit::Count(max_count) = retention_target.limit else {
+        return Ok(vec![]);
     };
 
-    // Convert to abstracted testable type
+    let mut reduced_target = retention_target.clone();
+    reduced_target.limit = ConfigRetentionLimit::Count(max_count.saturating_sub(1).max(1));
+    log::info!("Making room for a new {retention_target} snapshot before taking it");
+    clean_snapshots(config, &reduced_target, promotion_target)
+}
+
+// Every snapshot's age is judged by `cleanup_order`, for both the count and size limits
+fn with_cleanup_times(config: &Config, entries: Vec<PirouetteDirEntry>) -> Vec<PirouetteDirEntry> {
     entries
-        .filter_map(|entry| entry.ok())
-        .map(|entry| entry.into())
+        .into_iter()
+        .map(|mut entry| {
+            entry.timestamp = get_cleanup_time(&config.options.cleanup_order, &entry);
+            entry
+        })
         .collect()
 }
 
+// The time in a snapshot's name survives it being copied or touched, unlike its mtime or ctime.
+// A name without a time falls back to the snapshot's mtime, as does an unreadable ctime.
+fn get_cleanup_time(
+    cleanup_order: &ConfigOptsCleanupOrder,
+    entry: &PirouetteDirEntry,
+) -> SystemTime {
+    match cleanup_order {
+        ConfigOptsCleanupOrder::Name => {
+            layout::parse_snapshot_name_time(&entry.path).unwrap_or(entry.timestamp)
+        }
+        ConfigOptsCleanupOrder::Mtime => entry.timestamp,
+        ConfigOptsCleanupOrder::Ctime => fs::symlink_metadata(&entry.path)
+            .map(|metadata| {
+                SystemTime::UNIX_EPOCH
+                    + Duration::new(metadata.ctime() as u64, metadata.ctime_nsec() as u32)
+            })
+            .unwrap_or(entry.timestamp),
+    }
+}
+
+// Labelled snapshots are neither counted towards the limit, nor deleted
+pub fn get_unlabelled_entries(entries: Vec<PirouetteDirEntry>) -> Vec<PirouetteDirEntry> {
+    let (labelled_entries, unlabelled_entries): (Vec<_>, Vec<_>) = entries
+        .into_iter()
+        .partition(|entry| label::is_labelled(&entry.path));
+
+    for entry in &labelled_entries {
+        match label::read_label(&entry.path) {
+            Ok(label) => log::info!("Keeping {entry}, labelled {label:?}"),
+            Err(e) => log::warn!("Keeping {entry}, which has an unreadable label: {e:#}"),
+        }
+    }
+
+    unlabelled_entries
+}
+
+// How many of the oldest snapshots must go for the rest to fit within `max_size` bytes.
+// The newest snapshot is always kept, even if it alone exceeds the budget.
+fn get_oversize_snapshot_count(entries: &[PirouetteDirEntry], max_size: u64) -> usize {
+    let mut sorted_entries: Vec<_> = entries.iter().collect();
+    sorted_entries.sort_by_key(|entry| entry.timestamp);
+
+    let sizes: Vec<u64> = sorted_entries
+        .iter()
+        .map(|entry| get_snapshot_size(&entry.path))
+        .collect();
+    let mut total_size: u64 = sizes.iter().sum();
+    log::info!("Currently using {total_size} bytes, want to keep {max_size} bytes");
+
+    let mut count = 0;
+    for size in sizes.iter().take(sizes.len().saturating_sub(1)) {
+        if total_size <= max_size {
+            break;
+        }
+        total_size -= size;
+        count += 1;
+    }
+
+    if total_size > max_size {
+        log::warn!("The newest snapshot alone exceeds the size limit of {max_size} bytes");
+    }
+
+    count
+}
+
+// Total size of a snapshot, whether it's a single tarball or a directory
+pub fn get_snapshot_size(path: &Path) -> u64 {
+    volume::get_snapshot_parts(path)
+        .iter()
+        .flat_map(WalkDir::new)
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
 fn get_expired_snapshots(
     entries: Vec<PirouetteDirEntry>,
     count: usize,
+    thinning: &ConfigOptsThinning,
 ) -> Result<Vec<PirouetteDirEntry>> {
+    // Never expire the newest snapshot, judged by either its mtime or its timestamped name,
+    // as a safety net against clock skew or touched files deleting the freshest backup
+    let protected_indexes = [
+        entries
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, entry)| entry.timestamp),
+        entries
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, entry)| entry.path.file_name()),
+    ]
+    .map(|newest| newest.map(|(index, _)| index));
+
+    let mut sorted_entries: Vec<PirouetteDirEntry> = entries
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !protected_indexes.contains(&Some(*index)))
+        .map(|(_, entry)| entry)
+        .collect();
+
+    if count > sorted_entries.len() {
+        log::warn!("Keeping the newest snapshot, even though it exceeds the retention limit");
+    }
+    let count = count.min(sorted_entries.len());
+
     // Sort the snapshots from oldest -> newest
-    let mut sorted_entries = entries;
     sorted_entries.sort_by_key(|entry| entry.timestamp);
 
-    // In theory, this fails if count > len, but we already early return
-    // in the parent function for that case, so this should always be Ok()
+    if *thinning == ConfigOptsThinning::Exponential {
+        return Ok(get_thinned_snapshots(sorted_entries, count));
+    }
+
+    // In theory, this fails if count > len, but we've already clamped
+    // the count to the unprotected entries, so this should always be Ok()
     let (expired_snapshots, _) = sorted_entries
         .split_at_checked(count)
         .context("Failed to calculate expired snapshots")?;
@@ -82,18 +286,90 @@ fn get_expired_snapshots(
     Ok(result)
 }
 
-fn delete_snapshots(expired_snapshots: Vec<PirouetteDirEntry>) {
+// Repeatedly expire whichever snapshot leaves the smallest gap relative to its age,
+// so recent snapshots stay dense while older ones become progressively sparser.
+// The oldest and newest snapshots are only expired once nothing else is left.
+fn get_thinned_snapshots(
+    sorted_entries: Vec<PirouetteDirEntry>,
+    count: usize,
+) -> Vec<PirouetteDirEntry> {
+    let mut remaining_entries = sorted_entries;
+    let mut result = vec![];
+
+    for _ in 0..count {
+        if remaining_entries.is_empty() {
+            break;
+        }
+
+        let expired_index = (1..remaining_entries.len().saturating_sub(1))
+            .min_by(|&a, &b| {
+                get_thinning_score(&remaining_entries, a)
+                    .total_cmp(&get_thinning_score(&remaining_entries, b))
+            })
+            .unwrap_or(0);
+
+        result.push(remaining_entries.remove(expired_index));
+    }
+
+    result
+}
+
+// The gap left behind by removing `sorted_entries[index]`, relative to its age
+fn get_thinning_score(sorted_entries: &[PirouetteDirEntry], index: usize) -> f64 {
+    let newest = sorted_entries[sorted_entries.len() - 1].timestamp;
+    let entry = sorted_entries[index].timestamp;
+
+    let gap = sorted_entries[index + 1]
+        .timestamp
+        .duration_since(sorted_entries[index - 1].timestamp)
+        .unwrap_or_default();
+    let age = newest.duration_since(entry).unwrap_or_default();
+
+    gap.as_secs_f64() / age.as_secs_f64().max(1.0)
+}
+
+// Every snapshot deletion goes through here, so `append_only` can't be bypassed
+pub fn delete_snapshots(config: &Config, expired_snapshots: Vec<PirouetteDirEntry>) {
+    if config.options.append_only {
+        for snapshot in expired_snapshots {
+            log::warn!("Not deleting {snapshot}, because append_only is set");
+        }
+        return;
+    }
+
     for snapshot in expired_snapshots {
         log::info!("Deleting {snapshot}");
 
-        if snapshot.path.is_dir() {
-            if let Err(err) = fs::remove_dir_all(&snapshot.path) {
+        // A split tarball is deleted along with all of its volumes
+        let mut is_deleted = true;
+        for snapshot_part in volume::get_snapshot_parts(&snapshot.path) {
+            if snapshot_part.is_dir() {
+                if let Err(err) = fs::remove_dir_all(&snapshot_part) {
+                    log::error!("{err}");
+                    is_deleted = false;
+                }
+            } else if snapshot_part.is_file()
+                && let Err(err) = fs::remove_file(&snapshot_part)
+            {
+                log::error!("{err}");
+                is_deleted = false;
+            }
+        }
+        if is_deleted {
+            index::remove_snapshot(config, &snapshot.path);
+            if let Err(err) =
+                audit::record_action(config, audit::AuditAction::Delete, &snapshot.path)
+            {
+                log::error!("{err:#}");
+            }
+        }
+
+        for sidecar_path in layout::get_sidecar_paths(&snapshot.path) {
+            if sidecar_path.exists()
+                && let Err(err) = fs::remove_file(&sidecar_path)
+            {
                 log::error!("{err}");
             }
-        } else if snapshot.path.is_file()
-            && let Err(err) = fs::remove_file(&snapshot.path)
-        {
-            log::error!("{err}");
         }
     }
 }
@@ -102,7 +378,7 @@ fn delete_snapshots(expired_snapshots: Vec<PirouetteDirEntry>) {
 mod tests {
     use super::*;
     use std::path::PathBuf;
-    use std::time::{Duration, UNIX_EPOCH};
+    use std::time::UNIX_EPOCH;
 
     #[test]
     fn test_expired_snapshot_count() {
@@ -117,7 +393,7 @@ mod tests {
         // Should return the number of entries we asked for
         for i in 0..10 {
             assert_eq!(
-                get_expired_snapshots(test_data.clone(), i)
+                get_expired_snapshots(test_data.clone(), i, &ConfigOptsThinning::Truncate)
                     .unwrap()
                     .len(),
                 i
@@ -125,6 +401,44 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_cleanup_order() -> Result<()> {
+        let test_path = std::env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&test_path)?;
+
+        // Oldest by name, by mtime, and by ctime are each a different snapshot
+        let names = ["2024-01-01T00:00", "2024-01-02T00:00", "2024-01-03T00:00"];
+        let mtime_hours = [2, 0, 1];
+        for (name, hour) in names.iter().zip(mtime_hours) {
+            fs::create_dir_all(test_path.join(name))?;
+            fs::File::open(test_path.join(name))?
+                .set_modified(UNIX_EPOCH + Duration::from_secs(hour * 60 * 60))?;
+        }
+        // Even an unchanged mode is a status change
+        for name in [names[2], names[0], names[1]] {
+            std::thread::sleep(Duration::from_millis(10));
+            let permissions = fs::metadata(test_path.join(name))?.permissions();
+            fs::set_permissions(test_path.join(name), permissions)?;
+        }
+
+        let get_oldest = |cleanup_order: ConfigOptsCleanupOrder| -> Option<PathBuf> {
+            names
+                .iter()
+                .map(|name| PirouetteDirEntry::from(test_path.join(name)))
+                .min_by_key(|entry| get_cleanup_time(&cleanup_order, entry))
+                .map(|entry| entry.path)
+        };
+        let oldest_by_name = get_oldest(ConfigOptsCleanupOrder::Name);
+        let oldest_by_mtime = get_oldest(ConfigOptsCleanupOrder::Mtime);
+        let oldest_by_ctime = get_oldest(ConfigOptsCleanupOrder::Ctime);
+
+        fs::remove_dir_all(&test_path)?;
+        assert_eq!(oldest_by_name, Some(test_path.join(names[0])));
+        assert_eq!(oldest_by_mtime, Some(test_path.join(names[1])));
+        assert_eq!(oldest_by_ctime, Some(test_path.join(names[2])));
+        Ok(())
+    }
+
     #[test]
     fn test_expired_snapshot_order() {
         let earlier_entry = PirouetteDirEntry {
@@ -137,9 +451,281 @@ mod tests {
         };
 
         let test_data = vec![earlier_entry.clone(), later_entry.clone()];
-        let result = get_expired_snapshots(test_data, 1).unwrap();
+        let result = get_expired_snapshots(test_data, 1, &ConfigOptsThinning::Truncate).unwrap();
 
         assert!(result.contains(&earlier_entry));
         assert!(!result.contains(&later_entry));
     }
+
+    #[test]
+    fn test_thinned_snapshot_spacing() {
+        // Two days of hourly snapshots, thinned down to 10
+        let mut test_data = vec![];
+        for i in 0..48 {
+            test_data.push(PirouetteDirEntry {
+                path: PathBuf::from(format!("/tmp/fake/{i}")),
+                timestamp: UNIX_EPOCH + Duration::from_secs(i * 3600),
+            })
+        }
+
+        let expired_snapshots =
+            get_expired_snapshots(test_data.clone(), 38, &ConfigOptsThinning::Exponential).unwrap();
+        assert_eq!(expired_snapshots.len(), 38);
+
+        let kept_snapshots: Vec<_> = test_data
+            .iter()
+            .filter(|entry| !expired_snapshots.contains(entry))
+            .collect();
+
+        // The history's endpoints are always kept
+        assert!(kept_snapshots.contains(&&test_data[0]));
+        assert!(kept_snapshots.contains(&&test_data[47]));
+
+        // Recent snapshots are kept more densely than old ones
+        let recent_count = kept_snapshots
+            .iter()
+            .filter(|entry| entry.timestamp >= test_data[36].timestamp)
+            .count();
+        let old_count = kept_snapshots
+            .iter()
+            .filter(|entry| entry.timestamp < test_data[12].timestamp)
+            .count();
+        assert!(recent_count > old_count);
+    }
+
+    #[test]
+    fn test_oversize_snapshot_count() -> Result<()> {
+        let target_path = std::env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(target_path.join("c"))?;
+
+        // Three snapshots of 100 bytes each, oldest to newest
+        let mut test_data = vec![];
+        for (i, name) in ["a.tgz", "b.tgz", "c/inner.txt"]
+            .iter()
+            .enumerate()
+        {
+            fs::write(target_path.join(name), [0u8; 100])?;
+            test_data.push(PirouetteDirEntry {
+                path: target_path.join(name.split('/').next().unwrap()),
+                timestamp: UNIX_EPOCH + Duration::from_secs(i as u64),
+            });
+        }
+
+        let counts: Vec<usize> = [300, 250, 200, 100, 50]
+            .iter()
+            .map(|max_size| get_oversize_snapshot_count(&test_data, *max_size))
+            .collect();
+
+        fs::remove_dir_all(&target_path)?;
+
+        // The newest snapshot is kept even when it's over budget
+        assert_eq!(counts, vec![0, 1, 1, 2, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_labelled_snapshots_are_kept() -> Result<()> {
+        let target_path = std::env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&target_path)?;
+        let test_data: Vec<PirouetteDirEntry> = ["a.tgz", "b.tgz"]
+            .iter()
+            .map(|name| target_path.join(name).into())
+            .collect();
+        label::write_label(&test_data[0].path, "pre-migration")?;
+
+        let unlabelled_entries = get_unlabelled_entries(test_data.clone());
+        fs::remove_dir_all(&target_path)?;
+
+        assert_eq!(unlabelled_entries, vec![test_data[1].clone()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_labelled_split_snapshots_are_kept() -> Result<()> {
+        let target_path = std::env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\nretention.hours = 1"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+        fs::create_dir_all(&all_targets[0].path)?;
+        for (name, seconds) in [("a.tgz.001", 1), ("a.tgz.002", 1), ("b.tgz", 2)] {
+            let snapshot_path = all_targets[0].path.join(name);
+            fs::write(&snapshot_path, "")?;
+            fs::File::options()
+                .write(true)
+                .open(&snapshot_path)?
+                .set_modified(UNIX_EPOCH + Duration::from_secs(seconds))?;
+        }
+        // Labelled as it's taken, by the tarball's own path rather than its first volume
+        label::write_label(&all_targets[0].path.join("a.tgz"), "pre-migration")?;
+
+        let deleted_paths = clean_snapshots(&config, &all_targets[0], None);
+        let is_split_kept = ["a.tgz.001", "a.tgz.002"]
+            .iter()
+            .all(|name| all_targets[0].path.join(name).exists());
+        fs::remove_dir_all(&target_path)?;
+
+        assert!(deleted_paths?.is_empty());
+        assert!(is_split_kept);
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_only_never_deletes() -> Result<()> {
+        let target_path = std::env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\nretention.hours = 1"
+        ))?;
+        config.options.append_only = true;
+        let all_targets = crate::get_all_retention_targets(&config);
+        for name in ["a.tgz", "b.tgz"] {
+            fs::create_dir_all(&all_targets[0].path)?;
+            fs::write(all_targets[0].path.join(name), "")?;
+        }
+
+        let deleted_paths = clean_snapshots(&config, &all_targets[0], None);
+        delete_snapshots(&config, vec![all_targets[0].path.join("a.tgz").into()]);
+        let remaining_count = fs::read_dir(&all_targets[0].path)?.count();
+        fs::remove_dir_all(&target_path)?;
+
+        assert!(deleted_paths?.is_empty());
+        assert_eq!(remaining_count, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_cleaned_snapshot_count() -> Result<()> {
+        let target_path = std::env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\nretention.hours = 1"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+        fs::create_dir_all(&all_targets[0].path)?;
+        for name in ["a.tgz", "b.tgz"] {
+            fs::write(all_targets[0].path.join(name), "")?;
+        }
+        label::write_label(&all_targets[0].path.join("b.tgz"), "keep")?;
+
+        let expected_result = check_cleaned_snapshot_count(&all_targets[0], 1);
+        let undeleted_result = check_cleaned_snapshot_count(&all_targets[0], 0);
+        fs::remove_dir_all(&target_path)?;
+
+        // The labelled snapshot is never counted
+        assert!(expected_result.is_ok());
+        assert!(undeleted_result.is_err_and(|e| e.to_string().contains("couldn't be deleted")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cleaned_count_with_dry_run_promotion() -> Result<()> {
+        let target_path = std::env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\n\
+            retention.hours = 1\nretention.days = {{ keep = 7, dry_run = true }}\n\
+            [options]\npromote = true\nverify_cleanup = true"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+        fs::create_dir_all(&all_targets[0].path)?;
+        for day in 0..3 {
+            let snapshot_path = all_targets[0]
+                .path
+                .join(format!("snapshot_{day}"));
+            fs::create_dir_all(&snapshot_path)?;
+            fs::File::open(&snapshot_path)?
+                .set_modified(UNIX_EPOCH + Duration::from_secs(day * 24 * 60 * 60))?;
+        }
+
+        // Both expired snapshots would be promoted, so neither is deleted, and both stay
+        let clean_result = clean_snapshots(&config, &all_targets[0], Some(&all_targets[1]));
+        let remaining_count = fs::read_dir(&all_targets[0].path)?.count();
+        fs::remove_dir_all(&target_path)?;
+
+        assert!(clean_result?.is_empty());
+        assert_eq!(remaining_count, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_before_snapshot() -> Result<()> {
+        let target_path = std::env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\n\
+            retention.hours = 3\nretention.days = 1"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+        for retention_target in &all_targets {
+            fs::create_dir_all(&retention_target.path)?;
+            for i in 0..3 {
+                let snapshot_path = retention_target.path.join(format!("{i}.tgz"));
+                fs::write(&snapshot_path, "")?;
+                fs::File::options()
+                    .write(true)
+                    .open(&snapshot_path)?
+                    .set_modified(UNIX_EPOCH + Duration::from_secs(i))?;
+            }
+        }
+
+        let hours_result = clean_before_snapshot(&config, &all_targets[0], None);
+        let days_result = clean_before_snapshot(&config, &all_targets[1], None);
+        let hours_count = fs::read_dir(&all_targets[0].path)?.count();
+        let days_count = fs::read_dir(&all_targets[1].path)?.count();
+        fs::remove_dir_all(&target_path)?;
+
+        hours_result?;
+        days_result?;
+        // One fewer than `keep`, but never none
+        assert_eq!(hours_count, 2);
+        assert_eq!(days_count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_empty_and_missing_directories() -> Result<()> {
+        let target_path = std::env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\n\
+            retention.hours = 1\nretention.days = 1\noptions.verify_cleanup = true"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+        // Only hours has a directory, and it's empty. Days' was never created.
+        fs::create_dir_all(&all_targets[0].path)?;
+
+        let empty_result = clean_snapshots(&config, &all_targets[0], None);
+        let missing_result = clean_snapshots(&config, &all_targets[1], None);
+        fs::remove_dir_all(&target_path)?;
+
+        assert!(empty_result?.is_empty());
+        assert!(missing_result?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_newest_snapshot_is_protected() {
+        // The newest name has the oldest mtime, eg: after a clock jump
+        let skewed_entry = PirouetteDirEntry {
+            path: PathBuf::from("/tmp/fake/2024-01-03T00:00"),
+            timestamp: UNIX_EPOCH,
+        };
+        let newest_entry = PirouetteDirEntry {
+            path: PathBuf::from("/tmp/fake/2024-01-02T00:00"),
+            timestamp: UNIX_EPOCH + Duration::from_secs(2),
+        };
+        let oldest_entry = PirouetteDirEntry {
+            path: PathBuf::from("/tmp/fake/2024-01-01T00:00"),
+            timestamp: UNIX_EPOCH + Duration::from_secs(1),
+        };
+        let test_data = vec![
+            skewed_entry.clone(),
+            newest_entry.clone(),
+            oldest_entry.clone(),
+        ];
+
+        for thinning in [
+            ConfigOptsThinning::Truncate,
+            ConfigOptsThinning::Exponential,
+        ] {
+            let result = get_expired_snapshots(test_data.clone(), 3, &thinning).unwrap();
+            assert_eq!(result, vec![oldest_entry.clone()]);
+        }
+    }
 }
@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::configuration::ConfigRetentionPeriod;
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Force a new snapshot for any period whose newest snapshot is older than this local
+    /// time, eg: "2024-01-01T00:00"
+    #[arg(long, value_parser = parse_cli_datetime)]
+    pub since: Option<SystemTime>,
+
+    /// Print each period's rotation decision before running, eg: its newest snapshot's age
+    #[arg(long)]
+    pub plan: bool,
+
+    /// Don't create or delete anything, overriding the config's `dry_run`
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Take each snapshot at most once for this id, so a retried run doesn't duplicate them
+    #[arg(long, env = "PIROUETTE_RUN_ID")]
+    pub run_id: Option<String>,
+
+    /// With `PIROUETTE_CONFIG_DIR`, run every config file's job at once, rather than in turn
+    #[arg(long)]
+    pub parallel: bool,
+
+    /// Print the output formats and features this build supports, then exit
+    #[arg(long)]
+    pub capabilities: bool,
+
+    /// How to print an error which ends the run
+    #[arg(long, value_enum, default_value_t = CliErrorFormat::Text)]
+    pub error_format: CliErrorFormat,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+pub enum CliErrorFormat {
+    Text,
+    /// A single JSON object on stderr, with the error, its causes, and the phase it failed in
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Check the config for likely mistakes, as well as hard errors, without running
+    #[command(alias = "config-test")]
+    Lint,
+    /// Check Directory snapshots against their manifest hashes, to detect bitrot
+    Verify {
+        /// A single snapshot to verify, instead of every snapshot with a manifest
+        snapshot: Option<PathBuf>,
+    },
+    /// Create the target and each period's directory, and check they're writable
+    Init,
+    /// List each retention period's directory and limit
+    Targets {
+        /// Print a JSON array, for external tooling
+        #[arg(long)]
+        json: bool,
+    },
+    /// Archive older snapshots of a period into one tarball per coarser period, eg: per day.
+    /// Each tarball is made from the newest snapshot in its period, and the rest are deleted.
+    Consolidate {
+        /// The retention period whose snapshots are consolidated
+        #[arg(value_enum)]
+        period: ConfigRetentionPeriod,
+        /// The coarser period to group snapshots by
+        #[arg(long, value_enum)]
+        by: ConfigRetentionPeriod,
+    },
+    /// Delete every period's snapshots from before a date, whatever their `keep` limits
+    Prune {
+        /// A local time, eg: "2023-01-01", judged against the time in each snapshot's name
+        #[arg(long, value_parser = parse_cli_datetime)]
+        before: SystemTime,
+    },
+    /// Print how big a period's next snapshot would be, without writing anything
+    Estimate {
+        /// The retention period to estimate
+        #[arg(value_enum)]
+        period: ConfigRetentionPeriod,
+        /// Also estimate its size as a gzipped tarball, by compressing a sample of the source
+        #[arg(long)]
+        sample: bool,
+    },
+    /// Stop taking, and so cleaning up, a period's snapshots until a time, without editing the config
+    Pause {
+        /// The retention period to pause
+        #[arg(value_enum)]
+        period: ConfigRetentionPeriod,
+        /// A local time, eg: "2024-01-20T00:00", after which the period rotates as usual again
+        #[arg(long, value_parser = parse_cli_datetime)]
+        until: SystemTime,
+    },
+    /// Undo `pause` for a period before its time is up
+    Resume {
+        /// The retention period to resume
+        #[arg(value_enum)]
+        period: ConfigRetentionPeriod,
+    },
+    /// Remove sidecars left behind by deleted snapshots, and rename snapshots whose names were
+    /// changed by hand, from their modification time. Anything else is reported.
+    Repair,
+    /// Rebuild the `snapshot_index` from the snapshots in each period's directory
+    Reindex,
+    /// Take a snapshot for one period right away, without checking its age or cleaning up
+    Snapshot {
+        /// The retention period to snapshot
+        #[arg(value_enum)]
+        period: ConfigRetentionPeriod,
+        /// Stream a tarball to stdout instead of storing it in the target
+        #[arg(long)]
+        stdout: bool,
+        /// Label the snapshot, eg: "pre-migration", so it's never cleaned up
+        #[arg(long, conflicts_with = "stdout")]
+        label: Option<String>,
+        /// Exit with code 3 if the snapshot's contents are identical to the previous snapshot's,
+        /// eg: to fail a CI job whose build produced nothing new
+        #[arg(long, conflicts_with = "stdout")]
+        fail_if_unchanged: bool,
+    },
+}
+
+pub fn parse_cli() -> Cli {
+    Cli::parse()
+}
+
+// Accepts the same format as snapshot names, with or without seconds, or a bare date
+fn parse_cli_datetime(s: &str) -> Result<SystemTime> {
+    let naive_datetime = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M"))
+        .or_else(|_| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default())
+        })
+        .with_context(|| format!("expected a time like \"2024-01-01T00:00\", got {s:?}"))?;
+
+    let local_datetime = Local
+        .from_local_datetime(&naive_datetime)
+        .earliest()
+        .with_context(|| format!("{s:?} is not a valid local time"))?;
+
+    Ok(local_datetime.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cli_datetime() {
+        let expected_time: SystemTime = Local
+            .with_ymd_and_hms(2024, 1, 1, 0, 0, 0)
+            .unwrap()
+            .into();
+
+        for s in ["2024-01-01T00:00:00", "2024-01-01T00:00", "2024-01-01"] {
+            assert_eq!(parse_cli_datetime(s).unwrap(), expected_time);
+        }
+
+        assert!(parse_cli_datetime("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_parse_snapshot_command() {
+        let cli = Cli::try_parse_from(["pirouette", "snapshot", "--stdout", "days"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Snapshot {
+                period: ConfigRetentionPeriod::Days,
+                stdout: true,
+                label: None,
+                fail_if_unchanged: false,
+            })
+        ));
+
+        let cli = Cli::try_parse_from(["pirouette", "snapshot", "days", "--label", "foo"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Snapshot { label: Some(label), .. }) if label == "foo"
+        ));
+
+        assert!(Cli::try_parse_from(["pirouette", "snapshot", "fortnights"]).is_err());
+        assert!(
+            Cli::try_parse_from([
+                "pirouette",
+                "snapshot",
+                "--stdout",
+                "days",
+                "--label",
+                "foo"
+            ])
+            .is_err()
+        );
+    }
+}
@@ -1,19 +1,27 @@
 use anyhow::{Context, Result};
 use glob::Pattern;
 use log::LevelFilter;
-use serde::{Deserialize, Deserializer};
-use std::collections::HashMap;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
 use std::env;
 use std::fmt;
 use std::fs;
 use std::hash::Hash;
 use std::path;
 
+use clap::ValueEnum;
+
+use crate::index;
+use crate::layout;
+use crate::notify;
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub source: ConfigPath,
-    pub target: ConfigPath,
-    pub retention: HashMap<ConfigRetentionPeriod, usize>,
+    pub target: ConfigTarget,
+    #[serde(default, deserialize_with = "deserialize_retention")]
+    pub retention: BTreeMap<ConfigRetentionPeriod, ConfigRetention>,
     #[serde(default = "default_opts")]
     pub options: ConfigOpts,
 }
@@ -23,6 +31,14 @@ pub struct ConfigPath {
     pub path: path::PathBuf,
 }
 
+// Each snapshot is also copied to the `mirrors`, which are cleaned up independently
+#[derive(Debug, Deserialize)]
+pub struct ConfigTarget {
+    pub path: path::PathBuf,
+    #[serde(default)]
+    pub mirrors: Vec<path::PathBuf>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ConfigOpts {
     #[serde(default = "default_opts_output_format")]
@@ -47,18 +63,288 @@ pub struct ConfigOpts {
         deserialize_with = "deserialize_opts_patterns"
     )]
     pub exclude: Vec<Pattern>,
+    #[serde(default = "default_opts_source_list")]
+    pub source_list: Option<path::PathBuf>,
+    #[serde(default = "default_opts_thinning")]
+    pub thinning: ConfigOptsThinning,
+    #[serde(default = "default_opts_manifest")]
+    pub manifest: bool,
+    #[serde(default = "default_opts_tar_block_size")]
+    pub tar_block_size: u64,
+    #[serde(default = "default_opts_staging")]
+    pub staging: bool,
+    #[serde(default = "default_opts_max_dir_entries")]
+    pub max_dir_entries: Option<usize>,
+    #[serde(default = "default_opts_strict_walk")]
+    pub strict_walk: bool,
+    #[serde(default = "default_opts_max_unreadable_entries")]
+    pub max_unreadable_entries: Option<usize>,
+    #[serde(default = "default_opts_check_inodes")]
+    pub check_inodes: bool,
+    #[serde(default = "default_opts_age_grace")]
+    pub age_grace: u64,
+    #[serde(default = "default_opts_boundary")]
+    pub boundary: ConfigOptsBoundary,
+    #[serde(default = "default_opts_layout")]
+    pub layout: String,
+    #[serde(default = "default_opts_durable")]
+    pub durable: bool,
+    #[serde(
+        default = "default_opts_split_size",
+        deserialize_with = "deserialize_opts_size"
+    )]
+    pub split_size: Option<u64>,
+    #[serde(default = "default_opts_include_source_basename")]
+    pub include_source_basename: bool,
+    #[serde(default = "default_opts_exclude_names")]
+    pub exclude_names: Vec<String>,
+    #[serde(default = "default_opts_tar_mtime")]
+    pub tar_mtime: ConfigOptsTarMtime,
+    #[serde(default = "default_opts_maintain_working_copy")]
+    pub maintain_working_copy: bool,
+    #[serde(default = "default_opts_future_timestamp")]
+    pub future_timestamp: ConfigOptsFutureTimestamp,
+    #[serde(default = "default_opts_post_clean_hook")]
+    pub post_clean_hook: Option<String>,
+    #[serde(default = "default_opts_temp_dir")]
+    pub temp_dir: Option<path::PathBuf>,
+    #[serde(default = "default_opts_copy_concurrency")]
+    pub copy_concurrency: usize,
+    #[serde(default = "default_opts_special_files")]
+    pub special_files: ConfigOptsSpecialFiles,
+    #[serde(default = "default_opts_log_file")]
+    pub log_file: Option<path::PathBuf>,
+    #[serde(default = "default_opts_log_max_files")]
+    pub log_max_files: usize,
+    #[serde(
+        default = "default_opts_log_max_size",
+        deserialize_with = "deserialize_opts_size"
+    )]
+    pub log_max_size: Option<u64>,
+    #[serde(
+        default = "default_opts_exclude_modified_within",
+        deserialize_with = "deserialize_opts_duration"
+    )]
+    pub exclude_modified_within: Option<u64>,
+    #[serde(default = "default_opts_verify_after_copy")]
+    pub verify_after_copy: bool,
+    #[serde(default = "default_opts_snapshot_time")]
+    pub snapshot_time: ConfigOptsSnapshotTime,
+    #[serde(default = "default_opts_preserve_hardlinks")]
+    pub preserve_hardlinks: bool,
+    #[serde(default = "default_opts_include_extensions")]
+    pub include_extensions: Vec<String>,
+    #[serde(default = "default_opts_on_in_progress")]
+    pub on_in_progress: ConfigOptsOnInProgress,
+    #[serde(default = "default_opts_preserve_acls")]
+    pub preserve_acls: bool,
+    #[serde(default = "default_opts_promote")]
+    pub promote: bool,
+    #[serde(default = "default_opts_on_directory_error")]
+    pub on_directory_error: ConfigOptsOnDirectoryError,
+    #[serde(default = "default_opts_tarball_extension")]
+    pub tarball_extension: String,
+    #[serde(default = "default_opts_append_only")]
+    pub append_only: bool,
+    #[serde(default = "default_opts_clean_before_snapshot")]
+    pub clean_before_snapshot: bool,
+    #[serde(default = "default_opts_skip_empty_files")]
+    pub skip_empty_files: bool,
+    #[serde(default = "default_opts_log_throughput")]
+    pub log_throughput: bool,
+    #[serde(
+        default = "default_opts_regexes",
+        deserialize_with = "deserialize_opts_regexes"
+    )]
+    pub include_regex: Vec<Regex>,
+    #[serde(
+        default = "default_opts_regexes",
+        deserialize_with = "deserialize_opts_regexes"
+    )]
+    pub exclude_regex: Vec<Regex>,
+    #[serde(default = "default_opts_follow_symlinks")]
+    pub follow_symlinks: bool,
+    #[serde(default = "default_opts_retention_file")]
+    pub retention_file: Option<path::PathBuf>,
+    #[serde(default = "default_opts_verify_cleanup")]
+    pub verify_cleanup: bool,
+    #[serde(default = "default_opts_preserve_metadata")]
+    pub preserve_metadata: bool,
+    #[serde(
+        default = "default_opts_compression_time_budget",
+        deserialize_with = "deserialize_opts_duration"
+    )]
+    pub compression_time_budget: Option<u64>,
+    #[serde(default = "default_opts_exclude_newest_per_dir")]
+    pub exclude_newest_per_dir: bool,
+    #[serde(default = "default_opts_resume_interrupted")]
+    pub resume_interrupted: bool,
+    #[serde(default = "default_opts_notify")]
+    pub notify: ConfigOptsNotify,
+    #[serde(default = "default_opts_notify_email")]
+    pub notify_email: Option<String>,
+    #[serde(default = "default_opts_notify_email_from")]
+    // Only read by the "notify-email" feature
+    #[cfg_attr(not(feature = "notify-email"), allow(dead_code))]
+    pub notify_email_from: Option<String>,
+    #[serde(default = "default_opts_notify_smtp_host")]
+    pub notify_smtp_host: Option<String>,
+    #[serde(default = "default_opts_notify_smtp_port")]
+    // Only read by the "notify-email" feature
+    #[cfg_attr(not(feature = "notify-email"), allow(dead_code))]
+    pub notify_smtp_port: u16,
+    #[serde(default = "default_opts_notify_smtp_username")]
+    pub notify_smtp_username: Option<String>,
+    #[serde(default = "default_opts_notify_smtp_password")]
+    pub notify_smtp_password: Option<ConfigSecret>,
+    #[serde(default = "default_opts_cleanup_order")]
+    pub cleanup_order: ConfigOptsCleanupOrder,
+    #[serde(default = "default_opts_validate_hook")]
+    pub validate_hook: Option<String>,
+    #[serde(default = "default_opts_on_validate_failure")]
+    pub on_validate_failure: ConfigOptsOnValidateFailure,
+    #[serde(default = "default_opts_age_comparison")]
+    pub age_comparison: ConfigOptsAgeComparison,
+    #[serde(default = "default_opts_audit_log")]
+    pub audit_log: Option<path::PathBuf>,
+    #[serde(
+        default = "default_opts_max_snapshot_size",
+        deserialize_with = "deserialize_opts_size"
+    )]
+    pub max_snapshot_size: Option<u64>,
+    #[serde(default = "default_opts_include_hidden")]
+    pub include_hidden: bool,
+    #[serde(default = "default_opts_entry_log")]
+    pub entry_log: Option<path::PathBuf>,
+    #[serde(default = "default_opts_snapshot_index")]
+    pub snapshot_index: Option<path::PathBuf>,
+    // Only ever set for a single run, by `--run-id`
+    #[serde(skip)]
+    pub run_id: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, clap::ValueEnum)]
 #[serde(rename_all = "snake_case")]
 pub enum ConfigOptsOutputFormat {
     Directory,
     Tarball,
 }
 
-#[derive(PartialEq, Eq, Hash, Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigOptsThinning {
+    Truncate,
+    Exponential,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigOptsBoundary {
+    Rolling,
+    Calendar,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigOptsAgeComparison {
+    AtLeast,
+    MoreThan,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigOptsTarMtime {
+    File,
+    Walk,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigOptsFutureTimestamp {
+    Block,
+    Rotate,
+    Error,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigOptsSpecialFiles {
+    Skip,
+    Archive,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigOptsSnapshotTime {
+    Now,
+    SourceMtime,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigOptsCleanupOrder {
+    Name,
+    Mtime,
+    Ctime,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigOptsOnValidateFailure {
+    Warn,
+    Delete,
+    Fail,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigOptsNotify {
+    None,
+    Desktop,
+    Email,
+}
+
+// A value which is never logged, eg: in the parsed config logged at debug level
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct ConfigSecret(String);
+
+impl ConfigSecret {
+    // Only read by the "notify-email" feature
+    #[cfg_attr(not(feature = "notify-email"), allow(dead_code))]
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for ConfigSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigOptsOnInProgress {
+    Skip,
+    Wait,
+    Error,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigOptsOnDirectoryError {
+    Fail,
+    Skip,
+}
+
+// Periods are ordered from finest to coarsest, which is the order they're rotated in
+#[derive(
+    PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Deserialize, Serialize, Clone, clap::ValueEnum,
+)]
 #[serde(rename_all = "snake_case")]
 pub enum ConfigRetentionPeriod {
+    Minutes,
     Hours,
     Days,
     Weeks,
@@ -69,6 +355,7 @@ pub enum ConfigRetentionPeriod {
 impl fmt::Display for ConfigRetentionPeriod {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            ConfigRetentionPeriod::Minutes => write!(f, "minutes"),
             ConfigRetentionPeriod::Hours => write!(f, "hours"),
             ConfigRetentionPeriod::Days => write!(f, "days"),
             ConfigRetentionPeriod::Weeks => write!(f, "weeks"),
@@ -78,6 +365,211 @@ impl fmt::Display for ConfigRetentionPeriod {
     }
 }
 
+// A `retention_file` holds only the retention map, eg: `days = 7`
+#[derive(Deserialize)]
+#[serde(transparent)]
+struct ConfigRetentionFile {
+    #[serde(deserialize_with = "deserialize_retention")]
+    retention: BTreeMap<ConfigRetentionPeriod, ConfigRetention>,
+}
+
+// Reports every unknown period at once, alongside the valid ones, rather than just the first
+fn deserialize_retention<'de, D>(
+    deserializer: D,
+) -> Result<BTreeMap<ConfigRetentionPeriod, ConfigRetention>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let raw_retention: BTreeMap<String, toml::Value> = BTreeMap::deserialize(deserializer)?;
+    let format_periods = |periods: Vec<String>| {
+        periods
+            .iter()
+            .map(|period| format!("`{period}`"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let valid_periods: Vec<String> = ConfigRetentionPeriod::value_variants()
+        .iter()
+        .map(|period| period.to_string())
+        .collect();
+    let unknown_periods: Vec<String> = raw_retention
+        .keys()
+        .filter(|period| !valid_periods.contains(period))
+        .cloned()
+        .collect();
+    if !unknown_periods.is_empty() {
+        return Err(serde::de::Error::custom(format!(
+            "unknown retention period{} {}, expected one of {}",
+            if unknown_periods.len() > 1 { "s" } else { "" },
+            format_periods(unknown_periods),
+            format_periods(valid_periods)
+        )));
+    }
+
+    raw_retention
+        .into_iter()
+        .map(|(period, value)| {
+            let retention = ConfigRetention::deserialize(value)
+                .map_err(|e| serde::de::Error::custom(format!("retention.{period}: {e}")))?;
+            let period = ConfigRetentionPeriod::deserialize(toml::Value::String(period))
+                .map_err(serde::de::Error::custom)?;
+            Ok((period, retention))
+        })
+        .collect()
+}
+
+// A retention period is either just its limit, eg: `days = 7`,
+// or a table with per-period overrides, eg: `days = { keep = 7, output_format = "tarball" }`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigRetention {
+    pub limit: ConfigRetentionLimit,
+    pub output_format: Option<ConfigOptsOutputFormat>,
+    // Only ever adds to the global `dry_run`, so `--dry-run` still covers every period
+    pub dry_run: bool,
+    pub exclude_modified_within: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigRetentionTable {
+    keep: ConfigRetentionLimit,
+    output_format: Option<ConfigOptsOutputFormat>,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default, deserialize_with = "deserialize_opts_duration")]
+    exclude_modified_within: Option<u64>,
+}
+
+impl<'de> Deserialize<'de> for ConfigRetention {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = toml::Value::deserialize(deserializer)?;
+
+        if value.is_table() {
+            let table: ConfigRetentionTable = value
+                .try_into()
+                .map_err(serde::de::Error::custom)?;
+            Ok(ConfigRetention {
+                limit: table.keep,
+                output_format: table.output_format,
+                dry_run: table.dry_run,
+                exclude_modified_within: table.exclude_modified_within,
+            })
+        } else {
+            Ok(ConfigRetention {
+                limit: ConfigRetentionLimit::deserialize(value)
+                    .map_err(serde::de::Error::custom)?,
+                output_format: None,
+                dry_run: false,
+                exclude_modified_within: None,
+            })
+        }
+    }
+}
+
+// Each retention period keeps either a number of snapshots, or a total size, eg: "50GB"
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigRetentionLimit {
+    Count(usize),
+    Size(u64),
+}
+
+impl fmt::Display for ConfigRetentionLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigRetentionLimit::Count(count) => write!(f, "{count} snapshots"),
+            ConfigRetentionLimit::Size(size) => write!(f, "{size} bytes"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ConfigRetentionLimit {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum RawLimit {
+            Count(usize),
+            Size(String),
+        }
+
+        match RawLimit::deserialize(deserializer)? {
+            RawLimit::Count(count) => Ok(ConfigRetentionLimit::Count(count)),
+            RawLimit::Size(s) => parse_size(&s)
+                .map(ConfigRetentionLimit::Size)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+// Parse a human-readable size like "50GB" into bytes, using 1024-based units
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let unit_index = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(unit_index);
+
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("invalid size {s:?}, expected eg: \"50GB\""))?;
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        _ => anyhow::bail!("invalid size unit in {s:?}, expected one of B, KB, MB, GB, TB"),
+    };
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+// Parse a human-readable duration like "90s", "10m", or "1h30m" into seconds
+pub fn parse_duration(s: &str) -> Result<u64> {
+    let invalid_duration = || format!("invalid duration {s:?}, expected eg: \"10m\" or \"1h30m\"");
+    let mut remaining = s.trim();
+    if remaining.is_empty() {
+        anyhow::bail!(invalid_duration());
+    }
+
+    let mut total_seconds: u64 = 0;
+    while !remaining.is_empty() {
+        let unit_index = remaining
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(remaining.len());
+        let (number, rest) = remaining.split_at(unit_index);
+        let number: u64 = number.parse().with_context(invalid_duration)?;
+
+        let unit_length = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let (unit, rest) = rest.split_at(unit_length);
+        let multiplier: u64 = match unit.to_lowercase().as_str() {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "w" => 7 * 24 * 60 * 60,
+            _ => anyhow::bail!("invalid duration unit in {s:?}, expected one of s, m, h, d, w"),
+        };
+
+        total_seconds = number
+            .checked_mul(multiplier)
+            .and_then(|seconds| total_seconds.checked_add(seconds))
+            .with_context(|| format!("duration {s:?} is too long"))?;
+        remaining = rest.trim_start();
+    }
+
+    Ok(total_seconds)
+}
+
 fn default_opts() -> ConfigOpts {
     ConfigOpts {
         output_format: default_opts_output_format(),
@@ -85,6 +577,72 @@ fn default_opts() -> ConfigOpts {
         dry_run: default_opts_dry_run(),
         include: default_opts_patterns(),
         exclude: default_opts_patterns(),
+        source_list: default_opts_source_list(),
+        thinning: default_opts_thinning(),
+        manifest: default_opts_manifest(),
+        tar_block_size: default_opts_tar_block_size(),
+        staging: default_opts_staging(),
+        max_dir_entries: default_opts_max_dir_entries(),
+        strict_walk: default_opts_strict_walk(),
+        max_unreadable_entries: default_opts_max_unreadable_entries(),
+        check_inodes: default_opts_check_inodes(),
+        age_grace: default_opts_age_grace(),
+        boundary: default_opts_boundary(),
+        layout: default_opts_layout(),
+        durable: default_opts_durable(),
+        split_size: default_opts_split_size(),
+        include_source_basename: default_opts_include_source_basename(),
+        exclude_names: default_opts_exclude_names(),
+        tar_mtime: default_opts_tar_mtime(),
+        maintain_working_copy: default_opts_maintain_working_copy(),
+        future_timestamp: default_opts_future_timestamp(),
+        post_clean_hook: default_opts_post_clean_hook(),
+        temp_dir: default_opts_temp_dir(),
+        copy_concurrency: default_opts_copy_concurrency(),
+        special_files: default_opts_special_files(),
+        log_file: default_opts_log_file(),
+        log_max_files: default_opts_log_max_files(),
+        log_max_size: default_opts_log_max_size(),
+        exclude_modified_within: default_opts_exclude_modified_within(),
+        verify_after_copy: default_opts_verify_after_copy(),
+        snapshot_time: default_opts_snapshot_time(),
+        preserve_hardlinks: default_opts_preserve_hardlinks(),
+        include_extensions: default_opts_include_extensions(),
+        on_in_progress: default_opts_on_in_progress(),
+        preserve_acls: default_opts_preserve_acls(),
+        promote: default_opts_promote(),
+        on_directory_error: default_opts_on_directory_error(),
+        tarball_extension: default_opts_tarball_extension(),
+        append_only: default_opts_append_only(),
+        clean_before_snapshot: default_opts_clean_before_snapshot(),
+        skip_empty_files: default_opts_skip_empty_files(),
+        log_throughput: default_opts_log_throughput(),
+        include_regex: default_opts_regexes(),
+        exclude_regex: default_opts_regexes(),
+        follow_symlinks: default_opts_follow_symlinks(),
+        retention_file: default_opts_retention_file(),
+        verify_cleanup: default_opts_verify_cleanup(),
+        preserve_metadata: default_opts_preserve_metadata(),
+        compression_time_budget: default_opts_compression_time_budget(),
+        exclude_newest_per_dir: default_opts_exclude_newest_per_dir(),
+        resume_interrupted: default_opts_resume_interrupted(),
+        notify: default_opts_notify(),
+        notify_email: default_opts_notify_email(),
+        notify_email_from: default_opts_notify_email_from(),
+        notify_smtp_host: default_opts_notify_smtp_host(),
+        notify_smtp_port: default_opts_notify_smtp_port(),
+        notify_smtp_username: default_opts_notify_smtp_username(),
+        notify_smtp_password: default_opts_notify_smtp_password(),
+        cleanup_order: default_opts_cleanup_order(),
+        validate_hook: default_opts_validate_hook(),
+        on_validate_failure: default_opts_on_validate_failure(),
+        age_comparison: default_opts_age_comparison(),
+        audit_log: default_opts_audit_log(),
+        max_snapshot_size: default_opts_max_snapshot_size(),
+        include_hidden: default_opts_include_hidden(),
+        entry_log: default_opts_entry_log(),
+        snapshot_index: default_opts_snapshot_index(),
+        run_id: None,
     }
 }
 
@@ -149,6 +707,318 @@ where
         .collect()
 }
 
+fn default_opts_source_list() -> Option<path::PathBuf> {
+    None
+}
+
+fn default_opts_thinning() -> ConfigOptsThinning {
+    ConfigOptsThinning::Truncate
+}
+
+fn default_opts_manifest() -> bool {
+    false
+}
+
+// The size of a single tar header block
+fn default_opts_tar_block_size() -> u64 {
+    512
+}
+
+fn default_opts_staging() -> bool {
+    false
+}
+
+fn default_opts_max_dir_entries() -> Option<usize> {
+    None
+}
+
+fn default_opts_strict_walk() -> bool {
+    false
+}
+
+fn default_opts_max_unreadable_entries() -> Option<usize> {
+    None
+}
+
+fn default_opts_check_inodes() -> bool {
+    false
+}
+
+fn default_opts_age_grace() -> u64 {
+    5 * 60
+}
+
+fn default_opts_boundary() -> ConfigOptsBoundary {
+    ConfigOptsBoundary::Rolling
+}
+
+fn default_opts_layout() -> String {
+    layout::LAYOUT_PERIOD.to_string()
+}
+
+fn default_opts_durable() -> bool {
+    false
+}
+
+fn default_opts_split_size() -> Option<u64> {
+    None
+}
+
+// Either a number of bytes, or a human-readable size like "4GB"
+fn deserialize_opts_size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawSize {
+        Bytes(u64),
+        Size(String),
+    }
+
+    match RawSize::deserialize(deserializer)? {
+        RawSize::Bytes(bytes) => Ok(Some(bytes)),
+        RawSize::Size(s) => parse_size(&s)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+fn default_opts_include_source_basename() -> bool {
+    false
+}
+
+fn default_opts_exclude_names() -> Vec<String> {
+    vec![]
+}
+
+fn default_opts_tar_mtime() -> ConfigOptsTarMtime {
+    ConfigOptsTarMtime::File
+}
+
+fn default_opts_maintain_working_copy() -> bool {
+    false
+}
+
+fn default_opts_future_timestamp() -> ConfigOptsFutureTimestamp {
+    ConfigOptsFutureTimestamp::Block
+}
+
+fn default_opts_post_clean_hook() -> Option<String> {
+    None
+}
+
+fn default_opts_temp_dir() -> Option<path::PathBuf> {
+    None
+}
+
+fn default_opts_copy_concurrency() -> usize {
+    1
+}
+
+fn default_opts_special_files() -> ConfigOptsSpecialFiles {
+    ConfigOptsSpecialFiles::Skip
+}
+
+fn default_opts_log_file() -> Option<path::PathBuf> {
+    None
+}
+
+fn default_opts_log_max_files() -> usize {
+    5
+}
+
+fn default_opts_log_max_size() -> Option<u64> {
+    None
+}
+
+fn default_opts_exclude_modified_within() -> Option<u64> {
+    None
+}
+
+// Either a number of seconds, or a human-readable duration like "10m" or "1h30m"
+fn deserialize_opts_duration<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawDuration {
+        Seconds(u64),
+        Duration(String),
+    }
+
+    match RawDuration::deserialize(deserializer)? {
+        RawDuration::Seconds(seconds) => Ok(Some(seconds)),
+        RawDuration::Duration(s) => parse_duration(&s)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+fn default_opts_verify_after_copy() -> bool {
+    false
+}
+
+fn default_opts_snapshot_time() -> ConfigOptsSnapshotTime {
+    ConfigOptsSnapshotTime::Now
+}
+
+fn default_opts_preserve_hardlinks() -> bool {
+    false
+}
+
+fn default_opts_include_extensions() -> Vec<String> {
+    vec![]
+}
+
+fn default_opts_on_in_progress() -> ConfigOptsOnInProgress {
+    ConfigOptsOnInProgress::Skip
+}
+
+fn default_opts_preserve_acls() -> bool {
+    false
+}
+
+fn default_opts_promote() -> bool {
+    false
+}
+
+fn default_opts_on_directory_error() -> ConfigOptsOnDirectoryError {
+    ConfigOptsOnDirectoryError::Fail
+}
+
+fn default_opts_tarball_extension() -> String {
+    "tgz".to_string()
+}
+
+fn default_opts_append_only() -> bool {
+    false
+}
+
+fn default_opts_clean_before_snapshot() -> bool {
+    false
+}
+
+fn default_opts_skip_empty_files() -> bool {
+    false
+}
+
+fn default_opts_log_throughput() -> bool {
+    false
+}
+
+fn default_opts_regexes() -> Vec<Regex> {
+    vec![]
+}
+
+fn default_opts_follow_symlinks() -> bool {
+    false
+}
+
+fn default_opts_retention_file() -> Option<path::PathBuf> {
+    None
+}
+
+fn default_opts_verify_cleanup() -> bool {
+    false
+}
+
+fn default_opts_preserve_metadata() -> bool {
+    false
+}
+
+fn default_opts_compression_time_budget() -> Option<u64> {
+    None
+}
+
+fn default_opts_exclude_newest_per_dir() -> bool {
+    false
+}
+
+fn default_opts_resume_interrupted() -> bool {
+    false
+}
+
+fn default_opts_notify() -> ConfigOptsNotify {
+    ConfigOptsNotify::None
+}
+
+fn default_opts_notify_email() -> Option<String> {
+    None
+}
+
+fn default_opts_notify_email_from() -> Option<String> {
+    None
+}
+
+fn default_opts_notify_smtp_host() -> Option<String> {
+    None
+}
+
+// The submission port, which is upgraded to TLS with STARTTLS
+fn default_opts_notify_smtp_port() -> u16 {
+    587
+}
+
+fn default_opts_notify_smtp_username() -> Option<String> {
+    None
+}
+
+fn default_opts_notify_smtp_password() -> Option<ConfigSecret> {
+    None
+}
+
+fn default_opts_cleanup_order() -> ConfigOptsCleanupOrder {
+    ConfigOptsCleanupOrder::Name
+}
+
+fn default_opts_validate_hook() -> Option<String> {
+    None
+}
+
+fn default_opts_on_validate_failure() -> ConfigOptsOnValidateFailure {
+    ConfigOptsOnValidateFailure::Fail
+}
+
+fn default_opts_age_comparison() -> ConfigOptsAgeComparison {
+    ConfigOptsAgeComparison::AtLeast
+}
+
+fn default_opts_audit_log() -> Option<path::PathBuf> {
+    None
+}
+
+fn default_opts_max_snapshot_size() -> Option<u64> {
+    None
+}
+
+fn default_opts_include_hidden() -> bool {
+    true
+}
+
+fn default_opts_entry_log() -> Option<path::PathBuf> {
+    None
+}
+
+fn default_opts_snapshot_index() -> Option<path::PathBuf> {
+    None
+}
+
+fn deserialize_opts_regexes<'de, D>(deserializer: D) -> Result<Vec<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let regexes: Vec<String> = Vec::deserialize(deserializer)?;
+    regexes
+        .into_iter()
+        .map(|s| {
+            Regex::new(&s)
+                .map_err(|e| serde::de::Error::custom(format!("invalid regex {s:?}: {e}")))
+        })
+        .collect()
+}
+
 /*
     Read config from disk
 */
@@ -166,6 +1036,51 @@ fn get_config_file_path() -> path::PathBuf {
     }
 }
 
+// Returns the config contents, and a description of where they came from for error messages
+fn read_config_str() -> Result<(String, String)> {
+    // Inline content takes precedence over any file path
+    if let Ok(config_str) = env::var("PIROUETTE_CONFIG")
+        && !config_str.is_empty()
+    {
+        return Ok((config_str, "from PIROUETTE_CONFIG".to_string()));
+    }
+
+    let config_file_path = get_config_file_path();
+    let config_file_str = fs::read_to_string(&config_file_path)
+        .with_context(|| format!("failed to read config file: {config_file_path:?}"))?;
+
+    Ok((config_file_str, format!("file: {config_file_path:?}")))
+}
+
+// A `conf.d/` style directory, with one config file per job
+pub fn get_config_dir_path() -> Option<path::PathBuf> {
+    env::var("PIROUETTE_CONFIG_DIR")
+        .ok()
+        .filter(|config_dir| !config_dir.is_empty())
+        .map(path::PathBuf::from)
+}
+
+// Sorted by name, so jobs run in a predictable order, eg: "10-home.toml" before "20-mail.toml"
+pub fn get_config_dir_files(config_dir: &path::Path) -> Result<Vec<path::PathBuf>> {
+    let mut config_files = vec![];
+    for entry in fs::read_dir(config_dir)
+        .with_context(|| format!("failed to read config directory {config_dir:?}"))?
+    {
+        let path = entry
+            .with_context(|| format!("failed to read config directory {config_dir:?}"))?
+            .path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "toml") {
+            config_files.push(path);
+        }
+    }
+    config_files.sort();
+
+    if config_files.is_empty() {
+        anyhow::bail!("config directory {config_dir:?} has no .toml files");
+    }
+    Ok(config_files)
+}
+
 fn get_config_file_path_default() -> path::PathBuf {
     let default_directory = match in_container::in_container() {
         true => path::PathBuf::from("/config"),
@@ -178,6 +1093,35 @@ fn get_config_file_path_default() -> path::PathBuf {
     config_file_path
 }
 
+// Periods from `retention_file` are added alongside the inline `retention`.
+// Neither overrides the other, so a period set in both is an error.
+fn read_config_retention_file(config: &mut Config) -> Result<()> {
+    let Some(retention_file) = &config.options.retention_file else {
+        return Ok(());
+    };
+
+    let retention_str = fs::read_to_string(retention_file)
+        .with_context(|| format!("failed to read {retention_file:?}"))?;
+    let file_retention = match retention_file
+        .extension()
+        .is_some_and(|ext| ext == "json")
+    {
+        true => serde_json::from_str::<ConfigRetentionFile>(&retention_str)
+            .with_context(|| format!("failed to parse {retention_file:?}"))?,
+        false => toml::from_str::<ConfigRetentionFile>(&retention_str)
+            .with_context(|| format!("failed to parse {retention_file:?}"))?,
+    };
+
+    for (period, retention) in file_retention.retention {
+        if config.retention.contains_key(&period) {
+            anyhow::bail!("retention.{period} is set both inline and in {retention_file:?}");
+        }
+        config.retention.insert(period, retention);
+    }
+
+    Ok(())
+}
+
 /*
     User input validation
 */
@@ -191,17 +1135,118 @@ fn validate_config_source(source: &ConfigPath) -> Result<()> {
     Ok(())
 }
 
-// A valid `target` is only a directory, or a new non-existent path
-fn validate_config_target(target: &ConfigPath) -> Result<()> {
+// A valid `target`, and each of its `mirrors`, is only a directory, or a new non-existent path
+fn validate_config_target(target: &ConfigTarget) -> Result<()> {
     if target.path.exists() && !target.path.is_dir() {
         anyhow::bail!("target path is a file, not a directory");
     }
 
+    for mirror_path in &target.mirrors {
+        if mirror_path.exists() && !mirror_path.is_dir() {
+            anyhow::bail!("target mirror {mirror_path:?} is a file, not a directory");
+        }
+        if *mirror_path == target.path {
+            anyhow::bail!("target mirror {mirror_path:?} is the same as the target path");
+        }
+    }
+
+    Ok(())
+}
+
+// A valid `source_list` is an existing file, paired with a `source` directory to strip
+fn validate_config_source_list(
+    source: &ConfigPath,
+    source_list: &Option<path::PathBuf>,
+) -> Result<()> {
+    let Some(source_list) = source_list else {
+        return Ok(());
+    };
+
+    if !source_list.is_file() {
+        anyhow::bail!("source_list {source_list:?} is not an existing file");
+    }
+    if !source.path.is_dir() {
+        anyhow::bail!("source path must be a directory when source_list is set");
+    }
+
+    Ok(())
+}
+
+// A valid `tar_block_size` is a whole number of tar blocks
+fn validate_config_tar_block_size(tar_block_size: u64) -> Result<()> {
+    let block_size = default_opts_tar_block_size();
+    if tar_block_size == 0 || !tar_block_size.is_multiple_of(block_size) {
+        anyhow::bail!("tar_block_size must be a multiple of {block_size}, got {tar_block_size}");
+    }
+
+    Ok(())
+}
+
+// A valid `split_size` leaves room for at least one byte per volume
+fn validate_config_split_size(split_size: Option<u64>) -> Result<()> {
+    if split_size == Some(0) {
+        anyhow::bail!("split_size must be greater than zero");
+    }
+
+    Ok(())
+}
+
+// A valid `copy_concurrency` has at least one copy running
+fn validate_config_copy_concurrency(copy_concurrency: usize) -> Result<()> {
+    if copy_concurrency == 0 {
+        anyhow::bail!("copy_concurrency must be greater than zero");
+    }
+
+    Ok(())
+}
+
+// A valid `snapshot_index` is only set in a build which can keep one
+fn validate_config_snapshot_index(snapshot_index: &Option<path::PathBuf>) -> Result<()> {
+    if snapshot_index.is_some() && !index::is_supported() {
+        anyhow::bail!(
+            "snapshot_index needs pirouette to be built with the \"sqlite-index\" feature"
+        );
+    }
+
+    Ok(())
+}
+
+// A valid `notify` has a backend built in, and everything it needs to send
+fn validate_config_notify(options: &ConfigOpts) -> Result<()> {
+    match options.notify {
+        ConfigOptsNotify::None => {}
+        ConfigOptsNotify::Desktop => {
+            if !notify::is_desktop_supported() {
+                anyhow::bail!(
+                    "notify = \"desktop\" needs pirouette to be built with the \"notify-desktop\" feature"
+                );
+            }
+        }
+        ConfigOptsNotify::Email => {
+            if !notify::is_email_supported() {
+                anyhow::bail!(
+                    "notify = \"email\" needs pirouette to be built with the \"notify-email\" feature"
+                );
+            }
+            if options.notify_email.is_none() {
+                anyhow::bail!("notify = \"email\" needs a notify_email address");
+            }
+            if options.notify_smtp_host.is_none() {
+                anyhow::bail!("notify = \"email\" needs a notify_smtp_host to send through");
+            }
+            if options.notify_smtp_username.is_some() != options.notify_smtp_password.is_some() {
+                anyhow::bail!("notify_smtp_username and notify_smtp_password must be set together");
+            }
+        }
+    }
+
     Ok(())
 }
 
 // A valid `retention` has at least one non-None field
-fn validate_config_retention(retention: &HashMap<ConfigRetentionPeriod, usize>) -> Result<()> {
+fn validate_config_retention(
+    retention: &BTreeMap<ConfigRetentionPeriod, ConfigRetention>,
+) -> Result<()> {
     if retention.is_empty() {
         anyhow::bail!("no retention period was specified");
     }
@@ -209,20 +1254,70 @@ fn validate_config_retention(retention: &HashMap<ConfigRetentionPeriod, usize>)
     Ok(())
 }
 
+/*
+    Path normalisation
+*/
+
+// Resolve relative and symlinked paths, so all later path comparisons are consistent.
+// A path which doesn't exist yet (eg: a new `target`) is resolved via its nearest existing parent.
+fn canonicalize_config_path(path: &path::Path) -> Result<path::PathBuf> {
+    if path.exists() {
+        return fs::canonicalize(path).with_context(|| format!("failed to resolve {path:?}"));
+    }
+
+    let absolute_path =
+        path::absolute(path).with_context(|| format!("failed to resolve {path:?}"))?;
+    match (absolute_path.parent(), absolute_path.file_name()) {
+        (Some(parent), Some(file_name)) => Ok(canonicalize_config_path(parent)?.join(file_name)),
+        _ => Ok(absolute_path),
+    }
+}
+
 pub fn parse_config() -> Result<Config> {
-    // Read configuration file as string
-    let config_file_path = get_config_file_path();
-    let config_file_str = fs::read_to_string(&config_file_path)
+    // Read configuration as string, either inline or from a file
+    let (config_str, config_origin) = read_config_str()?;
+    parse_config_str(&config_str, &config_origin)
+}
+
+pub fn parse_config_file(config_file_path: &path::Path) -> Result<Config> {
+    let config_file_str = fs::read_to_string(config_file_path)
         .with_context(|| format!("failed to read config file: {config_file_path:?}"))?;
+    parse_config_str(&config_file_str, &format!("file: {config_file_path:?}"))
+}
 
+fn parse_config_str(config_str: &str, config_origin: &str) -> Result<Config> {
     // Parse the toml into a struct
-    let config: Config = toml::from_str(&config_file_str)
-        .with_context(|| format!("failed to parse config file: {config_file_path:?}"))?;
+    let mut config: Config = toml::from_str(config_str)
+        .with_context(|| format!("failed to parse config {config_origin}"))?;
 
     // Panic if we have any invalid input
     validate_config_source(&config.source).context("failed to validate source")?;
     validate_config_target(&config.target).context("failed to validate target")?;
+    read_config_retention_file(&mut config).context("failed to read retention_file")?;
     validate_config_retention(&config.retention).context("failed to validate retention")?;
+    validate_config_source_list(&config.source, &config.options.source_list)
+        .context("failed to validate source_list")?;
+    validate_config_tar_block_size(config.options.tar_block_size)
+        .context("failed to validate tar_block_size")?;
+    layout::validate_layout(&config.options.layout).context("failed to validate layout")?;
+    validate_config_split_size(config.options.split_size)
+        .context("failed to validate split_size")?;
+    validate_config_copy_concurrency(config.options.copy_concurrency)
+        .context("failed to validate copy_concurrency")?;
+    layout::validate_tarball_extension(&config.options.tarball_extension)
+        .context("failed to validate tarball_extension")?;
+    validate_config_notify(&config.options).context("failed to validate notify")?;
+    validate_config_snapshot_index(&config.options.snapshot_index)
+        .context("failed to validate snapshot_index")?;
+
+    config.source.path =
+        canonicalize_config_path(&config.source.path).context("failed to resolve source")?;
+    config.target.path =
+        canonicalize_config_path(&config.target.path).context("failed to resolve target")?;
+    for mirror_path in config.target.mirrors.iter_mut() {
+        *mirror_path =
+            canonicalize_config_path(mirror_path).context("failed to resolve target mirror")?;
+    }
 
     Ok(config)
 }
@@ -264,6 +1359,59 @@ mod tests {
         })
     }
 
+    #[test]
+    fn get_config_dir_files_in_name_order() -> Result<()> {
+        let config_dir = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(config_dir.join("30-nested.toml"))?;
+        for name in ["20-mail.toml", "10-home.toml", "README.md"] {
+            fs::write(config_dir.join(name), "")?;
+        }
+
+        let config_files = get_config_dir_files(&config_dir);
+        let empty_dir_files = get_config_dir_files(&config_dir.join("30-nested.toml"));
+
+        fs::remove_dir_all(&config_dir)?;
+        assert_eq!(
+            config_files?,
+            vec![
+                config_dir.join("10-home.toml"),
+                config_dir.join("20-mail.toml")
+            ]
+        );
+        assert!(empty_dir_files.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn read_config_from_inline_envvar() -> Result<()> {
+        temp_env::with_vars(
+            [
+                ("PIROUETTE_CONFIG", Some("[source]\npath = \"/source\"")),
+                ("PIROUETTE_CONFIG_FILE", Some("/test/path.toml")),
+            ],
+            || {
+                let (config_str, _) = read_config_str()?;
+                assert_eq!(config_str, "[source]\npath = \"/source\"");
+                Ok(())
+            },
+        )
+    }
+
+    #[test]
+    fn read_config_with_empty_inline_envvar() {
+        temp_env::with_vars(
+            [
+                ("PIROUETTE_CONFIG", Some("")),
+                ("PIROUETTE_CONFIG_FILE", Some("/test/path.toml")),
+            ],
+            || {
+                // Falls back to the (nonexistent) file path
+                let actual_result = read_config_str();
+                assert!(actual_result.is_err());
+            },
+        )
+    }
+
     #[test]
     fn validate_source_fails_on_nonexistent_file() {
         let test_data = ConfigPath {
@@ -301,4 +1449,225 @@ mod tests {
         assert!(actual_result.is_ok());
         Ok(())
     }
+
+    #[test]
+    fn canonicalize_symlinked_source() -> Result<()> {
+        let real_dir =
+            fs::canonicalize(env::temp_dir())?.join(format!("pirouette_{}", get_random_string(10)));
+        let link_dir = env::temp_dir().join(format!("pirouette_{}", get_random_string(10)));
+        fs::create_dir_all(&real_dir)?;
+        std::os::unix::fs::symlink(&real_dir, &link_dir)?;
+
+        let actual_path = canonicalize_config_path(&link_dir);
+
+        fs::remove_file(&link_dir)?;
+        fs::remove_dir_all(&real_dir)?;
+
+        // An entry yielded under the real path must strip against the resolved source
+        let actual_path = actual_path?;
+        assert_eq!(actual_path, real_dir);
+        assert!(
+            real_dir
+                .join("foo.txt")
+                .strip_prefix(&actual_path)
+                .is_ok()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn canonicalize_relative_source() -> Result<()> {
+        let actual_path = canonicalize_config_path(path::Path::new("./src"))?;
+        assert_eq!(actual_path, env::current_dir()?.join("src"));
+        Ok(())
+    }
+
+    #[test]
+    fn canonicalize_nonexistent_target() -> Result<()> {
+        let actual_path = canonicalize_config_path(path::Path::new("./src/not/yet/created"))?;
+        assert_eq!(actual_path, env::current_dir()?.join("src/not/yet/created"));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_retention_limits() {
+        let retention: BTreeMap<ConfigRetentionPeriod, ConfigRetentionLimit> =
+            toml::from_str("hours = 5\ndays = \"50GB\"\nweeks = \"1.5 KB\"").unwrap();
+
+        assert_eq!(
+            retention[&ConfigRetentionPeriod::Hours],
+            ConfigRetentionLimit::Count(5)
+        );
+        assert_eq!(
+            retention[&ConfigRetentionPeriod::Days],
+            ConfigRetentionLimit::Size(50 * 1024 * 1024 * 1024)
+        );
+        assert_eq!(
+            retention[&ConfigRetentionPeriod::Weeks],
+            ConfigRetentionLimit::Size(1536)
+        );
+
+        assert!(parse_size("50 furlongs").is_err());
+        assert!(parse_size("GB").is_err());
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("90s").unwrap(), 90);
+        assert_eq!(parse_duration("10m").unwrap(), 600);
+        assert_eq!(parse_duration("1h30m").unwrap(), 5400);
+        assert_eq!(parse_duration("1d 2h").unwrap(), 93600);
+
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("10 fortnights").is_err());
+        assert!(parse_duration("18446744073709551615w").is_err());
+    }
+
+    #[test]
+    fn parse_retention_overrides() {
+        let retention: BTreeMap<ConfigRetentionPeriod, ConfigRetention> = toml::from_str(
+            "hours = 5\nyears = { keep = \"1TB\", output_format = \"tarball\", dry_run = true, exclude_modified_within = \"1h\" }",
+        )
+        .unwrap();
+
+        assert_eq!(
+            retention[&ConfigRetentionPeriod::Hours],
+            ConfigRetention {
+                limit: ConfigRetentionLimit::Count(5),
+                output_format: None,
+                dry_run: false,
+                exclude_modified_within: None,
+            }
+        );
+        assert_eq!(
+            retention[&ConfigRetentionPeriod::Years],
+            ConfigRetention {
+                limit: ConfigRetentionLimit::Size(1 << 40),
+                output_format: Some(ConfigOptsOutputFormat::Tarball),
+                dry_run: true,
+                exclude_modified_within: Some(60 * 60),
+            }
+        );
+
+        // Unknown overrides are rejected, rather than silently ignored
+        let unknown_result: Result<BTreeMap<ConfigRetentionPeriod, ConfigRetention>, _> =
+            toml::from_str("hours = { keep = 5, output = \"tarball\" }");
+        assert!(unknown_result.is_err());
+    }
+
+    #[test]
+    fn parse_unknown_retention_periods() {
+        let config_result: Result<Config, _> = toml::from_str(
+            "source.path = \"/source\"\ntarget.path = \"/target\"\nretention = { seconds = 3, fortnights = 2, days = 1 }",
+        );
+
+        // Every unknown period is named, along with the valid ones
+        let error = config_result.unwrap_err().to_string();
+        assert!(error.contains("unknown retention periods `fortnights`, `seconds`"));
+        assert!(error.contains("`minutes`, `hours`, `days`, `weeks`, `months`, `years`"));
+    }
+
+    #[test]
+    fn validate_notify_backends() {
+        let validate_notify = |notify_options: &str| -> Result<()> {
+            let config: Config = toml::from_str(&format!(
+                "source.path = \"/source\"\ntarget.path = \"/target\"\nretention.hours = 1\n\
+                [options]\n{notify_options}"
+            ))?;
+            validate_config_notify(&config.options)
+        };
+        let email_options = "notify = \"email\"\nnotify_email = \"me@example.com\"";
+
+        assert!(validate_notify("notify = \"none\"").is_ok());
+        assert_eq!(
+            validate_notify("notify = \"desktop\"").is_ok(),
+            notify::is_desktop_supported()
+        );
+        assert_eq!(
+            validate_notify(&format!(
+                "{email_options}\nnotify_smtp_host = \"smtp.example.com\""
+            ))
+            .is_ok(),
+            notify::is_email_supported()
+        );
+        assert!(validate_notify(email_options).is_err());
+        assert!(
+            validate_notify(&format!(
+                "{email_options}\nnotify_smtp_host = \"smtp.example.com\"\n\
+                notify_smtp_username = \"me\""
+            ))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn redact_smtp_password() -> Result<()> {
+        let config: Config = toml::from_str(
+            "source.path = \"/source\"\ntarget.path = \"/target\"\nretention.hours = 1\n\
+            [options]\nnotify_smtp_username = \"me\"\nnotify_smtp_password = \"hunter2\"",
+        )?;
+        let password = config.options.notify_smtp_password.as_ref();
+
+        assert_eq!(password.map(|password| password.expose()), Some("hunter2"));
+        assert!(!format!("{config:#?}").contains("hunter2"));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_invalid_regexes() {
+        let config_result: Result<Config, _> = toml::from_str(
+            "source.path = \"/source\"\ntarget.path = \"/target\"\nretention.days = 1\n\
+            options.exclude_regex = [\"\\\\.tmp$\", \"(bak\"]",
+        );
+
+        // The bad pattern is named, rather than only its position
+        let error = config_result.unwrap_err().to_string();
+        assert!(error.contains("invalid regex \"(bak\""));
+    }
+
+    #[test]
+    fn read_retention_files() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", get_random_string(10)));
+        fs::create_dir_all(&test_path)?;
+        fs::write(
+            test_path.join("retention.toml"),
+            "days = 7\nweeks = { keep = 4 }",
+        )?;
+        fs::write(test_path.join("retention.json"), r#"{ "days": 7 }"#)?;
+
+        let read_with_retention_file = |file_name: &str| -> Result<Config> {
+            let mut config: Config = toml::from_str(&format!(
+                "source.path = \"/source\"\ntarget.path = \"/target\"\nretention.hours = 24\n\
+                options.retention_file = {:?}",
+                test_path.join(file_name)
+            ))?;
+            read_config_retention_file(&mut config)?;
+            Ok(config)
+        };
+
+        let toml_config = read_with_retention_file("retention.toml")?;
+        let json_config = read_with_retention_file("retention.json")?;
+        fs::write(test_path.join("conflict.toml"), "hours = 12")?;
+        let conflict_result = read_with_retention_file("conflict.toml");
+
+        fs::remove_dir_all(&test_path)?;
+        assert_eq!(
+            toml_config.retention.keys().collect::<Vec<_>>(),
+            vec![
+                &ConfigRetentionPeriod::Hours,
+                &ConfigRetentionPeriod::Days,
+                &ConfigRetentionPeriod::Weeks
+            ]
+        );
+        assert_eq!(json_config.retention.len(), 2);
+        assert!(
+            conflict_result
+                .unwrap_err()
+                .to_string()
+                .contains("retention.hours is set both inline")
+        );
+        Ok(())
+    }
 }
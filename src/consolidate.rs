@@ -0,0 +1,236 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use crate::PirouetteDirEntry;
+use crate::PirouetteRetentionTarget;
+use crate::audit;
+use crate::clean;
+use crate::configuration::Config;
+use crate::configuration::ConfigRetentionPeriod;
+use crate::current_state;
+use crate::dry_run;
+use crate::index;
+use crate::label;
+use crate::layout;
+use crate::snapshot;
+use crate::volume;
+
+/*
+    Consolidation turns a period's older snapshots into one tarball per coarser calendar period,
+    eg: hundreds of hourly Directory snapshots into one tarball per day, for cold storage.
+*/
+
+pub fn consolidate_snapshots(
+    config: &Config,
+    all_targets: &[PirouetteRetentionTarget],
+    period: &ConfigRetentionPeriod,
+    bucket_period: &ConfigRetentionPeriod,
+) -> Result<()> {
+    let retention_target = all_targets
+        .iter()
+        .find(|retention_target| retention_target.period == *period)
+        .with_context(|| format!("{period} is not a configured retention period"))?;
+    if bucket_period <= period {
+        anyhow::bail!("{period} can only be consolidated by a longer period, not {bucket_period}");
+    }
+    if config.options.append_only {
+        anyhow::bail!("consolidating deletes snapshots, which append_only doesn't allow");
+    }
+
+    // The current bucket is still being filled, and its newest snapshot is needed for rotation
+    let current_bucket = current_state::get_calendar_bucket(bucket_period, &chrono::Local::now());
+
+    for (bucket, snapshots) in get_snapshot_buckets(config, retention_target, bucket_period) {
+        if bucket == current_bucket {
+            continue;
+        }
+        consolidate_bucket(config, retention_target, &bucket, snapshots)?;
+    }
+
+    Ok(())
+}
+
+fn get_snapshot_buckets(
+    config: &Config,
+    retention_target: &PirouetteRetentionTarget,
+    bucket_period: &ConfigRetentionPeriod,
+) -> BTreeMap<String, Vec<PirouetteDirEntry>> {
+    let mut snapshot_buckets: BTreeMap<String, Vec<PirouetteDirEntry>> = BTreeMap::new();
+
+    // Labelled snapshots are kept as they are, and stray files aren't snapshots at all
+    for snapshot in layout::get_snapshot_entries(retention_target)
+        .into_iter()
+        .filter(|snapshot| layout::get_snapshot_format(config, &snapshot.path).is_some())
+        .filter(|snapshot| !label::is_labelled(&snapshot.path))
+    {
+        let bucket =
+            current_state::get_calendar_bucket(bucket_period, &get_snapshot_time(&snapshot).into());
+        snapshot_buckets
+            .entry(bucket)
+            .or_default()
+            .push(snapshot);
+    }
+
+    snapshot_buckets
+}
+
+// As for `prune`, a snapshot's time is read from its name where it can be
+fn get_snapshot_time(snapshot: &PirouetteDirEntry) -> SystemTime {
+    layout::parse_snapshot_name_time(&snapshot.path).unwrap_or(snapshot.timestamp)
+}
+
+// Keep the newest snapshot in the bucket as a tarball, and delete the rest
+fn consolidate_bucket(
+    config: &Config,
+    retention_target: &PirouetteRetentionTarget,
+    bucket: &str,
+    mut snapshots: Vec<PirouetteDirEntry>,
+) -> Result<()> {
+    snapshots.sort_by_key(get_snapshot_time);
+    let Some(newest_snapshot) = snapshots.pop() else {
+        return Ok(());
+    };
+
+    // An already consolidated bucket is left alone
+    if snapshots.is_empty() && !newest_snapshot.path.is_dir() {
+        return Ok(());
+    }
+    log::info!(
+        "Consolidating {} {retention_target} snapshots from {bucket} into {newest_snapshot}",
+        snapshots.len() + 1
+    );
+
+    let tarball_path = match newest_snapshot.path.is_dir() {
+        true => layout::with_tarball_extension(config, &newest_snapshot.path),
+        false => newest_snapshot.path.clone(),
+    };
+    if newest_snapshot.path.is_dir() {
+        dry_run!(
+            retention_target.dry_run,
+            format!("{tarball_path:?} will not be created"),
+            {
+                snapshot::archive_directory_snapshot(config, &newest_snapshot, &tarball_path)
+                    .with_context(|| format!("failed to consolidate {bucket}"))?;
+                index::add_snapshot(config, &retention_target.period, &tarball_path);
+                audit::record_action(config, audit::AuditAction::Create, &tarball_path)
+            }
+        )?;
+        snapshots.push(newest_snapshot);
+    }
+
+    // Nothing is deleted unless the tarball it was consolidated into is actually there
+    if !retention_target.dry_run && volume::get_snapshot_parts(&tarball_path).is_empty() {
+        anyhow::bail!("{tarball_path:?} wasn't written, so {bucket} was left as it is");
+    }
+
+    dry_run!(
+        retention_target.dry_run,
+        format!("consolidated snapshots will not be deleted"),
+        {
+            clean::delete_snapshots(config, snapshots);
+            // This function doesn't fail, but dry_run!() expects a Result<>
+            Ok::<(), anyhow::Error>(())
+        }
+    )
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn test_consolidate_snapshots() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\nretention.hours = 24"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+
+        // Three hourly snapshots from the same day, long ago
+        let day_start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        for hour in 0..3 {
+            let snapshot_path = target_path.join(format!("hours/snapshot_{hour}"));
+            fs::create_dir_all(&snapshot_path)?;
+            fs::write(snapshot_path.join("foo"), hour.to_string())?;
+            fs::File::open(&snapshot_path)?
+                .set_modified(day_start + Duration::from_secs(hour * 60))?;
+        }
+
+        let result = consolidate_snapshots(
+            &config,
+            &all_targets,
+            &ConfigRetentionPeriod::Hours,
+            &ConfigRetentionPeriod::Years,
+        );
+        let mut remaining: Vec<String> = fs::read_dir(target_path.join("hours"))?
+            .map(|entry| {
+                entry
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        remaining.sort();
+        let tarball_mtime = fs::metadata(target_path.join("hours/snapshot_2.tgz"))?.modified()?;
+
+        fs::remove_dir_all(&target_path)?;
+        result?;
+        assert_eq!(remaining, vec!["snapshot_2.tgz"]);
+        assert_eq!(tarball_mtime, day_start + Duration::from_secs(120));
+        Ok(())
+    }
+
+    #[test]
+    fn test_consolidate_ignores_stray_files() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\nretention.hours = 24"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+
+        // Bucketed by their names, though they were only just modified
+        for name in ["2001-01-01T00:00:00", "2001-01-01T01:00:00"] {
+            let snapshot_path = target_path.join("hours").join(name);
+            fs::create_dir_all(&snapshot_path)?;
+            fs::write(snapshot_path.join("foo"), name)?;
+        }
+        // A stray file, newer than either snapshot, but in the same bucket
+        let stray_path = target_path.join("hours/notes.txt");
+        fs::write(&stray_path, "notes")?;
+        fs::File::options()
+            .write(true)
+            .open(&stray_path)?
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000))?;
+
+        let result = consolidate_snapshots(
+            &config,
+            &all_targets,
+            &ConfigRetentionPeriod::Hours,
+            &ConfigRetentionPeriod::Years,
+        );
+        let mut remaining: Vec<String> = fs::read_dir(target_path.join("hours"))?
+            .map(|entry| {
+                entry
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        remaining.sort();
+
+        fs::remove_dir_all(&target_path)?;
+        result?;
+        assert_eq!(remaining, vec!["2001-01-01T01:00:00.tgz", "notes.txt"]);
+        Ok(())
+    }
+}
@@ -1,41 +1,75 @@
 use anyhow::{Context, Result};
+use std::fmt;
 use std::fs;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use crate::DisplayVec;
 use crate::PirouetteDirEntry;
 use crate::PirouetteRetentionTarget;
 use crate::configuration::Config;
+use crate::configuration::ConfigOptsAgeComparison;
+use crate::configuration::ConfigOptsBoundary;
+use crate::configuration::ConfigOptsFutureTimestamp;
+use crate::configuration::ConfigOptsOnDirectoryError;
+use crate::configuration::ConfigOptsOutputFormat;
 use crate::configuration::ConfigRetentionPeriod;
 use crate::dry_run;
+use crate::layout;
+use crate::pause;
 
 pub fn get_rotation_targets(
     config: &Config,
     all_targets: Vec<PirouetteRetentionTarget>,
+    since: Option<SystemTime>,
 ) -> Result<Vec<PirouetteRetentionTarget>> {
     let mut rotation_targets = vec![];
 
     for retention_target in all_targets {
+        if let Some(paused_until) = pause::get_paused_until(&retention_target) {
+            let paused_until: chrono::DateTime<chrono::Local> = paused_until.into();
+            log::info!("{retention_target} is paused until {paused_until}, skipping it");
+            continue;
+        }
         log::info!("Checking existing state for {retention_target}");
 
-        create_target_directory(config, &retention_target)?;
-
-        match get_newest_directory_entry(&retention_target) {
-            // If there's existing snapshots, check if they're old enough to need rotation
-            Some(snapshot) => {
-                if has_target_snapshot_aged_out(&retention_target, &snapshot) {
-                    log::info!("{retention_target} requires a new snapshot");
-                    rotation_targets.push(retention_target);
-                } else {
-                    log::info!("{retention_target} does not require a new snapshot",);
-                }
-            }
+        let newest_snapshot = get_newest_directory_entry(config, &retention_target);
+        if let Some((_, newest_format)) = &newest_snapshot
+            && *newest_format != retention_target.output_format
+        {
+            log::info!(
+                "{retention_target}'s newest snapshot is a {newest_format:?}, but new snapshots will be a {:?}",
+                retention_target.output_format
+            );
+        }
+        let newest_snapshot = newest_snapshot.map(|(entry, _)| entry);
 
-            // If there's no previous snapshots, we always need to rotate
-            None => {
+        match get_rotation_verdict(config, &retention_target, newest_snapshot.as_ref(), since)? {
+            RotationVerdict::Empty => {
                 log::info!("{retention_target} is empty and requires a new snapshot");
                 rotation_targets.push(retention_target);
             }
+            RotationVerdict::AgedOut => {
+                log::info!("{retention_target} requires a new snapshot");
+                rotation_targets.push(retention_target);
+            }
+            RotationVerdict::PredatesSince => {
+                log::info!("{retention_target} is older than --since and requires a new snapshot");
+                rotation_targets.push(retention_target);
+            }
+            RotationVerdict::Current => {
+                log::info!("{retention_target} does not require a new snapshot");
+            }
+            RotationVerdict::FutureBlocked => {
+                log::warn!(
+                    "{retention_target} has a snapshot in the future, is the system clock correct? Not rotating until it's in the past"
+                );
+            }
+            RotationVerdict::FutureRotated => {
+                log::warn!(
+                    "{retention_target} has a snapshot in the future, is the system clock correct? Rotating anyway"
+                );
+                rotation_targets.push(retention_target);
+            }
         }
     }
 
@@ -46,11 +80,255 @@ pub fn get_rotation_targets(
     Ok(rotation_targets)
 }
 
-fn create_target_directory(
+#[derive(Debug, PartialEq)]
+enum RotationVerdict {
+    Empty,
+    AgedOut,
+    PredatesSince,
+    Current,
+    FutureBlocked,
+    FutureRotated,
+}
+
+impl fmt::Display for RotationVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RotationVerdict::Empty => write!(f, "rotate (empty)"),
+            RotationVerdict::AgedOut => write!(f, "rotate (aged out)"),
+            RotationVerdict::PredatesSince => write!(f, "rotate (older than --since)"),
+            RotationVerdict::Current => write!(f, "skip"),
+            RotationVerdict::FutureBlocked => write!(f, "skip (in the future)"),
+            RotationVerdict::FutureRotated => write!(f, "rotate (in the future)"),
+        }
+    }
+}
+
+fn get_rotation_verdict(
     config: &Config,
     retention_target: &PirouetteRetentionTarget,
-) -> Result<()> {
-    if retention_target.path.exists() {
+    newest_snapshot: Option<&PirouetteDirEntry>,
+    since: Option<SystemTime>,
+) -> Result<RotationVerdict> {
+    // If there's no previous snapshots, we always need to rotate
+    let Some(snapshot) = newest_snapshot else {
+        return Ok(RotationVerdict::Empty);
+    };
+
+    // A bogus future timestamp would otherwise never age out, so it's up to the user
+    if snapshot.timestamp > SystemTime::now() {
+        return match config.options.future_timestamp {
+            ConfigOptsFutureTimestamp::Block => Ok(RotationVerdict::FutureBlocked),
+            ConfigOptsFutureTimestamp::Rotate => Ok(RotationVerdict::FutureRotated),
+            ConfigOptsFutureTimestamp::Error => anyhow::bail!(
+                "{retention_target} has a snapshot in the future, is the system clock correct? {snapshot}"
+            ),
+        };
+    }
+
+    // If there's existing snapshots, check if they're old enough to need rotation
+    let has_aged_out = match config.options.boundary {
+        ConfigOptsBoundary::Rolling => {
+            has_target_snapshot_aged_out(config, retention_target, snapshot)
+        }
+        ConfigOptsBoundary::Calendar => {
+            has_snapshot_crossed_boundary(retention_target, snapshot, chrono::Local::now())
+        }
+    };
+
+    if has_aged_out {
+        Ok(RotationVerdict::AgedOut)
+    } else if has_snapshot_predated_since(snapshot, since) {
+        Ok(RotationVerdict::PredatesSince)
+    } else {
+        Ok(RotationVerdict::Current)
+    }
+}
+
+// A summary of each period's rotation decision, for `--plan`. Nothing is created or changed.
+pub fn print_rotation_plan(
+    config: &Config,
+    all_targets: &[PirouetteRetentionTarget],
+    since: Option<SystemTime>,
+) {
+    println!(
+        "{:<8} {:<22} {:<9} {:<12} {:<9} {:<20} VERDICT",
+        "PERIOD", "NEWEST", "AGE", "THRESHOLD", "SNAPSHOTS", "KEEP"
+    );
+
+    for retention_target in all_targets {
+        let snapshots = layout::get_snapshot_entries(retention_target);
+        let newest_snapshot =
+            get_newest_snapshot(config, snapshots.clone()).map(|(entry, _)| entry);
+        let newest_snapshot = newest_snapshot.as_ref();
+
+        let newest_name = newest_snapshot
+            .and_then(|entry| entry.path.file_name())
+            .map_or("-".to_string(), |name| name.to_string_lossy().to_string());
+        let newest_age = newest_snapshot
+            .and_then(|entry| {
+                SystemTime::now()
+                    .duration_since(entry.timestamp)
+                    .ok()
+            })
+            .map_or("-".to_string(), |age| format_age(age.as_secs()));
+        let threshold = match config.options.boundary {
+            ConfigOptsBoundary::Rolling => format_age(get_age_threshold(
+                &retention_target.period,
+                config.options.age_grace,
+            )),
+            ConfigOptsBoundary::Calendar => format!(
+                "new {}",
+                retention_target
+                    .period
+                    .to_string()
+                    .trim_end_matches('s')
+            ),
+        };
+        let verdict = get_rotation_verdict(config, retention_target, newest_snapshot, since)
+            .map_or("error (in the future)".to_string(), |verdict| {
+                verdict.to_string()
+            });
+
+        println!(
+            "{:<8} {:<22} {:<9} {:<12} {:<9} {:<20} {verdict}",
+            retention_target.to_string(),
+            newest_name,
+            newest_age,
+            threshold,
+            snapshots.len(),
+            retention_target.limit.to_string(),
+        );
+    }
+}
+
+// The two largest units of a number of seconds, eg: "2d 3h" or "5m 0s"
+fn format_age(seconds: u64) -> String {
+    let units = [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)];
+
+    let Some(largest_index) = units
+        .iter()
+        .position(|(_, size)| seconds >= *size)
+    else {
+        return "0s".to_string();
+    };
+    units[largest_index..]
+        .iter()
+        .take(2)
+        .scan(seconds, |remaining, (name, size)| {
+            let count = *remaining / size;
+            *remaining %= size;
+            Some(format!("{count}{name}"))
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Set up the target's directories ahead of the first run, without taking any snapshots.
+// Safe to run again, as existing directories are left alone.
+pub fn initialise_target(config: &Config, all_targets: &[PirouetteRetentionTarget]) -> Result<()> {
+    for retention_target in all_targets {
+        create_target_directory(retention_target)?;
+    }
+    check_target_writable(config)?;
+
+    println!("{}", config.target.path.display());
+    for retention_target in all_targets {
+        println!(
+            "  {:<7} {}",
+            retention_target.to_string(),
+            retention_target.path.display()
+        );
+    }
+
+    Ok(())
+}
+
+// Fail fast on a broken destination, even on runs where no snapshots are due
+pub fn check_target_writable(config: &Config) -> Result<()> {
+    // The target may not exist yet, in which case we'll need to create it inside its parent
+    let probe_directory = config
+        .target
+        .path
+        .ancestors()
+        .find(|path| path.exists())
+        .with_context(|| format!("no part of the target path {:?} exists", config.target.path))?;
+    let probe_path = probe_directory.join(format!(".pirouette-write-test-{}", std::process::id()));
+
+    dry_run!(
+        config.options.dry_run,
+        format!("{probe_directory:?} will not be checked for write access"),
+        {
+            fs::write(&probe_path, "")
+                .and_then(|_| fs::remove_file(&probe_path))
+                .with_context(|| format!("target path {probe_directory:?} is not writable"))
+        }
+    )
+}
+
+// Turn off options the target's filesystem can't support, eg: hardlinks on FAT, so they're
+// not attempted file by file partway through a run
+pub fn disable_unsupported_features(config: &mut Config) {
+    if config.options.staging && !can_target_hardlink(config) {
+        log::warn!(
+            "Target {:?} doesn't support hardlinks, disabling staging so each period gets its own snapshot",
+            config.target.path
+        );
+        config.options.staging = false;
+    }
+}
+
+fn can_target_hardlink(config: &Config) -> bool {
+    if config.options.dry_run {
+        log::debug!("[DRY RUN] target will not be checked for hardlink support");
+        return true;
+    }
+
+    let Some(probe_directory) = config
+        .target
+        .path
+        .ancestors()
+        .find(|path| path.exists())
+    else {
+        return true;
+    };
+    let probe_path = probe_directory.join(format!(".pirouette-link-test-{}", std::process::id()));
+    let link_path = probe_path.with_extension("link");
+
+    let can_hardlink = fs::write(&probe_path, "")
+        .and_then(|_| fs::hard_link(&probe_path, &link_path))
+        .inspect_err(|e| log::debug!("Hardlink probe in {probe_directory:?} failed: {e}"))
+        .is_ok();
+
+    let _ = fs::remove_file(&link_path);
+    let _ = fs::remove_file(&probe_path);
+    can_hardlink
+}
+
+// Returns the periods whose directory exists or was created, and the errors for any others.
+// Unless `on_directory_error = "skip"`, the first error fails the run instead.
+pub fn create_target_directories(
+    config: &Config,
+    all_targets: Vec<PirouetteRetentionTarget>,
+) -> Result<(Vec<PirouetteRetentionTarget>, Vec<anyhow::Error>)> {
+    let mut created_targets = vec![];
+    let mut errors = vec![];
+
+    for retention_target in all_targets {
+        match create_target_directory(&retention_target) {
+            Ok(()) => created_targets.push(retention_target),
+            Err(e) if config.options.on_directory_error == ConfigOptsOnDirectoryError::Skip => {
+                log::warn!("Skipping {retention_target}: {e:#}");
+                errors.push(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok((created_targets, errors))
+}
+
+pub fn create_target_directory(retention_target: &PirouetteRetentionTarget) -> Result<()> {
+    if retention_target.path.is_dir() {
         return Ok(());
     }
     log::info!(
@@ -59,7 +337,7 @@ fn create_target_directory(
     );
 
     dry_run!(
-        config.options.dry_run,
+        retention_target.dry_run,
         format!("{:?} directory will not be created", retention_target.path),
         {
             fs::create_dir_all(&retention_target.path)
@@ -68,19 +346,13 @@ fn create_target_directory(
     )
 }
 
-fn get_newest_directory_entry(
+// Returns the newest snapshot along with its format, which may not be the period's current
+// `output_format`, eg: after it's changed
+pub fn get_newest_directory_entry(
+    config: &Config,
     retention_target: &PirouetteRetentionTarget,
-) -> Option<PirouetteDirEntry> {
-    let entries = match fs::read_dir(&retention_target.path) {
-        Ok(entries) => entries,
-        Err(_) => return None,
-    };
-
-    // Convert to abstracted testable type
-    let typed_entries: Vec<_> = entries
-        .filter_map(|entry| entry.ok())
-        .map(|entry| entry.into())
-        .collect();
+) -> Option<(PirouetteDirEntry, ConfigOptsOutputFormat)> {
+    let typed_entries = layout::get_snapshot_entries(retention_target);
 
     log::info!(
         "{retention_target} contains {} existing entries",
@@ -88,21 +360,59 @@ fn get_newest_directory_entry(
     );
     log::debug!("{retention_target} contents: {typed_entries:?}");
 
-    // Return the newest item in the directory
-    typed_entries
+    get_newest_snapshot(config, typed_entries)
+}
+
+// Anything which isn't a snapshot is never mistaken for the newest, eg: a stray checksum file
+fn get_newest_snapshot(
+    config: &Config,
+    entries: Vec<PirouetteDirEntry>,
+) -> Option<(PirouetteDirEntry, ConfigOptsOutputFormat)> {
+    entries
         .into_iter()
-        .max_by_key(|entry: &PirouetteDirEntry| entry.timestamp)
+        .filter_map(|entry| {
+            layout::get_snapshot_format(config, &entry.path).map(|format| (entry, format))
+        })
+        .max_by_key(|(entry, _)| entry.timestamp)
 }
 
 fn has_target_snapshot_aged_out(
+    config: &Config,
     retention_target: &PirouetteRetentionTarget,
     snapshot: &PirouetteDirEntry,
 ) -> bool {
     log::debug!("Checking age of snapshot: {snapshot:?}");
 
     let snapshot_age = SystemTime::now().duration_since(snapshot.timestamp);
+    let age_threshold = get_age_threshold(&retention_target.period, config.options.age_grace);
 
-    let age_threshold = match retention_target.period {
+    match snapshot_age {
+        Err(_) => {
+            log::warn!("Age was in the future for {snapshot}, is the system clock correct?",);
+            false
+        }
+        Ok(snapshot_age) => {
+            is_past_age_threshold(&config.options.age_comparison, snapshot_age, age_threshold)
+        }
+    }
+}
+
+// Ages are compared in whole seconds, so a snapshot exactly one period old is either due or
+// not depending on `age_comparison`, rather than on sub-second timing
+pub fn is_past_age_threshold(
+    age_comparison: &ConfigOptsAgeComparison,
+    age: Duration,
+    age_threshold: u64,
+) -> bool {
+    match age_comparison {
+        ConfigOptsAgeComparison::AtLeast => age.as_secs() >= age_threshold,
+        ConfigOptsAgeComparison::MoreThan => age.as_secs() > age_threshold,
+    }
+}
+
+pub fn get_age_threshold(period: &ConfigRetentionPeriod, age_grace: u64) -> u64 {
+    let age_threshold: u64 = match period {
+        ConfigRetentionPeriod::Minutes => 60,
         ConfigRetentionPeriod::Hours => 60 * 60,
         ConfigRetentionPeriod::Days => 24 * 60 * 60,
         ConfigRetentionPeriod::Weeks => 7 * 24 * 60 * 60,
@@ -110,24 +420,70 @@ fn has_target_snapshot_aged_out(
         ConfigRetentionPeriod::Years => 365 * 24 * 60 * 60,
     };
 
-    match snapshot_age {
-        Err(_) => {
-            log::warn!("Age was in the future for {snapshot}, is the system clock correct?",);
-            false
-        }
-        Ok(snapshot_age) => snapshot_age.as_secs() >= age_threshold,
+    // Absorbs scheduling drift, so a snapshot taken a few seconds late still rotates on time.
+    // Capped at a tenth of the period, so short periods aren't swallowed by it entirely.
+    age_threshold - age_grace.min(age_threshold / 10)
+}
+
+// In calendar mode, a snapshot is stale once now is in a different hour/day/week/etc. bucket
+fn has_snapshot_crossed_boundary(
+    retention_target: &PirouetteRetentionTarget,
+    snapshot: &PirouetteDirEntry,
+    now: chrono::DateTime<chrono::Local>,
+) -> bool {
+    log::debug!("Checking calendar boundary of snapshot: {snapshot:?}");
+
+    let snapshot_time: chrono::DateTime<chrono::Local> = snapshot.timestamp.into();
+    if snapshot_time > now {
+        log::warn!("Age was in the future for {snapshot}, is the system clock correct?",);
+        return false;
     }
+
+    get_calendar_bucket(&retention_target.period, &snapshot_time)
+        != get_calendar_bucket(&retention_target.period, &now)
+}
+
+// The calendar minute, hour, day, ISO week, month, or year that `time` falls in, eg: "2024-W01"
+pub fn get_calendar_bucket(
+    period: &ConfigRetentionPeriod,
+    time: &chrono::DateTime<chrono::Local>,
+) -> String {
+    let bucket_format = match period {
+        ConfigRetentionPeriod::Minutes => "%Y-%m-%dT%H:%M",
+        ConfigRetentionPeriod::Hours => "%Y-%m-%dT%H",
+        ConfigRetentionPeriod::Days => "%Y-%m-%d",
+        ConfigRetentionPeriod::Weeks => "%G-W%V",
+        ConfigRetentionPeriod::Months => "%Y-%m",
+        ConfigRetentionPeriod::Years => "%Y",
+    };
+
+    time.format(bucket_format).to_string()
+}
+
+// With --since, any snapshot older than that instant is stale regardless of its period
+fn has_snapshot_predated_since(snapshot: &PirouetteDirEntry, since: Option<SystemTime>) -> bool {
+    since.is_some_and(|since| snapshot.timestamp < since)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::configuration::ConfigRetentionLimit;
     use std::path::PathBuf;
-    use std::time::Duration;
 
     #[test]
-    fn test_has_target_snapshot_aged_out() {
+    fn test_has_target_snapshot_aged_out() -> Result<()> {
+        let get_config = |age_grace: u64| -> Result<Config> {
+            Ok(toml::from_str(&format!(
+                "source.path = \"/source\"\ntarget.path = \"/target\"\nretention.hours = 1\n\
+                [options]\nage_grace = {age_grace}"
+            ))?)
+        };
+        let config = get_config(0)?;
+        let graced_config = get_config(60)?;
+
         let test_params: Vec<(ConfigRetentionPeriod, u64)> = vec![
+            (ConfigRetentionPeriod::Minutes, 60),
             (ConfigRetentionPeriod::Hours, 3600),
             (ConfigRetentionPeriod::Days, 86400),
             (ConfigRetentionPeriod::Weeks, 604800),
@@ -139,14 +495,20 @@ mod tests {
             let retention_target = PirouetteRetentionTarget {
                 period: retention_period,
                 path: PathBuf::from("/tmp"),
-                max_count: 1,
+                period_path: PathBuf::from("/tmp"),
+                snapshot_pattern: "/tmp/*".to_string(),
+                limit: ConfigRetentionLimit::Count(1),
+                output_format: ConfigOptsOutputFormat::Directory,
+                dry_run: false,
+                exclude_modified_within: None,
             };
 
             let expired_snapshot = PirouetteDirEntry {
                 path: PathBuf::from("/tmp/fake"),
                 timestamp: SystemTime::now() - Duration::from_secs(threshold_seconds),
             };
-            let expired_result = has_target_snapshot_aged_out(&retention_target, &expired_snapshot);
+            let expired_result =
+                has_target_snapshot_aged_out(&config, &retention_target, &expired_snapshot);
             assert!(expired_result);
 
             let fresh_snapshot = PirouetteDirEntry {
@@ -154,8 +516,261 @@ mod tests {
                 // This assumes the function will return within 1 second
                 timestamp: SystemTime::now() - Duration::from_secs(threshold_seconds - 1),
             };
-            let fresh_result = has_target_snapshot_aged_out(&retention_target, &fresh_snapshot);
+            let fresh_result =
+                has_target_snapshot_aged_out(&config, &retention_target, &fresh_snapshot);
             assert!(!fresh_result);
+
+            // Within the grace period, the same snapshot is old enough
+            let graced_result =
+                has_target_snapshot_aged_out(&graced_config, &retention_target, &fresh_snapshot);
+            assert!(graced_result);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_past_age_threshold() {
+        let at_least = ConfigOptsAgeComparison::AtLeast;
+        let more_than = ConfigOptsAgeComparison::MoreThan;
+
+        // Exactly on the threshold, only `at_least` is due
+        assert!(is_past_age_threshold(
+            &at_least,
+            Duration::from_secs(3600),
+            3600
+        ));
+        assert!(!is_past_age_threshold(
+            &more_than,
+            Duration::from_secs(3600),
+            3600
+        ));
+        // Part of a second over still counts as exactly on it
+        assert!(!is_past_age_threshold(
+            &more_than,
+            Duration::from_millis(3_600_900),
+            3600
+        ));
+        assert!(is_past_age_threshold(
+            &more_than,
+            Duration::from_secs(3601),
+            3600
+        ));
+        assert!(!is_past_age_threshold(
+            &at_least,
+            Duration::from_secs(3599),
+            3600
+        ));
+    }
+
+    #[test]
+    fn test_future_timestamp_verdict() {
+        let retention_target = PirouetteRetentionTarget {
+            period: ConfigRetentionPeriod::Days,
+            path: PathBuf::from("/tmp"),
+            period_path: PathBuf::from("/tmp"),
+            snapshot_pattern: "/tmp/*".to_string(),
+            limit: ConfigRetentionLimit::Count(1),
+            output_format: ConfigOptsOutputFormat::Directory,
+            dry_run: false,
+            exclude_modified_within: None,
+        };
+        let future_snapshot = PirouetteDirEntry {
+            path: PathBuf::from("/tmp/fake"),
+            timestamp: SystemTime::now() + Duration::from_secs(3600),
+        };
+        let get_verdict = |future_timestamp: &str| {
+            let config: Config = toml::from_str(&format!(
+                "source.path = \"/tmp\"\ntarget.path = \"/tmp\"\nretention.days = 1\n[options]\nfuture_timestamp = \"{future_timestamp}\""
+            ))
+            .unwrap();
+            get_rotation_verdict(&config, &retention_target, Some(&future_snapshot), None)
+        };
+
+        assert_eq!(
+            get_verdict("block").unwrap(),
+            RotationVerdict::FutureBlocked
+        );
+        assert_eq!(
+            get_verdict("rotate").unwrap(),
+            RotationVerdict::FutureRotated
+        );
+        assert!(get_verdict("error").is_err());
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(0), "0s");
+        assert_eq!(format_age(59), "59s");
+        assert_eq!(format_age(300), "5m 0s");
+        assert_eq!(format_age(2 * 86400 + 3 * 3600 + 59), "2d 3h");
+    }
+
+    #[test]
+    fn test_has_snapshot_crossed_boundary() {
+        let local_time = |datetime: &str| {
+            chrono::NaiveDateTime::parse_from_str(datetime, "%Y-%m-%dT%H:%M")
+                .unwrap()
+                .and_local_timezone(chrono::Local)
+                .unwrap()
+        };
+        let snapshot = PirouetteDirEntry {
+            path: PathBuf::from("/tmp/fake"),
+            timestamp: local_time("2025-01-05T23:55").into(),
+        };
+
+        // 2025-01-05 is a Sunday, so the next day is also a new ISO week
+        let test_params: Vec<(ConfigRetentionPeriod, &str, bool)> = vec![
+            (ConfigRetentionPeriod::Minutes, "2025-01-05T23:55", false),
+            (ConfigRetentionPeriod::Minutes, "2025-01-05T23:56", true),
+            (ConfigRetentionPeriod::Hours, "2025-01-05T23:59", false),
+            (ConfigRetentionPeriod::Days, "2025-01-05T23:59", false),
+            (ConfigRetentionPeriod::Days, "2025-01-06T00:05", true),
+            (ConfigRetentionPeriod::Weeks, "2025-01-06T00:05", true),
+            (ConfigRetentionPeriod::Months, "2025-01-31T23:59", false),
+            (ConfigRetentionPeriod::Years, "2025-12-31T23:59", false),
+            (ConfigRetentionPeriod::Years, "2026-01-01T00:00", true),
+            // Snapshots from the future never rotate
+            (ConfigRetentionPeriod::Hours, "2025-01-05T12:00", false),
+        ];
+
+        for (retention_period, now, expected) in test_params {
+            let retention_target = PirouetteRetentionTarget {
+                period: retention_period,
+                path: PathBuf::from("/tmp"),
+                period_path: PathBuf::from("/tmp"),
+                snapshot_pattern: "/tmp/*".to_string(),
+                limit: ConfigRetentionLimit::Count(1),
+                output_format: ConfigOptsOutputFormat::Directory,
+                dry_run: false,
+                exclude_modified_within: None,
+            };
+            let result =
+                has_snapshot_crossed_boundary(&retention_target, &snapshot, local_time(now));
+            assert_eq!(result, expected, "{retention_target} at {now}");
+        }
+    }
+
+    #[test]
+    fn test_has_snapshot_predated_since() {
+        let snapshot = PirouetteDirEntry {
+            path: PathBuf::from("/tmp/fake"),
+            timestamp: SystemTime::now() - Duration::from_secs(60),
+        };
+
+        assert!(!has_snapshot_predated_since(&snapshot, None));
+        assert!(has_snapshot_predated_since(
+            &snapshot,
+            Some(SystemTime::now())
+        ));
+        assert!(!has_snapshot_predated_since(
+            &snapshot,
+            Some(SystemTime::now() - Duration::from_secs(3600))
+        ));
+    }
+
+    #[test]
+    fn test_initialise_target_is_idempotent() -> Result<()> {
+        let target_path = std::env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\nretention = {{ hours = 1, days = 1 }}"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+
+        let first_result = initialise_target(&config, &all_targets);
+        let second_result = initialise_target(&config, &all_targets);
+        let created = ["hours", "days"].map(|period| target_path.join(period).is_dir());
+
+        fs::remove_dir_all(&target_path)?;
+        first_result?;
+        second_result?;
+        assert_eq!(created, [true, true]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_supported_features_stay_enabled() -> Result<()> {
+        let target_path = std::env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&target_path)?;
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\nretention.hours = 1\n[options]\nstaging = true"
+        ))?;
+
+        disable_unsupported_features(&mut config);
+        let probe_leftovers = fs::read_dir(&target_path)?.count();
+
+        fs::remove_dir_all(&target_path)?;
+        assert!(config.options.staging);
+        assert_eq!(probe_leftovers, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_newest_snapshot_ignores_sidecars() -> Result<()> {
+        let target_path = std::env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(target_path.join("2024-01-01T00:00:00"))?;
+
+        // Oldest to newest, after a change from Directory to Tarball, with a stray checksum
+        let mut entries = vec![];
+        for (i, name) in [
+            "2024-01-01T00:00:00",
+            "2024-01-02T00:00:00.tgz",
+            "foo.sha256",
+        ]
+        .iter()
+        .enumerate()
+        {
+            let path = target_path.join(name);
+            if !path.exists() {
+                fs::write(&path, "")?;
+            }
+            entries.push(PirouetteDirEntry {
+                path,
+                timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(i as u64),
+            });
         }
+
+        let mut config: Config = toml::from_str(
+            "source.path = \"/source\"\ntarget.path = \"/target\"\nretention.hours = 1",
+        )?;
+        let newest_snapshot = get_newest_snapshot(&config, entries.clone());
+        config.options.tarball_extension = "tar.gz".to_string();
+        let renamed_newest_snapshot = get_newest_snapshot(&config, entries.clone());
+        fs::remove_dir_all(&target_path)?;
+
+        assert_eq!(
+            newest_snapshot,
+            Some((entries[1].clone(), ConfigOptsOutputFormat::Tarball))
+        );
+        // A tarball with another extension is just a stray file
+        assert_eq!(
+            renamed_newest_snapshot,
+            Some((entries[0].clone(), ConfigOptsOutputFormat::Directory))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_errors_can_be_skipped() -> Result<()> {
+        let target_path = std::env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&target_path)?;
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\n\
+            retention.hours = 1\nretention.days = 1"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+
+        // A file in the way of the hours directory
+        fs::write(&all_targets[0].path, "")?;
+        let fail_result = create_target_directories(&config, all_targets.clone());
+        config.options.on_directory_error = ConfigOptsOnDirectoryError::Skip;
+        let skip_result = create_target_directories(&config, all_targets.clone());
+        fs::remove_dir_all(&target_path)?;
+
+        let (created_targets, errors) = skip_result?;
+        assert!(fail_result.is_err());
+        assert_eq!(created_targets.len(), 1);
+        assert_eq!(created_targets[0].period, ConfigRetentionPeriod::Days);
+        assert_eq!(errors.len(), 1);
+        Ok(())
     }
 }
@@ -0,0 +1,166 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::archive;
+use crate::configuration::Config;
+use crate::configuration::ConfigOptsOutputFormat;
+use crate::layout;
+use crate::manifest;
+
+/*
+    A digest of a snapshot's contents, so two snapshots can be compared whatever their format.
+    Only each entry's inner path and contents are hashed, never its times or permissions.
+*/
+
+pub fn get_snapshot_digest(config: &Config, snapshot_path: &Path) -> Result<String> {
+    get_snapshot_digest_and_count(config, snapshot_path).map(|(digest, _)| digest)
+}
+
+// Along with how many files and links the digest covers
+pub fn get_snapshot_digest_and_count(
+    config: &Config,
+    snapshot_path: &Path,
+) -> Result<(String, usize)> {
+    let mut entry_hashes = match layout::get_snapshot_format(config, snapshot_path) {
+        Some(ConfigOptsOutputFormat::Directory) => get_directory_entry_hashes(snapshot_path)?,
+        Some(ConfigOptsOutputFormat::Tarball) => get_tarball_entry_hashes(snapshot_path)?,
+        None => anyhow::bail!("{snapshot_path:?} is not a snapshot"),
+    };
+    entry_hashes.sort();
+
+    let mut hasher = Sha256::new();
+    for (inner_path, hash) in &entry_hashes {
+        hasher.update(format!("{hash}  {}\n", inner_path.display()));
+    }
+    Ok((manifest::format_hash(hasher), entry_hashes.len()))
+}
+
+fn get_directory_entry_hashes(snapshot_path: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let mut entry_hashes = vec![];
+
+    for entry in WalkDir::new(snapshot_path) {
+        let entry = entry.with_context(|| format!("failed to read {snapshot_path:?}"))?;
+        let inner_path = entry
+            .path()
+            .strip_prefix(snapshot_path)
+            .unwrap_or(entry.path())
+            .to_path_buf();
+
+        let hash = if entry.file_type().is_symlink() {
+            let link_target = fs::read_link(entry.path())
+                .with_context(|| format!("failed to read symlink {:?}", entry.path()))?;
+            hash_link_target(&link_target)
+        } else if entry.file_type().is_file() {
+            manifest::hash_file(entry.path())?
+        } else {
+            // Directories are implied by the paths inside them
+            continue;
+        };
+        entry_hashes.push((inner_path, hash));
+    }
+
+    Ok(entry_hashes)
+}
+
+fn get_tarball_entry_hashes(snapshot_path: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let mut entry_hashes: Vec<(PathBuf, String)> = vec![];
+    let mut archive = archive::open_tarball(snapshot_path)?;
+
+    for entry in archive
+        .entries()
+        .with_context(|| format!("failed to read tarball {snapshot_path:?}"))?
+    {
+        let mut entry =
+            entry.with_context(|| format!("failed to read tarball {snapshot_path:?}"))?;
+        let inner_path = entry.path()?.to_path_buf();
+
+        let hash = match entry.header().entry_type() {
+            tar::EntryType::Directory => continue,
+            // A hardlink has the same contents as the file it's linked to, as in a directory
+            tar::EntryType::Link => {
+                let link_target = entry
+                    .link_name()?
+                    .unwrap_or_default()
+                    .to_path_buf();
+                entry_hashes
+                    .iter()
+                    .find(|(inner_path, _)| *inner_path == link_target)
+                    .map(|(_, hash)| hash.clone())
+                    .unwrap_or_else(|| hash_link_target(&link_target))
+            }
+            tar::EntryType::Symlink => {
+                let link_target = entry
+                    .link_name()?
+                    .unwrap_or_default()
+                    .to_path_buf();
+                hash_link_target(&link_target)
+            }
+            _ => {
+                let mut hasher = Sha256::new();
+                io::copy(&mut entry, &mut hasher).with_context(|| {
+                    format!("failed to hash {inner_path:?} in {snapshot_path:?}")
+                })?;
+                manifest::format_hash(hasher)
+            }
+        };
+        entry_hashes.push((inner_path, hash));
+    }
+
+    Ok(entry_hashes)
+}
+
+// Links are hashed by where they point, so they can't collide with a file's contents
+fn hash_link_target(link_target: &Path) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"link:");
+    hasher.update(link_target.as_os_str().as_encoded_bytes());
+    manifest::format_hash(hasher)
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_snapshot_digest() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {test_path:?}\nretention.hours = 1"
+        ))?;
+
+        // The same contents, as a directory and as a tarball
+        let directory_path = test_path.join("directory");
+        fs::create_dir_all(directory_path.join("sub"))?;
+        fs::write(directory_path.join("sub/foo"), "foo")?;
+        std::os::unix::fs::symlink("sub/foo", directory_path.join("bar"))?;
+
+        let tarball_path = test_path.join("snapshot.tgz");
+        let encoder = flate2::write::GzEncoder::new(
+            fs::File::create(&tarball_path)?,
+            flate2::Compression::default(),
+        );
+        let mut builder = tar::Builder::new(encoder);
+        builder.follow_symlinks(false);
+        builder.append_dir_all("", &directory_path)?;
+        builder.into_inner()?.finish()?;
+
+        let directory_digest = get_snapshot_digest(&config, &directory_path)?;
+        let tarball_digest = get_snapshot_digest(&config, &tarball_path)?;
+        fs::write(directory_path.join("sub/foo"), "changed")?;
+        let changed_digest = get_snapshot_digest(&config, &directory_path)?;
+
+        fs::remove_dir_all(&test_path)?;
+        assert_eq!(directory_digest, tarball_digest);
+        assert_ne!(directory_digest, changed_digest);
+        Ok(())
+    }
+}
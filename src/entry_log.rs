@@ -0,0 +1,91 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::configuration::Config;
+
+/*
+    With `entry_log`, each entry's path inside a tarball is written out as soon as it's archived,
+    one per line, eg: to a named pipe, so a consumer can follow a snapshot's progress live.
+*/
+
+// Written to stdout instead of a file
+const ENTRY_LOG_STDOUT: &str = "-";
+
+pub struct EntryLog {
+    writer: Option<Box<dyn Write>>,
+}
+
+impl EntryLog {
+    // Opening a named pipe waits until something is reading from it
+    pub fn open(config: &Config) -> Result<Self> {
+        let writer: Option<Box<dyn Write>> = match &config.options.entry_log {
+            None => None,
+            Some(entry_log) if entry_log == Path::new(ENTRY_LOG_STDOUT) => {
+                Some(Box::new(io::stdout()))
+            }
+            Some(entry_log) => Some(Box::new(
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(entry_log)
+                    .with_context(|| format!("failed to open entry_log {entry_log:?}"))?,
+            )),
+        };
+
+        Ok(EntryLog { writer })
+    }
+
+    // A consumer going away only stops the log, never the snapshot
+    pub fn record(&mut self, inner_entry_path: &Path) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+
+        if let Err(e) =
+            writeln!(writer, "{}", inner_entry_path.display()).and_then(|_| writer.flush())
+        {
+            log::warn!("Failed to write to entry_log, no more entries will be written to it: {e}");
+            self.writer = None;
+        }
+    }
+}
+
+// stdout can't carry both the tarball and its entries
+pub fn is_stdout(config: &Config) -> bool {
+    config
+        .options
+        .entry_log
+        .as_ref()
+        .is_some_and(|entry_log| entry_log == Path::new(ENTRY_LOG_STDOUT))
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_entry_log_records_each_entry() -> Result<()> {
+        let entry_log_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = \"/target\"\nretention.hours = 1\n\
+            [options]\nentry_log = {entry_log_path:?}"
+        ))?;
+
+        let mut entry_log = EntryLog::open(&config)?;
+        entry_log.record(Path::new("foo"));
+        entry_log.record(Path::new("sub/bar"));
+        let entry_log_contents = fs::read_to_string(&entry_log_path)?;
+
+        fs::remove_file(&entry_log_path)?;
+        assert_eq!(entry_log_contents, "foo\nsub/bar\n");
+        assert!(!is_stdout(&config));
+        Ok(())
+    }
+}
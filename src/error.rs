@@ -0,0 +1,128 @@
+use std::fmt;
+use std::process::ExitCode;
+
+use crate::cli::CliErrorFormat;
+
+/*
+    Top-level failures, tagged with the phase of the run they happened in
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorPhase {
+    Config,
+    Rotate,
+    Verify,
+    Lint,
+    Snapshot,
+    Init,
+    Targets,
+    Consolidate,
+    Unchanged,
+    Prune,
+    Estimate,
+    Repair,
+    Pause,
+    Resume,
+    Reindex,
+}
+
+impl ErrorPhase {
+    // A bad config is distinguished from a failed run, so automation can tell them apart
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ErrorPhase::Config => 2,
+            // Not a failure as such, but `--fail-if-unchanged` asked for it to fail the job
+            ErrorPhase::Unchanged => 3,
+            ErrorPhase::Rotate
+            | ErrorPhase::Verify
+            | ErrorPhase::Lint
+            | ErrorPhase::Snapshot
+            | ErrorPhase::Init
+            | ErrorPhase::Targets
+            | ErrorPhase::Consolidate
+            | ErrorPhase::Prune
+            | ErrorPhase::Estimate
+            | ErrorPhase::Repair
+            | ErrorPhase::Pause
+            | ErrorPhase::Resume
+            | ErrorPhase::Reindex => 1,
+        }
+    }
+}
+
+impl fmt::Display for ErrorPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorPhase::Config => write!(f, "config"),
+            ErrorPhase::Rotate => write!(f, "rotate"),
+            ErrorPhase::Verify => write!(f, "verify"),
+            ErrorPhase::Lint => write!(f, "lint"),
+            ErrorPhase::Snapshot => write!(f, "snapshot"),
+            ErrorPhase::Init => write!(f, "init"),
+            ErrorPhase::Targets => write!(f, "targets"),
+            ErrorPhase::Consolidate => write!(f, "consolidate"),
+            ErrorPhase::Unchanged => write!(f, "unchanged"),
+            ErrorPhase::Prune => write!(f, "prune"),
+            ErrorPhase::Estimate => write!(f, "estimate"),
+            ErrorPhase::Repair => write!(f, "repair"),
+            ErrorPhase::Pause => write!(f, "pause"),
+            ErrorPhase::Resume => write!(f, "resume"),
+            ErrorPhase::Reindex => write!(f, "reindex"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PirouetteError {
+    pub phase: ErrorPhase,
+    pub error: anyhow::Error,
+}
+
+impl PirouetteError {
+    pub fn report(&self, error_format: &CliErrorFormat) -> ExitCode {
+        match error_format {
+            CliErrorFormat::Text => eprintln!("Error: {:?}", self.error),
+            CliErrorFormat::Json => eprintln!("{}", self.to_json()),
+        }
+
+        ExitCode::from(self.phase.exit_code())
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "error": self.error.to_string(),
+            "causes": self.error.chain().skip(1).map(|cause| cause.to_string()).collect::<Vec<_>>(),
+            "phase": self.phase.to_string(),
+            "exit_code": self.phase.exit_code(),
+        })
+    }
+}
+
+// Lets `?` tag an anyhow::Error with its phase, eg: `.map_err(phase(ErrorPhase::Config))?`
+pub fn phase(phase: ErrorPhase) -> impl Fn(anyhow::Error) -> PirouetteError {
+    move |error| PirouetteError { phase, error }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_error_json() {
+        let error = Err::<(), _>(anyhow::anyhow!("source path does not exist"))
+            .context("failed to validate source")
+            .unwrap_err();
+        let actual_json = phase(ErrorPhase::Config)(error).to_json();
+
+        assert_eq!(
+            actual_json,
+            serde_json::json!({
+                "error": "failed to validate source",
+                "causes": ["source path does not exist"],
+                "phase": "config",
+                "exit_code": 2,
+            })
+        );
+    }
+}
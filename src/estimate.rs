@@ -0,0 +1,193 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::PirouetteRetentionTarget;
+use crate::PirouetteStats;
+use crate::configuration::Config;
+use crate::configuration::ConfigRetentionPeriod;
+use crate::snapshot;
+
+/*
+    How big a period's next snapshot would be, from the same walk and filters a snapshot uses,
+    without writing anything
+*/
+
+// With `--sample`, the start of each file is compressed, up to a total, for a rough ratio
+const SAMPLE_FILE_BYTES: u64 = 64 * 1024;
+const SAMPLE_TOTAL_BYTES: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SnapshotEstimate {
+    pub entry_count: usize,
+    pub total_size: u64,
+    pub compressed_size: Option<u64>,
+}
+
+pub fn print_snapshot_estimate(
+    config: &Config,
+    all_targets: &[PirouetteRetentionTarget],
+    period: &ConfigRetentionPeriod,
+    sample: bool,
+) -> Result<()> {
+    let retention_target = all_targets
+        .iter()
+        .find(|retention_target| retention_target.period == *period)
+        .with_context(|| format!("{period} is not a configured retention period"))?;
+
+    let estimate = estimate_snapshot(config, retention_target, sample)?;
+    println!(
+        "{retention_target}: {} files, {}",
+        crate::format_count(estimate.entry_count),
+        format_megabytes(estimate.total_size)
+    );
+    if let Some(compressed_size) = estimate.compressed_size {
+        println!(
+            "{retention_target}: about {} as a gzipped tarball",
+            format_megabytes(compressed_size)
+        );
+    }
+
+    Ok(())
+}
+
+pub fn estimate_snapshot(
+    config: &Config,
+    retention_target: &PirouetteRetentionTarget,
+    sample: bool,
+) -> Result<SnapshotEstimate> {
+    let stats = PirouetteStats::default();
+    let mut estimate = SnapshotEstimate::default();
+    let mut sampler = sample.then(CompressionSampler::new);
+
+    for entry in snapshot::get_filtered_source_contents_iter(
+        config,
+        retention_target.exclude_modified_within,
+        &stats,
+    )? {
+        let entry = entry?;
+        estimate.entry_count += 1;
+
+        // Symlinks and special files take no space of their own
+        let Ok(metadata) = fs::symlink_metadata(&entry.path) else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        estimate.total_size += metadata.len();
+
+        if let Some(sampler) = sampler.as_mut() {
+            sampler.sample_file(&entry.path)?;
+        }
+    }
+
+    estimate.compressed_size = sampler
+        .map(|sampler| sampler.estimate_compressed_size(estimate.total_size))
+        .transpose()?;
+    Ok(estimate)
+}
+
+fn format_megabytes(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+// Compresses the start of each file at the same level as a Tarball snapshot
+struct CompressionSampler {
+    encoder: flate2::write::GzEncoder<Vec<u8>>,
+    sampled_bytes: u64,
+}
+
+impl CompressionSampler {
+    fn new() -> Self {
+        CompressionSampler {
+            encoder: flate2::write::GzEncoder::new(vec![], flate2::Compression::best()),
+            sampled_bytes: 0,
+        }
+    }
+
+    fn sample_file(&mut self, path: &Path) -> Result<()> {
+        let remaining_bytes = SAMPLE_TOTAL_BYTES.saturating_sub(self.sampled_bytes);
+        if remaining_bytes == 0 {
+            return Ok(());
+        }
+
+        let mut sample = vec![];
+        fs::File::open(path)
+            .and_then(|f| {
+                f.take(SAMPLE_FILE_BYTES.min(remaining_bytes))
+                    .read_to_end(&mut sample)
+            })
+            .with_context(|| format!("failed to read file {path:?}"))?;
+        self.encoder
+            .write_all(&sample)
+            .context("failed to compress sample")?;
+        self.sampled_bytes += sample.len() as u64;
+        Ok(())
+    }
+
+    fn estimate_compressed_size(self, total_size: u64) -> Result<u64> {
+        let sampled_bytes = self.sampled_bytes;
+        let compressed_bytes = self
+            .encoder
+            .finish()
+            .context("failed to compress sample")?
+            .len() as u64;
+        if sampled_bytes == 0 {
+            return Ok(0);
+        }
+
+        let ratio = compressed_bytes as f64 / sampled_bytes as f64;
+        log::info!(
+            "Sampled {} of contents, which compressed to {:.0}%",
+            format_megabytes(sampled_bytes),
+            ratio * 100.0
+        );
+        Ok((total_size as f64 * ratio) as u64)
+    }
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_estimate_snapshot() -> Result<()> {
+        let source_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(source_path.join("sub"))?;
+        fs::write(source_path.join("sub/zeros"), vec![0; 100_000])?;
+        fs::write(source_path.join("foo.txt"), "foo")?;
+        fs::write(source_path.join("foo.tmp"), "excluded")?;
+        std::os::unix::fs::symlink("foo.txt", source_path.join("bar"))?;
+
+        let config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = \"/target\"\nretention.hours = 1\n\
+            [options]\nexclude = [\"*.tmp\"]"
+        ))?;
+        let retention_target = &crate::get_all_retention_targets(&config)[0];
+        let estimate = estimate_snapshot(&config, retention_target, false);
+        let sampled_estimate = estimate_snapshot(&config, retention_target, true);
+
+        fs::remove_dir_all(&source_path)?;
+        assert_eq!(
+            estimate?,
+            SnapshotEstimate {
+                entry_count: 3,
+                total_size: 100_003,
+                compressed_size: None,
+            }
+        );
+        // Zeros compress very well
+        let compressed_size = sampled_estimate?
+            .compressed_size
+            .unwrap_or_default();
+        assert!(compressed_size > 0 && compressed_size < 1_000);
+        Ok(())
+    }
+}
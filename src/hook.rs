@@ -0,0 +1,242 @@
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::PirouetteRetentionTarget;
+use crate::clean;
+use crate::configuration::Config;
+use crate::configuration::ConfigOptsOnValidateFailure;
+use crate::dry_run;
+
+/*
+    User commands run around a rotation, configured in the `[options]` table
+*/
+
+// After cleanup, tell `post_clean_hook` which snapshots were deleted, one path per line on stdin.
+// In a dry run, the command is only logged, along with what it would have been given.
+pub fn run_post_clean_hook(
+    config: &Config,
+    retention_target: &PirouetteRetentionTarget,
+    deleted_paths: &[PathBuf],
+) -> Result<()> {
+    let Some(hook) = &config.options.post_clean_hook else {
+        return Ok(());
+    };
+    if deleted_paths.is_empty() {
+        return Ok(());
+    }
+
+    log::info!("Running post_clean_hook for {retention_target}");
+    let period = retention_target.period.to_string();
+    let stdin_contents: String = deleted_paths
+        .iter()
+        .map(|path| format!("{}\n", path.display()))
+        .collect();
+
+    // A hook which didn't run can't fail, so a dry run is never stopped by one
+    dry_run!(
+        retention_target.dry_run,
+        format!(
+            "post_clean_hook will not run: PIROUETTE_PERIOD={period} sh -c {hook:?}, with stdin:\n{stdin_contents}"
+        ),
+        { run_hook_command(hook, &period, &stdin_contents) }
+    )
+}
+
+// After a snapshot is written, and before it's counted as taken, let `validate_hook` check it,
+// eg: by test-restoring a database dump. Fails if the snapshot should fail, having deleted it
+// first with `on_validate_failure = "delete"`.
+pub fn run_validate_hook(config: &Config, snapshot_path: &Path) -> Result<()> {
+    let Some(hook) = &config.options.validate_hook else {
+        return Ok(());
+    };
+
+    log::info!("Running validate_hook for {snapshot_path:?}");
+    let Err(e) = run_validate_command(hook, snapshot_path) else {
+        return Ok(());
+    };
+
+    match config.options.on_validate_failure {
+        ConfigOptsOnValidateFailure::Warn => {
+            log::warn!("Keeping {snapshot_path:?}, although it failed validation: {e:#}");
+            Ok(())
+        }
+        ConfigOptsOnValidateFailure::Delete if config.options.append_only => Err(e.context(
+            format!("{snapshot_path:?} failed validation, and was kept because append_only is set"),
+        )),
+        ConfigOptsOnValidateFailure::Delete => {
+            log::warn!("Deleting {snapshot_path:?}, because it failed validation");
+            clean::delete_snapshots(config, vec![snapshot_path.to_path_buf().into()]);
+            Err(e.context(format!(
+                "{snapshot_path:?} failed validation, and was deleted"
+            )))
+        }
+        ConfigOptsOnValidateFailure::Fail => {
+            Err(e.context(format!("{snapshot_path:?} failed validation")))
+        }
+    }
+}
+
+fn run_validate_command(hook: &str, snapshot_path: &Path) -> Result<()> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .env("PIROUETTE_SNAPSHOT", snapshot_path)
+        .status()
+        .with_context(|| format!("failed to run validate_hook {hook:?}"))?;
+    if !status.success() {
+        anyhow::bail!("validate_hook {hook:?} failed with {status}");
+    }
+
+    Ok(())
+}
+
+fn run_hook_command(hook: &str, period: &str, stdin_contents: &str) -> Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .env("PIROUETTE_PERIOD", period)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run post_clean_hook {hook:?}"))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // A hook which doesn't read its stdin closes it early, which isn't worth failing over
+        if let Err(err) = stdin.write_all(stdin_contents.as_bytes()) {
+            log::debug!("post_clean_hook didn't read every deleted path: {err}");
+        }
+    }
+
+    let status = child
+        .wait()
+        .with_context(|| format!("failed to wait for post_clean_hook {hook:?}"))?;
+    if !status.success() {
+        anyhow::bail!("post_clean_hook {hook:?} failed with {status}");
+    }
+
+    Ok(())
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{
+        ConfigOptsOutputFormat, ConfigRetentionLimit, ConfigRetentionPeriod,
+    };
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_run_post_clean_hook() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&test_path)?;
+        let output_path = test_path.join("output");
+
+        let get_config = |hook: &str, dry_run: bool| -> Result<Config> {
+            Ok(toml::from_str(&format!(
+                "source.path = {test_path:?}\ntarget.path = {test_path:?}\nretention.days = 1\n[options]\ndry_run = \"{dry_run}\"\npost_clean_hook = {hook:?}"
+            ))?)
+        };
+        let get_retention_target = |dry_run: bool| PirouetteRetentionTarget {
+            period: ConfigRetentionPeriod::Days,
+            path: test_path.join("days"),
+            period_path: test_path.join("days"),
+            snapshot_pattern: "*".to_string(),
+            limit: ConfigRetentionLimit::Count(1),
+            output_format: ConfigOptsOutputFormat::Directory,
+            dry_run,
+            exclude_modified_within: None,
+        };
+        let deleted_paths = [PathBuf::from("/a"), PathBuf::from("/b")];
+
+        let writing_hook = format!("{{ echo \"$PIROUETTE_PERIOD\"; cat; }} > {output_path:?}");
+        run_post_clean_hook(
+            &get_config(&writing_hook, true)?,
+            &get_retention_target(true),
+            &deleted_paths,
+        )?;
+        let dry_run_output_exists = output_path.exists();
+        run_post_clean_hook(
+            &get_config(&writing_hook, false)?,
+            &get_retention_target(false),
+            &deleted_paths,
+        )?;
+        let output = fs::read_to_string(&output_path);
+        let dry_run_failed_result = run_post_clean_hook(
+            &get_config("exit 3", true)?,
+            &get_retention_target(true),
+            &deleted_paths,
+        );
+        let failed_result = run_post_clean_hook(
+            &get_config("exit 3", false)?,
+            &get_retention_target(false),
+            &deleted_paths,
+        );
+
+        fs::remove_dir_all(&test_path)?;
+        assert!(!dry_run_output_exists);
+        assert_eq!(output?, "days\n/a\n/b\n");
+        assert!(dry_run_failed_result.is_ok());
+        assert!(failed_result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_validate_hook() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let snapshot_path = test_path.join("snapshot");
+        fs::create_dir_all(&snapshot_path)?;
+
+        let run_validate = |hook: &str, on_validate_failure: &str| -> Result<bool> {
+            let config: Config = toml::from_str(&format!(
+                "source.path = {test_path:?}\ntarget.path = {test_path:?}\nretention.days = 1\n\
+                [options]\nvalidate_hook = {hook:?}\non_validate_failure = {on_validate_failure:?}"
+            ))?;
+            let result = run_validate_hook(&config, &snapshot_path);
+            Ok(result.is_ok())
+        };
+        let passed = run_validate("test -d \"$PIROUETTE_SNAPSHOT\"", "fail")?;
+        let warned = run_validate("exit 1", "warn")?;
+        let failed = run_validate("exit 1", "fail")?;
+        let is_kept = snapshot_path.exists();
+        let deleted = run_validate("exit 1", "delete")?;
+        let is_deleted = !snapshot_path.exists();
+
+        fs::remove_dir_all(&test_path)?;
+        assert_eq!(
+            [passed, warned, failed, deleted],
+            [true, true, false, false]
+        );
+        assert!(is_kept);
+        assert!(is_deleted);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_hook_under_append_only() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let snapshot_path = test_path.join("snapshot");
+        fs::create_dir_all(&snapshot_path)?;
+        let config: Config = toml::from_str(&format!(
+            "source.path = {test_path:?}\ntarget.path = {test_path:?}\nretention.days = 1\n\
+            [options]\nvalidate_hook = \"exit 1\"\non_validate_failure = \"delete\"\n\
+            append_only = true"
+        ))?;
+
+        let result = run_validate_hook(&config, &snapshot_path);
+        let is_kept = snapshot_path.exists();
+
+        fs::remove_dir_all(&test_path)?;
+        assert!(result.is_err_and(|e| {
+            e.to_string()
+                .contains("was kept because append_only")
+        }));
+        assert!(is_kept);
+        Ok(())
+    }
+}
@@ -0,0 +1,489 @@
+use anyhow::{Context, Result};
+use nix::sys::signal;
+use nix::unistd::Pid;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::PirouetteRetentionTarget;
+use crate::clean;
+use crate::configuration::Config;
+use crate::configuration::ConfigOptsOnInProgress;
+use crate::dry_run;
+
+/*
+    Each period's own directory holds a marker while a snapshot is being written into it, eg:
+    "<target>/days/.pirouette-in-progress", even with a dated `layout`. It records the writing
+    process and the snapshot, so a later run can tell a live snapshot from one left behind by
+    a crash.
+*/
+
+const IN_PROGRESS_MARKER: &str = ".pirouette-in-progress";
+// With `resume_interrupted`, where an abandoned Directory snapshot waits for the next one
+const RESUME_DIRECTORY: &str = ".pirouette-resume";
+
+// How often `on_in_progress = "wait"` checks whether the other run has finished. There's no
+// timeout, so a run which hangs without dying holds up every later one until it's killed.
+const WAIT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq)]
+struct InProgressSnapshot {
+    pid: i32,
+    snapshot_path: PathBuf,
+}
+
+// Removes the marker once the snapshot is finished, or has failed
+pub struct InProgressMarker {
+    marker_path: Option<PathBuf>,
+}
+
+impl Drop for InProgressMarker {
+    fn drop(&mut self) {
+        if let Some(marker_path) = &self.marker_path
+            && let Err(e) = fs::remove_file(marker_path)
+        {
+            log::error!("failed to remove in-progress marker {marker_path:?}: {e}");
+        }
+    }
+}
+
+pub fn is_marker_path(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|file_name| file_name == IN_PROGRESS_MARKER)
+}
+
+pub fn is_resume_path(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|file_name| file_name == RESUME_DIRECTORY)
+}
+
+fn get_resume_path(retention_target: &PirouetteRetentionTarget) -> PathBuf {
+    retention_target
+        .period_path
+        .join(RESUME_DIRECTORY)
+}
+
+// Carry on from an interrupted Directory snapshot, if one was kept, by moving it to the new
+// snapshot's path. Files it already holds are only copied again if they've changed since.
+pub fn resume_interrupted_snapshot(
+    retention_target: &PirouetteRetentionTarget,
+    snapshot_path: &Path,
+) -> Result<()> {
+    let resume_path = get_resume_path(retention_target);
+    if !resume_path.is_dir() {
+        return Ok(());
+    }
+
+    log::info!("Resuming the interrupted {retention_target} snapshot at {snapshot_path:?}");
+    fs::rename(&resume_path, snapshot_path)
+        .with_context(|| format!("failed to move {resume_path:?} to {snapshot_path:?}"))?;
+
+    // Otherwise, the snapshot would look as old as the run which was interrupted
+    fs::File::open(snapshot_path)
+        .and_then(|f| f.set_modified(SystemTime::now()))
+        .with_context(|| format!("failed to set modification time of {snapshot_path:?}"))
+}
+
+fn get_marker_path(retention_target: &PirouetteRetentionTarget) -> PathBuf {
+    retention_target
+        .period_path
+        .join(IN_PROGRESS_MARKER)
+}
+
+// Only one run can create the marker, so two runs which both found the period free can't
+// both write into it
+pub fn mark_in_progress(
+    retention_target: &PirouetteRetentionTarget,
+    snapshot_path: &Path,
+) -> Result<InProgressMarker> {
+    if retention_target.dry_run {
+        return Ok(InProgressMarker { marker_path: None });
+    }
+
+    let marker_path = get_marker_path(retention_target);
+    fs::create_dir_all(&retention_target.path)
+        .with_context(|| format!("failed to create directory {:?}", retention_target.path))?;
+    let mut marker_file = match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&marker_path)
+    {
+        Ok(marker_file) => marker_file,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            anyhow::bail!("{retention_target} has a snapshot still being written by another run")
+        }
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("failed to create in-progress marker {marker_path:?}"));
+        }
+    };
+    let marker = InProgressMarker {
+        marker_path: Some(marker_path.clone()),
+    };
+    write!(
+        marker_file,
+        "{}\n{}\n",
+        std::process::id(),
+        snapshot_path.display()
+    )
+    .with_context(|| format!("failed to write in-progress marker {marker_path:?}"))?;
+
+    Ok(marker)
+}
+
+fn read_marker(marker_path: &Path) -> Result<Option<InProgressSnapshot>> {
+    let contents = match fs::read_to_string(marker_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("failed to read in-progress marker {marker_path:?}"));
+        }
+    };
+
+    let (pid, snapshot_path) = contents
+        .trim_end()
+        .split_once('\n')
+        .with_context(|| format!("malformed in-progress marker {marker_path:?}"))?;
+    let pid = pid
+        .parse()
+        .with_context(|| format!("malformed in-progress marker {marker_path:?}"))?;
+
+    Ok(Some(InProgressSnapshot {
+        pid,
+        snapshot_path: PathBuf::from(snapshot_path),
+    }))
+}
+
+// Signal 0 only checks the process exists. EPERM means it exists, but belongs to another user.
+fn is_process_alive(pid: i32) -> bool {
+    match signal::kill(Pid::from_raw(pid), None) {
+        Ok(()) => true,
+        Err(e) => e == nix::errno::Errno::EPERM,
+    }
+}
+
+/*
+    Checking before rotation
+*/
+
+// Drop or wait for periods with a snapshot still being written by another run, per
+// `on_in_progress`, and clean up any snapshot left incomplete by a run which has died
+pub fn check_in_progress_targets(
+    config: &Config,
+    all_targets: Vec<PirouetteRetentionTarget>,
+) -> Result<Vec<PirouetteRetentionTarget>> {
+    let mut ready_targets = vec![];
+
+    for retention_target in all_targets {
+        if is_target_ready(config, &retention_target)? {
+            ready_targets.push(retention_target);
+        }
+    }
+
+    Ok(ready_targets)
+}
+
+fn is_target_ready(config: &Config, retention_target: &PirouetteRetentionTarget) -> Result<bool> {
+    let marker_path = get_marker_path(retention_target);
+    let mut has_waited = false;
+
+    loop {
+        let Some(in_progress) = read_marker(&marker_path)? else {
+            return Ok(true);
+        };
+
+        if !is_process_alive(in_progress.pid) {
+            remove_abandoned_snapshot(config, retention_target, &marker_path, &in_progress)?;
+            return Ok(true);
+        }
+
+        let message = format!(
+            "{retention_target} has a snapshot still being written by process {}: {:?}",
+            in_progress.pid, in_progress.snapshot_path
+        );
+        match config.options.on_in_progress {
+            ConfigOptsOnInProgress::Skip => {
+                log::warn!("{message}, skipping it");
+                return Ok(false);
+            }
+            ConfigOptsOnInProgress::Error => anyhow::bail!("{message}"),
+            ConfigOptsOnInProgress::Wait => {
+                if !has_waited {
+                    log::warn!("{message}, waiting for it to finish");
+                    has_waited = true;
+                }
+                thread::sleep(WAIT_INTERVAL);
+            }
+        }
+    }
+}
+
+fn remove_abandoned_snapshot(
+    config: &Config,
+    retention_target: &PirouetteRetentionTarget,
+    marker_path: &Path,
+    in_progress: &InProgressSnapshot,
+) -> Result<()> {
+    if config.options.resume_interrupted && in_progress.snapshot_path.is_dir() {
+        return keep_abandoned_snapshot(retention_target, marker_path, in_progress);
+    }
+    // The marker stays, so the partial snapshot is never mistaken for a complete one
+    if config.options.append_only {
+        anyhow::bail!(
+            "{:?} was left incomplete by process {}, and append_only doesn't allow removing it, so it needs removing by hand along with {marker_path:?}",
+            in_progress.snapshot_path,
+            in_progress.pid
+        );
+    }
+
+    log::warn!(
+        "{:?} was left incomplete by process {}, which is no longer running. Removing it",
+        in_progress.snapshot_path,
+        in_progress.pid
+    );
+
+    dry_run!(
+        retention_target.dry_run,
+        format!("incomplete snapshot will not be deleted"),
+        {
+            clean::delete_snapshots(config, vec![in_progress.snapshot_path.clone().into()]);
+            fs::remove_file(marker_path)
+                .with_context(|| format!("failed to remove in-progress marker {marker_path:?}"))
+        }
+    )
+}
+
+fn keep_abandoned_snapshot(
+    retention_target: &PirouetteRetentionTarget,
+    marker_path: &Path,
+    in_progress: &InProgressSnapshot,
+) -> Result<()> {
+    let resume_path = get_resume_path(retention_target);
+    log::warn!(
+        "{:?} was left incomplete by process {}, which is no longer running. Keeping it to resume from",
+        in_progress.snapshot_path,
+        in_progress.pid
+    );
+
+    dry_run!(
+        retention_target.dry_run,
+        format!("incomplete snapshot will not be moved to {resume_path:?}"),
+        {
+            // Only the most recent interruption is worth resuming from
+            if resume_path.exists() {
+                fs::remove_dir_all(&resume_path)
+                    .with_context(|| format!("failed to remove {resume_path:?}"))?;
+            }
+            fs::rename(&in_progress.snapshot_path, &resume_path).with_context(|| {
+                format!(
+                    "failed to move {:?} to {resume_path:?}",
+                    in_progress.snapshot_path
+                )
+            })?;
+            fs::remove_file(marker_path)
+                .with_context(|| format!("failed to remove in-progress marker {marker_path:?}"))
+        }
+    )
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn create_test_config(target_path: &Path, on_in_progress: &str) -> Result<Config> {
+        Ok(toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\nretention.hours = 1\n\
+            [options]\non_in_progress = {on_in_progress:?}"
+        ))?)
+    }
+
+    #[test]
+    fn test_marker_is_removed_when_dropped() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config = create_test_config(&target_path, "skip")?;
+        let retention_target = &crate::get_all_retention_targets(&config)[0];
+        let snapshot_path = retention_target.path.join("snapshot");
+
+        let marker = mark_in_progress(retention_target, &snapshot_path)?;
+        let marked = read_marker(&get_marker_path(retention_target))?;
+        drop(marker);
+        let unmarked = read_marker(&get_marker_path(retention_target))?;
+        fs::remove_dir_all(&target_path)?;
+
+        assert_eq!(
+            marked,
+            Some(InProgressSnapshot {
+                pid: std::process::id() as i32,
+                snapshot_path,
+            })
+        );
+        assert_eq!(unmarked, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_marker_is_only_created_once() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config = create_test_config(&target_path, "skip")?;
+        let retention_target = &crate::get_all_retention_targets(&config)[0];
+        let snapshot_path = retention_target.path.join("snapshot");
+
+        // Another run got there first, so this one mustn't take the marker over, or remove it
+        let marker = mark_in_progress(retention_target, &snapshot_path)?;
+        let second_result =
+            mark_in_progress(retention_target, &retention_target.path.join("other"));
+        let marked = read_marker(&get_marker_path(retention_target))?;
+        drop(marker);
+        fs::remove_dir_all(&target_path)?;
+
+        assert!(second_result.is_err());
+        assert_eq!(
+            marked.map(|in_progress| in_progress.snapshot_path),
+            Some(snapshot_path)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_progress_targets() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let skip_config = create_test_config(&target_path, "skip")?;
+        let error_config = create_test_config(&target_path, "error")?;
+        let all_targets = crate::get_all_retention_targets(&skip_config);
+        let snapshot_path = all_targets[0].path.join("snapshot");
+        fs::create_dir_all(&snapshot_path)?;
+
+        // This process is alive, so the snapshot is still in progress
+        let marker = mark_in_progress(&all_targets[0], &snapshot_path)?;
+        let skip_result = check_in_progress_targets(&skip_config, all_targets.clone());
+        let error_result = check_in_progress_targets(&error_config, all_targets.clone());
+        std::mem::forget(marker);
+
+        // No process can have this pid, so the snapshot was abandoned
+        let marker_path = get_marker_path(&all_targets[0]);
+        fs::write(
+            &marker_path,
+            format!("{}\n{}\n", i32::MAX, snapshot_path.display()),
+        )?;
+        let abandoned_result = check_in_progress_targets(&skip_config, all_targets.clone());
+        let is_abandoned_removed = !snapshot_path.exists() && !marker_path.exists();
+        fs::remove_dir_all(&target_path)?;
+
+        assert!(skip_result?.is_empty());
+        assert!(error_result.is_err());
+        assert_eq!(abandoned_result?.len(), all_targets.len());
+        assert!(is_abandoned_removed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_only_keeps_abandoned_marker() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let mut config = create_test_config(&target_path, "skip")?;
+        config.options.append_only = true;
+        let all_targets = crate::get_all_retention_targets(&config);
+        let snapshot_path = all_targets[0].path.join("snapshot");
+        fs::create_dir_all(&snapshot_path)?;
+        let marker_path = get_marker_path(&all_targets[0]);
+        fs::write(
+            &marker_path,
+            format!("{}\n{}\n", i32::MAX, snapshot_path.display()),
+        )?;
+
+        let abandoned_result = check_in_progress_targets(&config, all_targets.clone());
+        let is_abandoned_kept = snapshot_path.exists() && marker_path.exists();
+        fs::remove_dir_all(&target_path)?;
+
+        assert!(abandoned_result.is_err());
+        assert!(is_abandoned_kept);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_interrupted_snapshot() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let mut config = create_test_config(&target_path, "skip")?;
+        config.options.resume_interrupted = true;
+        let all_targets = crate::get_all_retention_targets(&config);
+        let snapshot_path = all_targets[0].path.join("snapshot");
+        fs::create_dir_all(&snapshot_path)?;
+        fs::write(snapshot_path.join("foo"), "foo")?;
+        fs::write(
+            get_marker_path(&all_targets[0]),
+            format!("{}\n{}\n", i32::MAX, snapshot_path.display()),
+        )?;
+
+        // The abandoned snapshot is kept out of sight, until the next one takes it over
+        let abandoned_result = check_in_progress_targets(&config, all_targets.clone());
+        let snapshot_count = crate::layout::get_snapshot_entries(&all_targets[0]).len();
+        let next_snapshot_path = all_targets[0].path.join("next_snapshot");
+        let resume_result = resume_interrupted_snapshot(&all_targets[0], &next_snapshot_path);
+        let resumed_contents = fs::read_to_string(next_snapshot_path.join("foo"));
+        fs::remove_dir_all(&target_path)?;
+
+        assert_eq!(abandoned_result?.len(), all_targets.len());
+        assert_eq!(snapshot_count, 0);
+        resume_result?;
+        assert_eq!(resumed_contents?, "foo");
+        Ok(())
+    }
+
+    #[test]
+    fn test_resumed_snapshot_drops_deleted_files() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let source_path = test_path.join("source");
+        fs::create_dir_all(source_path.join("sub"))?;
+        fs::write(source_path.join("kept"), "kept")?;
+        fs::write(source_path.join("sub/deleted"), "deleted")?;
+        let config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = {:?}\nretention.hours = 1\n\
+            [options]\nresume_interrupted = true",
+            test_path.join("target")
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+
+        // The interrupted run had copied everything, before a file was deleted from the source
+        let interrupted_path = all_targets[0].path.join("interrupted");
+        fs::create_dir_all(interrupted_path.join("sub"))?;
+        fs::copy(source_path.join("kept"), interrupted_path.join("kept"))?;
+        fs::copy(
+            source_path.join("sub/deleted"),
+            interrupted_path.join("sub/deleted"),
+        )?;
+        fs::write(
+            get_marker_path(&all_targets[0]),
+            format!("{}\n{}\n", i32::MAX, interrupted_path.display()),
+        )?;
+        fs::remove_file(source_path.join("sub/deleted"))?;
+
+        check_in_progress_targets(&config, all_targets.clone())?;
+        let snapshot_result = crate::snapshot::copy_snapshot(
+            &config,
+            &all_targets[0],
+            &crate::PirouetteStats::default(),
+        );
+        let snapshot_path = snapshot_result
+            .as_ref()
+            .map(|path| path.to_path_buf());
+        let is_kept = snapshot_path
+            .as_ref()
+            .is_ok_and(|path| path.join("kept").is_file());
+        let is_deleted = snapshot_path
+            .as_ref()
+            .is_ok_and(|path| !path.join("sub").exists());
+
+        fs::remove_dir_all(&test_path)?;
+        snapshot_result?;
+        assert!(is_kept);
+        assert!(is_deleted);
+        Ok(())
+    }
+}
@@ -0,0 +1,377 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::PirouetteDirEntry;
+use crate::PirouetteRetentionTarget;
+use crate::clean;
+use crate::configuration::Config;
+use crate::configuration::ConfigRetentionPeriod;
+use crate::digest;
+use crate::dry_run;
+use crate::layout;
+use crate::volume;
+
+/*
+    With `snapshot_index`, each snapshot's period, path, time, size, file count, and digest are
+    kept in a SQLite database, eg: to query thousands of snapshots without walking the target.
+    It's only a cache of the target, so a failed update is a warning, and `reindex` rebuilds it.
+    Needs the "sqlite-index" cargo feature.
+*/
+
+#[cfg_attr(not(feature = "sqlite-index"), allow(dead_code))]
+#[derive(Debug, PartialEq)]
+struct IndexRow {
+    period: String,
+    path: PathBuf,
+    // Seconds since the Unix epoch
+    timestamp: i64,
+    size: u64,
+    file_count: usize,
+    checksum: String,
+}
+
+pub fn add_snapshot(config: &Config, period: &ConfigRetentionPeriod, snapshot_path: &Path) {
+    let Some(snapshot_index) = &config.options.snapshot_index else {
+        return;
+    };
+
+    if let Err(e) = get_index_row(config, period, &snapshot_path.to_path_buf().into())
+        .and_then(|index_row| database::insert_rows(snapshot_index, &[index_row]))
+    {
+        log::warn!(
+            "Failed to add {snapshot_path:?} to snapshot_index, `pirouette reindex` will rebuild it: {e:#}"
+        );
+    }
+}
+
+pub fn remove_snapshot(config: &Config, snapshot_path: &Path) {
+    let Some(snapshot_index) = &config.options.snapshot_index else {
+        return;
+    };
+
+    if let Err(e) = database::delete_row(snapshot_index, &volume::get_base_path(snapshot_path)) {
+        log::warn!(
+            "Failed to remove {snapshot_path:?} from snapshot_index, `pirouette reindex` will rebuild it: {e:#}"
+        );
+    }
+}
+
+// Replaces every row under this config's target with the snapshots actually there
+pub fn reindex_snapshots(config: &Config, all_targets: &[PirouetteRetentionTarget]) -> Result<()> {
+    let Some(snapshot_index) = &config.options.snapshot_index else {
+        anyhow::bail!("there's no snapshot_index to rebuild");
+    };
+
+    let mut index_rows = vec![];
+    for retention_target in all_targets {
+        log::info!("Indexing {retention_target} snapshots");
+        for snapshot in layout::read_snapshot_entries(retention_target)? {
+            if layout::get_snapshot_format(config, &snapshot.path).is_none() {
+                log::warn!("Not indexing {snapshot}, it's neither a directory nor a tarball");
+                continue;
+            }
+            index_rows.push(get_index_row(config, &retention_target.period, &snapshot)?);
+        }
+    }
+
+    dry_run!(
+        config.options.dry_run,
+        format!("{snapshot_index:?} will not be rebuilt"),
+        {
+            log::info!(
+                "Rebuilding {snapshot_index:?} with {} snapshots",
+                index_rows.len()
+            );
+            database::replace_rows(snapshot_index, &config.target.path, &index_rows)
+        }
+    )
+}
+
+fn get_index_row(
+    config: &Config,
+    period: &ConfigRetentionPeriod,
+    snapshot: &PirouetteDirEntry,
+) -> Result<IndexRow> {
+    // As for `prune`, a snapshot's time is read from its name where it can be
+    let timestamp = layout::parse_snapshot_name_time(&snapshot.path).unwrap_or(snapshot.timestamp);
+    let (checksum, file_count) = digest::get_snapshot_digest_and_count(config, &snapshot.path)?;
+
+    // A split tarball is keyed by its base path, however it was found
+    Ok(IndexRow {
+        period: period.to_string(),
+        path: volume::get_base_path(&snapshot.path),
+        timestamp: timestamp
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or_default(),
+        size: clean::get_snapshot_size(&snapshot.path),
+        file_count,
+        checksum,
+    })
+}
+
+#[cfg(feature = "sqlite-index")]
+mod database {
+    use super::*;
+    use anyhow::Context;
+    use rusqlite::{Connection, params};
+
+    const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS snapshots (
+        path TEXT PRIMARY KEY,
+        period TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        file_count INTEGER NOT NULL,
+        checksum TEXT NOT NULL
+    )";
+
+    fn open(snapshot_index: &Path) -> Result<Connection> {
+        let connection = Connection::open(snapshot_index)
+            .with_context(|| format!("failed to open snapshot_index {snapshot_index:?}"))?;
+        connection
+            .execute(CREATE_TABLE, [])
+            .with_context(|| format!("failed to create table in {snapshot_index:?}"))?;
+        Ok(connection)
+    }
+
+    fn insert_row(connection: &Connection, index_row: &IndexRow) -> rusqlite::Result<usize> {
+        connection.execute(
+            "INSERT OR REPLACE INTO snapshots (path, period, timestamp, size, file_count, checksum)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                index_row.path.to_string_lossy(),
+                index_row.period,
+                index_row.timestamp,
+                index_row.size as i64,
+                index_row.file_count as i64,
+                index_row.checksum,
+            ],
+        )
+    }
+
+    pub fn insert_rows(snapshot_index: &Path, index_rows: &[IndexRow]) -> Result<()> {
+        let connection = open(snapshot_index)?;
+        for index_row in index_rows {
+            insert_row(&connection, index_row)
+                .with_context(|| format!("failed to insert into {snapshot_index:?}"))?;
+        }
+        Ok(())
+    }
+
+    pub fn delete_row(snapshot_index: &Path, snapshot_path: &Path) -> Result<()> {
+        open(snapshot_index)?
+            .execute(
+                "DELETE FROM snapshots WHERE path = ?1",
+                params![snapshot_path.to_string_lossy()],
+            )
+            .with_context(|| format!("failed to delete from {snapshot_index:?}"))?;
+        Ok(())
+    }
+
+    // Several configs may share one index, so only rows under `target_path` are replaced
+    pub fn replace_rows(
+        snapshot_index: &Path,
+        target_path: &Path,
+        index_rows: &[IndexRow],
+    ) -> Result<()> {
+        let mut connection = open(snapshot_index)?;
+        let transaction = connection
+            .transaction()
+            .with_context(|| format!("failed to start a transaction on {snapshot_index:?}"))?;
+
+        let indexed_paths: Vec<String> = transaction
+            .prepare("SELECT path FROM snapshots")
+            .and_then(|mut statement| {
+                statement
+                    .query_map([], |row| row.get(0))?
+                    .collect::<rusqlite::Result<_>>()
+            })
+            .with_context(|| format!("failed to read {snapshot_index:?}"))?;
+        for indexed_path in indexed_paths {
+            if Path::new(&indexed_path).starts_with(target_path) {
+                transaction
+                    .execute(
+                        "DELETE FROM snapshots WHERE path = ?1",
+                        params![indexed_path],
+                    )
+                    .with_context(|| format!("failed to delete from {snapshot_index:?}"))?;
+            }
+        }
+        for index_row in index_rows {
+            insert_row(&transaction, index_row)
+                .with_context(|| format!("failed to insert into {snapshot_index:?}"))?;
+        }
+
+        transaction
+            .commit()
+            .with_context(|| format!("failed to commit {snapshot_index:?}"))
+    }
+}
+
+// `snapshot_index` is refused at config parse time without the feature, so these never run
+#[cfg(not(feature = "sqlite-index"))]
+mod database {
+    use super::*;
+
+    fn unsupported() -> anyhow::Error {
+        anyhow::anyhow!(
+            "this build doesn't support snapshot_index, it needs the \"sqlite-index\" feature"
+        )
+    }
+
+    pub fn insert_rows(_snapshot_index: &Path, _index_rows: &[IndexRow]) -> Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn delete_row(_snapshot_index: &Path, _snapshot_path: &Path) -> Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn replace_rows(
+        _snapshot_index: &Path,
+        _target_path: &Path,
+        _index_rows: &[IndexRow],
+    ) -> Result<()> {
+        Err(unsupported())
+    }
+}
+
+// Whether this build can keep a `snapshot_index`
+pub fn is_supported() -> bool {
+    cfg!(feature = "sqlite-index")
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_index_row() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\nretention.hours = 1"
+        ))?;
+        let snapshot_path = target_path.join("hours/2024-01-01T00:00:00");
+        fs::create_dir_all(snapshot_path.join("sub"))?;
+        fs::write(snapshot_path.join("foo"), "foo")?;
+        fs::write(snapshot_path.join("sub/bar"), "bar")?;
+
+        let index_row = get_index_row(
+            &config,
+            &ConfigRetentionPeriod::Hours,
+            &snapshot_path.clone().into(),
+        )?;
+        let expected_timestamp = layout::parse_snapshot_name_time(&snapshot_path)
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs() as i64);
+        let expected_checksum = digest::get_snapshot_digest(&config, &snapshot_path)?;
+
+        fs::remove_dir_all(&target_path)?;
+        assert_eq!(index_row.period, "hours");
+        assert_eq!(Some(index_row.timestamp), expected_timestamp);
+        assert_eq!(index_row.size, 6);
+        assert_eq!(index_row.file_count, 2);
+        assert_eq!(index_row.checksum, expected_checksum);
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite-index")]
+    #[test]
+    fn test_reindex_snapshots() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let snapshot_index = target_path.join("index.sqlite");
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\nretention.hours = 1\n\
+            [options]\nsnapshot_index = {snapshot_index:?}"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+        let kept_path = target_path.join("hours/2024-01-01T00:00:00");
+        let deleted_path = target_path.join("hours/2024-01-01T01:00:00");
+        fs::create_dir_all(&kept_path)?;
+        fs::create_dir_all(&deleted_path)?;
+
+        // A row for another config's target survives a rebuild of this one
+        let other_path = env::temp_dir().join("pirouette_other/hours/2024-01-01T00:00:00");
+        database::insert_rows(
+            &snapshot_index,
+            &[IndexRow {
+                period: "hours".to_string(),
+                path: other_path.clone(),
+                timestamp: 0,
+                size: 0,
+                file_count: 0,
+                checksum: String::new(),
+            }],
+        )?;
+
+        reindex_snapshots(&config, &all_targets)?;
+        remove_snapshot(&config, &deleted_path);
+        let indexed_paths: Vec<String> = rusqlite::Connection::open(&snapshot_index)?
+            .prepare("SELECT path FROM snapshots ORDER BY path")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        fs::remove_dir_all(&target_path)?;
+        assert_eq!(
+            indexed_paths,
+            vec![
+                kept_path.to_string_lossy().to_string(),
+                other_path.to_string_lossy().to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite-index")]
+    #[test]
+    fn test_split_snapshot_rows() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let snapshot_index = target_path.join("index.sqlite");
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\nretention.hours = 1\n\
+            [options]\noutput_format = \"tarball\"\nsnapshot_index = {snapshot_index:?}"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+        let get_indexed_paths = || -> Result<Vec<String>> {
+            Ok(rusqlite::Connection::open(&snapshot_index)?
+                .prepare("SELECT path FROM snapshots")?
+                .query_map([], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?)
+        };
+
+        // A tarball of a single volume, which is listed as "<snapshot>.tgz.001"
+        let directory_path = target_path.join("hours/2024-01-01T00:00:00");
+        let snapshot_path = layout::with_tarball_extension(&config, &directory_path);
+        let first_volume = volume::get_volume_path(&snapshot_path, 1);
+        fs::create_dir_all(&directory_path)?;
+        fs::write(directory_path.join("foo"), "foo")?;
+        crate::snapshot::archive_directory_snapshot(
+            &config,
+            &directory_path.clone().into(),
+            &snapshot_path,
+        )?;
+        fs::remove_dir_all(&directory_path)?;
+        fs::rename(&snapshot_path, &first_volume)?;
+
+        add_snapshot(&config, &ConfigRetentionPeriod::Hours, &snapshot_path);
+        reindex_snapshots(&config, &all_targets)?;
+        let reindexed_paths = get_indexed_paths()?;
+        remove_snapshot(&config, &first_volume);
+        let removed_paths = get_indexed_paths()?;
+
+        fs::remove_dir_all(&target_path)?;
+        assert_eq!(
+            reindexed_paths,
+            vec![snapshot_path.to_string_lossy().to_string()]
+        );
+        assert!(removed_paths.is_empty());
+        Ok(())
+    }
+}
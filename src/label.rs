@@ -0,0 +1,65 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::layout;
+
+/*
+    A label is a plain text sidecar next to a snapshot, eg: "2024-01-01T00:00.label".
+    Labelled snapshots are kept out of cleanup entirely, eg: one taken before a migration.
+*/
+
+const LABEL_EXTENSION: &str = "label";
+
+pub fn get_label_path(snapshot_path: &Path) -> PathBuf {
+    layout::get_sidecar_path(snapshot_path, LABEL_EXTENSION)
+}
+
+pub fn is_label_path(path: &Path) -> bool {
+    path.to_string_lossy()
+        .ends_with(&format!(".{LABEL_EXTENSION}"))
+}
+
+pub fn is_labelled(snapshot_path: &Path) -> bool {
+    get_label_path(snapshot_path).exists()
+}
+
+pub fn read_label(snapshot_path: &Path) -> Result<String> {
+    let label_path = get_label_path(snapshot_path);
+    let label = fs::read_to_string(&label_path)
+        .with_context(|| format!("failed to read label {label_path:?}"))?;
+
+    Ok(label.trim_end().to_string())
+}
+
+pub fn write_label(snapshot_path: &Path, label: &str) -> Result<()> {
+    if label.trim().is_empty() {
+        anyhow::bail!("a snapshot label can't be empty");
+    }
+
+    let label_path = get_label_path(snapshot_path);
+    log::info!("Labelling {snapshot_path:?} as {label:?}");
+    fs::write(&label_path, format!("{label}\n"))
+        .with_context(|| format!("failed to write label {label_path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_write_label() -> Result<()> {
+        let snapshot_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let unlabelled = is_labelled(&snapshot_path);
+        write_label(&snapshot_path, "pre-migration")?;
+        let label = read_label(&snapshot_path);
+        fs::remove_file(get_label_path(&snapshot_path))?;
+
+        assert!(!unlabelled);
+        assert_eq!(label?, "pre-migration");
+        assert!(is_label_path(&get_label_path(&snapshot_path)));
+        assert!(write_label(&snapshot_path, " ").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,430 @@
+use anyhow::Context;
+use chrono::format::{Item, StrftimeItems};
+use glob::Pattern;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::PirouetteDirEntry;
+use crate::PirouetteRetentionTarget;
+use crate::configuration::Config;
+use crate::configuration::ConfigOptsOutputFormat;
+use crate::configuration::ConfigRetentionPeriod;
+use crate::in_progress;
+use crate::label;
+use crate::manifest;
+use crate::pause;
+use crate::run_id;
+use crate::volume;
+
+// Replaced with the retention period's name, eg: "days"
+pub const LAYOUT_PERIOD: &str = "{period}";
+
+/*
+    Where snapshots are stored inside `target`, from the `layout` template
+*/
+
+// The directory a new snapshot goes in, eg: "{period}/%Y/%m" -> "<target>/days/2025/01"
+pub fn render_layout(
+    target_path: &Path,
+    layout: &str,
+    period: &ConfigRetentionPeriod,
+    now: &chrono::DateTime<chrono::Local>,
+) -> PathBuf {
+    let layout = layout.replace(LAYOUT_PERIOD, &period.to_string());
+    target_path.join(now.format(&layout).to_string())
+}
+
+// The layout only up to `{period}`, eg: "{period}/%Y/%m" -> "<target>/days". Unlike
+// `render_layout`, it doesn't move on with the date after `{period}`.
+pub fn render_period_path(
+    target_path: &Path,
+    layout: &str,
+    period: &ConfigRetentionPeriod,
+    now: &chrono::DateTime<chrono::Local>,
+) -> PathBuf {
+    let mut period_layout = PathBuf::new();
+    for component in Path::new(layout).components() {
+        period_layout.push(component);
+        if component
+            .as_os_str()
+            .to_string_lossy()
+            .contains(LAYOUT_PERIOD)
+        {
+            break;
+        }
+    }
+
+    render_layout(target_path, &period_layout.to_string_lossy(), period, now)
+}
+
+// A glob for every snapshot of a period, at the leaf level of the layout.
+// Each date field matches any value, so older snapshots are still found after the date moves on.
+pub fn get_layout_pattern(
+    target_path: &Path,
+    layout: &str,
+    period: &ConfigRetentionPeriod,
+) -> String {
+    let layout = layout.replace(LAYOUT_PERIOD, &period.to_string());
+
+    let mut layout_pattern = String::new();
+    let mut literal = String::new();
+    let mut layout_chars = layout.chars().peekable();
+    while let Some(layout_char) = layout_chars.next() {
+        if layout_char != '%' {
+            literal.push(layout_char);
+            continue;
+        }
+        if layout_chars.peek() == Some(&'%') {
+            layout_chars.next();
+            literal.push('%');
+            continue;
+        }
+
+        // Skip any padding or precision modifiers, eg: "%-d" or "%.3f"
+        while layout_chars
+            .peek()
+            .is_some_and(|c| matches!(c, '-' | '_' | '0'..='9' | ':' | '.' | '#'))
+        {
+            layout_chars.next();
+        }
+
+        layout_pattern.push_str(&Pattern::escape(&literal));
+        literal.clear();
+        match layout_chars.next() {
+            // These expand to a full date, including slashes
+            Some('D') | Some('x') => layout_pattern.push_str("*/*/*"),
+            _ => layout_pattern.push('*'),
+        }
+    }
+    layout_pattern.push_str(&Pattern::escape(&literal));
+
+    format!(
+        "{}/{layout_pattern}/*",
+        Pattern::escape(&target_path.display().to_string())
+    )
+}
+
+// Every tarball is named with `tarball_extension`, eg: "<snapshot>.tgz"
+pub fn with_tarball_extension(config: &Config, path: &Path) -> PathBuf {
+    let mut tarball_path = path.as_os_str().to_owned();
+    tarball_path.push(format!(".{}", config.options.tarball_extension));
+    tarball_path.into()
+}
+
+fn is_tarball_path(config: &Config, path: &Path) -> bool {
+    path.to_string_lossy()
+        .ends_with(&format!(".{}", config.options.tarball_extension))
+}
+
+// A valid `tarball_extension` is a plain suffix, eg: "tar.gz", which can't change the directory
+pub fn validate_tarball_extension(tarball_extension: &str) -> anyhow::Result<()> {
+    if tarball_extension.is_empty() {
+        anyhow::bail!("tarball_extension must not be empty");
+    }
+    if tarball_extension.starts_with('.') || tarball_extension.ends_with('.') {
+        anyhow::bail!("tarball_extension {tarball_extension:?} must not start or end with \".\"");
+    }
+    if tarball_extension.contains(std::path::is_separator) {
+        anyhow::bail!("tarball_extension {tarball_extension:?} must not contain a path separator");
+    }
+
+    Ok(())
+}
+
+// A valid layout keeps periods apart, stays inside `target`, and only uses known date fields
+pub fn validate_layout(layout: &str) -> anyhow::Result<()> {
+    if !layout.contains(LAYOUT_PERIOD) {
+        anyhow::bail!("layout {layout:?} must contain {LAYOUT_PERIOD}");
+    }
+
+    let layout_path = Path::new(layout);
+    if layout_path.is_absolute() {
+        anyhow::bail!("layout {layout:?} must be relative to the target");
+    }
+    if layout_path
+        .components()
+        .any(|component| component == std::path::Component::ParentDir)
+    {
+        anyhow::bail!("layout {layout:?} must not contain \"..\"");
+    }
+
+    if StrftimeItems::new(layout).any(|item| matches!(item, Item::Error)) {
+        anyhow::bail!("layout {layout:?} contains an invalid date field");
+    }
+
+    Ok(())
+}
+
+// Anything which can't be read is left out with a warning, eg: for listings
+pub fn get_snapshot_entries(retention_target: &PirouetteRetentionTarget) -> Vec<PirouetteDirEntry> {
+    let entry_paths = match glob::glob(&retention_target.snapshot_pattern) {
+        Ok(entry_paths) => entry_paths,
+        Err(e) => {
+            log::warn!("failed to read {retention_target} snapshots: {e}");
+            return vec![];
+        }
+    };
+
+    // Convert to abstracted testable type
+    entry_paths
+        .filter_map(|entry_path| {
+            entry_path
+                .inspect_err(|e| log::warn!("failed to read {retention_target} snapshots: {e}"))
+                .ok()
+        })
+        .filter(|entry_path| is_snapshot_entry_path(entry_path))
+        .map(|entry_path| entry_path.into())
+        .collect()
+}
+
+// A missing period directory has no snapshots yet, but an unreadable one is an error, eg: for
+// cleanup, which would otherwise take a broken target for an empty one
+pub fn read_snapshot_entries(
+    retention_target: &PirouetteRetentionTarget,
+) -> anyhow::Result<Vec<PirouetteDirEntry>> {
+    let entry_paths = glob::glob(&retention_target.snapshot_pattern)
+        .with_context(|| format!("failed to read {retention_target} snapshots"))?;
+
+    let mut snapshot_entries = vec![];
+    for entry_path in entry_paths {
+        let entry_path =
+            entry_path.with_context(|| format!("failed to read {retention_target} snapshots"))?;
+        if is_snapshot_entry_path(&entry_path) {
+            snapshot_entries.push(entry_path.into());
+        }
+    }
+
+    Ok(snapshot_entries)
+}
+
+fn is_snapshot_entry_path(entry_path: &Path) -> bool {
+    !manifest::is_manifest_path(entry_path)
+        && !in_progress::is_marker_path(entry_path)
+        && !in_progress::is_resume_path(entry_path)
+        && !pause::is_pause_marker_path(entry_path)
+        && !label::is_label_path(entry_path)
+        && !run_id::is_run_id_path(entry_path)
+        && !volume::is_extra_volume_path(entry_path)
+}
+
+// A sidecar of a split tarball is named after the tarball, not its first volume,
+// eg: "<snapshot>.tgz.001" -> "<snapshot>.tgz.label"
+pub fn get_sidecar_path(snapshot_path: &Path, sidecar_extension: &str) -> PathBuf {
+    let mut sidecar_path = volume::get_base_path(snapshot_path).into_os_string();
+    sidecar_path.push(format!(".{sidecar_extension}"));
+    sidecar_path.into()
+}
+
+// The plain files kept beside a snapshot, whether or not they exist, eg: "<snapshot>.label"
+pub fn get_sidecar_paths(snapshot_path: &Path) -> [PathBuf; 3] {
+    [
+        manifest::get_manifest_path(snapshot_path),
+        label::get_label_path(snapshot_path),
+        run_id::get_run_id_path(snapshot_path),
+    ]
+}
+
+pub fn is_sidecar_path(path: &Path) -> bool {
+    manifest::is_manifest_path(path) || label::is_label_path(path) || run_id::is_run_id_path(path)
+}
+
+// A snapshot is either a directory, or a tarball, which may be split into volumes.
+// Anything else is a sidecar or a stray file, eg: "<snapshot>.sha256".
+// Split or not, a tarball only counts with the current `tarball_extension`.
+pub fn get_snapshot_format(config: &Config, path: &Path) -> Option<ConfigOptsOutputFormat> {
+    if path.is_dir() {
+        Some(ConfigOptsOutputFormat::Directory)
+    } else if is_tarball_path(config, &volume::get_base_path(path)) {
+        Some(ConfigOptsOutputFormat::Tarball)
+    } else {
+        None
+    }
+}
+
+// The time a snapshot was taken, from its name, eg: "2024-01-01T00:00:00.tgz.001".
+// Names from before seconds were added, eg: "2024-01-01T00:00", are also understood.
+pub fn parse_snapshot_name_time(path: &Path) -> Option<SystemTime> {
+    let file_name = path.file_name()?.to_string_lossy();
+    let naive_datetime = ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| {
+            let timestamp_length = if format.ends_with("%S") { 19 } else { 16 };
+            let timestamp = file_name.get(..timestamp_length)?;
+            let remainder = &file_name[timestamp_length..];
+            // Anything after the time must be a tarball extension or a volume number
+            if !remainder.is_empty() && !remainder.starts_with('.') {
+                return None;
+            }
+            chrono::NaiveDateTime::parse_from_str(timestamp, format).ok()
+        })?;
+    naive_datetime
+        .and_local_timezone(chrono::Local)
+        .earliest()
+        .map(SystemTime::from)
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn local_time(datetime: &str) -> chrono::DateTime<chrono::Local> {
+        chrono::NaiveDateTime::parse_from_str(datetime, "%Y-%m-%dT%H:%M")
+            .unwrap()
+            .and_local_timezone(chrono::Local)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_render_layout() {
+        let now = local_time("2025-01-05T23:55");
+        let target_path = Path::new("/target");
+
+        let default_path =
+            render_layout(target_path, "{period}", &ConfigRetentionPeriod::Days, &now);
+        assert_eq!(default_path, PathBuf::from("/target/days"));
+
+        let dated_path = render_layout(
+            target_path,
+            "{period}/%Y/%m",
+            &ConfigRetentionPeriod::Days,
+            &now,
+        );
+        assert_eq!(dated_path, PathBuf::from("/target/days/2025/01"));
+
+        let period_path = render_period_path(
+            target_path,
+            "{period}/%Y/%m",
+            &ConfigRetentionPeriod::Days,
+            &now,
+        );
+        assert_eq!(period_path, PathBuf::from("/target/days"));
+    }
+
+    #[test]
+    fn test_layout_pattern() {
+        let target_path = Path::new("/target[1]");
+        let test_params = vec![
+            ("{period}", "/target[[]1[]]/hours/*"),
+            ("{period}/%Y/%-m", "/target[[]1[]]/hours/*/*/*"),
+            ("%Y/{period}_%%", "/target[[]1[]]/*/hours_%/*"),
+            ("{period}/%D", "/target[[]1[]]/hours/*/*/*/*"),
+        ];
+
+        for (layout, expected) in test_params {
+            let actual = get_layout_pattern(target_path, layout, &ConfigRetentionPeriod::Hours);
+            assert_eq!(actual, expected, "{layout}");
+        }
+    }
+
+    #[test]
+    fn test_validate_layout() {
+        assert!(validate_layout("{period}").is_ok());
+        assert!(validate_layout("{period}/%Y/%m").is_ok());
+        assert!(validate_layout("%Y").is_err());
+        assert!(validate_layout("/{period}").is_err());
+        assert!(validate_layout("../{period}").is_err());
+        assert!(validate_layout("{period}/%Q").is_err());
+    }
+
+    #[test]
+    fn test_parse_snapshot_name_time() {
+        let expected_time = Some(SystemTime::from(local_time("2024-01-01T00:00")));
+
+        for name in [
+            "2024-01-01T00:00:00",
+            "2024-01-01T00:00:00.tgz.001",
+            "2024-01-01T00:00",
+            "2024-01-01T00:00.tar.gz",
+        ] {
+            assert_eq!(
+                parse_snapshot_name_time(Path::new(name)),
+                expected_time,
+                "{name}"
+            );
+        }
+        assert_eq!(
+            parse_snapshot_name_time(Path::new("2024-01-01T00:00:00x")),
+            None
+        );
+        assert_eq!(parse_snapshot_name_time(Path::new("snapshot")), None);
+    }
+
+    #[test]
+    fn test_validate_tarball_extension() {
+        assert!(validate_tarball_extension("tgz").is_ok());
+        assert!(validate_tarball_extension("tar.gz").is_ok());
+        assert!(validate_tarball_extension("").is_err());
+        assert!(validate_tarball_extension(".tgz").is_err());
+        assert!(validate_tarball_extension("foo/tgz").is_err());
+    }
+
+    #[test]
+    fn test_snapshot_format() -> anyhow::Result<()> {
+        let config: Config = toml::from_str(
+            "source.path = \"/source\"\ntarget.path = \"/target\"\nretention.hours = 1\n\
+            [options]\ntarball_extension = \"tar.gz\"",
+        )?;
+        let get_format =
+            |name: &str| get_snapshot_format(&config, &Path::new("/target").join(name));
+
+        assert_eq!(
+            get_format("a.tar.gz"),
+            Some(ConfigOptsOutputFormat::Tarball)
+        );
+        assert_eq!(
+            get_format("a.tar.gz.001"),
+            Some(ConfigOptsOutputFormat::Tarball)
+        );
+        // A tarball from before `tarball_extension` changed is a stray file, split or not
+        assert_eq!(get_format("a.tgz"), None);
+        assert_eq!(get_format("a.tgz.001"), None);
+        assert_eq!(get_format("a.tar.gz.label"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_entries_span_layout_directories() -> anyhow::Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let layout = "{period}/%Y/%m";
+        for snapshot in ["days/2024/12/a", "days/2025/01/b", "hours/2025/01/c"] {
+            fs::create_dir_all(target_path.join(snapshot))?;
+        }
+        fs::write(target_path.join("days/2025/01/b.manifest.gz"), "")?;
+
+        let retention_target = PirouetteRetentionTarget {
+            period: ConfigRetentionPeriod::Days,
+            path: target_path.join("days/2025/01"),
+            period_path: target_path.join("days"),
+            snapshot_pattern: get_layout_pattern(
+                &target_path,
+                layout,
+                &ConfigRetentionPeriod::Days,
+            ),
+            limit: crate::configuration::ConfigRetentionLimit::Count(1),
+            output_format: crate::configuration::ConfigOptsOutputFormat::Directory,
+            dry_run: false,
+            exclude_modified_within: None,
+        };
+        let mut entry_paths: Vec<PathBuf> = get_snapshot_entries(&retention_target)
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        entry_paths.sort();
+
+        fs::remove_dir_all(&target_path)?;
+        assert_eq!(
+            entry_paths,
+            vec![
+                target_path.join("days/2024/12/a"),
+                target_path.join("days/2025/01/b"),
+            ]
+        );
+        Ok(())
+    }
+}
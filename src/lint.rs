@@ -0,0 +1,180 @@
+use anyhow::Result;
+use glob::Pattern;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::PirouetteStats;
+use crate::configuration::Config;
+use crate::configuration::ConfigRetentionLimit;
+use crate::snapshot;
+
+// How many source entries to test the include/exclude patterns against
+const PATTERN_SAMPLE_SIZE: usize = 1000;
+
+/*
+    Soft problems with a config which parsed and validated, but is probably a mistake
+*/
+
+#[derive(Debug, PartialEq)]
+pub struct ConfigWarning {
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+pub fn print_config_warnings(config: &Config) -> Result<()> {
+    let warnings = get_config_warnings(config)?;
+
+    for warning in &warnings {
+        println!("warning: {warning}");
+    }
+    println!("Config is valid, with {} warnings", warnings.len());
+
+    Ok(())
+}
+
+pub fn get_config_warnings(config: &Config) -> Result<Vec<ConfigWarning>> {
+    let mut warnings = vec![];
+
+    if is_source_empty(&config.source.path) {
+        warnings.push(ConfigWarning {
+            field: "source.path".to_string(),
+            message: "source is empty, so snapshots will contain nothing".to_string(),
+        });
+    }
+
+    if is_same_filesystem(&config.source.path, &config.target.path) {
+        warnings.push(ConfigWarning {
+            field: "target.path".to_string(),
+            message: "target is on the same filesystem as source, so one disk failure loses both"
+                .to_string(),
+        });
+    }
+
+    for (retention_period, retention) in config.retention.iter() {
+        if retention.limit == ConfigRetentionLimit::Count(1) {
+            warnings.push(ConfigWarning {
+                field: format!("retention.{retention_period}"),
+                message: "only one snapshot is kept, so there is no history to restore from"
+                    .to_string(),
+            });
+        }
+    }
+
+    let sample_paths: Vec<_> =
+        snapshot::get_source_contents_iter(config, &PirouetteStats::default())?
+            .filter_map(|entry| entry.ok())
+            .take(PATTERN_SAMPLE_SIZE)
+            .map(|entry| snapshot::format_inner_entry_path(config, &entry))
+            .collect();
+
+    for (field, patterns) in [
+        ("options.include", &config.options.include),
+        ("options.exclude", &config.options.exclude),
+    ] {
+        for pattern in get_unmatched_patterns(patterns, &sample_paths) {
+            warnings.push(ConfigWarning {
+                field: field.to_string(),
+                message: format!(
+                    "pattern {:?} matched nothing in the source",
+                    pattern.as_str()
+                ),
+            });
+        }
+    }
+
+    Ok(warnings)
+}
+
+fn is_source_empty(source_path: &Path) -> bool {
+    match fs::read_dir(source_path) {
+        Ok(mut entries) => entries.next().is_none(),
+        // A single file source is never empty
+        Err(_) => false,
+    }
+}
+
+fn is_same_filesystem(source_path: &Path, target_path: &Path) -> bool {
+    // The target may not exist yet, so compare against the part which does
+    let target_path = target_path.ancestors().find(|path| path.exists());
+
+    match (fs::metadata(source_path), target_path.map(fs::metadata)) {
+        (Ok(source_metadata), Some(Ok(target_metadata))) => {
+            source_metadata.dev() == target_metadata.dev()
+        }
+        _ => false,
+    }
+}
+
+fn get_unmatched_patterns<'a>(
+    patterns: &'a [Pattern],
+    sample_paths: &[std::path::PathBuf],
+) -> Vec<&'a Pattern> {
+    patterns
+        .iter()
+        .filter(|pattern| {
+            !sample_paths
+                .iter()
+                .any(|path| pattern.matches_path(path))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_config_warnings() -> Result<()> {
+        let source_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&source_path)?;
+
+        let config: Config = toml::from_str(&format!(
+            r#"
+            source.path = "{}"
+            target.path = "{}"
+            retention.hours = 1
+            retention.days = 7
+            options.include = ["*.txt"]
+            "#,
+            source_path.display(),
+            source_path.join("target").display()
+        ))?;
+        let warnings = get_config_warnings(&config);
+
+        fs::remove_dir_all(&source_path)?;
+
+        let fields: Vec<String> = warnings?.into_iter().map(|w| w.field).collect();
+        assert_eq!(
+            fields,
+            vec![
+                "source.path",
+                "target.path",
+                "retention.hours",
+                "options.include"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unmatched_patterns() {
+        let patterns = vec![
+            Pattern::new("foo/*").unwrap(),
+            Pattern::new("*.bak").unwrap(),
+        ];
+        let sample_paths = vec![PathBuf::from("foo/bar.txt"), PathBuf::from("baz.txt")];
+
+        let unmatched = get_unmatched_patterns(&patterns, &sample_paths);
+        assert_eq!(unmatched, vec![&patterns[1]]);
+    }
+}
@@ -0,0 +1,94 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::configuration::Config;
+
+/*
+    With `log_file`, logs are appended to a file instead of stderr. Older logs are rotated
+    to "<log_file>.1", "<log_file>.2", and so on, keeping at most `log_max_files` of them.
+*/
+
+// Rotate the log file if it's due, then open it for appending
+pub fn open_log_file(config: &Config, log_file: &Path) -> Result<fs::File> {
+    if is_rotation_due(log_file, config.options.log_max_size) {
+        rotate_log_files(log_file, config.options.log_max_files)
+            .with_context(|| format!("failed to rotate log file {log_file:?}"))?;
+    }
+
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)
+        .with_context(|| format!("failed to open log file {log_file:?}"))
+}
+
+// Without `log_max_size`, every run starts a new log file
+fn is_rotation_due(log_file: &Path, log_max_size: Option<u64>) -> bool {
+    let Ok(metadata) = fs::metadata(log_file) else {
+        return false;
+    };
+
+    match log_max_size {
+        Some(log_max_size) => metadata.len() >= log_max_size,
+        None => metadata.len() > 0,
+    }
+}
+
+fn get_rotated_path(log_file: &Path, index: usize) -> PathBuf {
+    let mut rotated_path = log_file.as_os_str().to_owned();
+    rotated_path.push(format!(".{index}"));
+    rotated_path.into()
+}
+
+// Shift each log along by one, dropping the oldest beyond `log_max_files`
+fn rotate_log_files(log_file: &Path, log_max_files: usize) -> Result<()> {
+    if log_max_files == 0 {
+        return Ok(fs::remove_file(log_file)?);
+    }
+
+    let oldest_path = get_rotated_path(log_file, log_max_files);
+    if oldest_path.exists() {
+        fs::remove_file(&oldest_path)?;
+    }
+
+    for index in (1..log_max_files).rev() {
+        let rotated_path = get_rotated_path(log_file, index);
+        if rotated_path.exists() {
+            fs::rename(&rotated_path, get_rotated_path(log_file, index + 1))?;
+        }
+    }
+
+    Ok(fs::rename(log_file, get_rotated_path(log_file, 1))?)
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_rotate_log_files() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&test_path)?;
+        let log_file = test_path.join("pirouette.log");
+
+        for run in 0..4 {
+            fs::write(&log_file, format!("run {run}"))?;
+            rotate_log_files(&log_file, 2)?;
+        }
+        let rotated_logs =
+            [1, 2, 3].map(|index| fs::read_to_string(get_rotated_path(&log_file, index)).ok());
+
+        fs::remove_dir_all(&test_path)?;
+        assert_eq!(
+            rotated_logs,
+            [Some("run 3".to_string()), Some("run 2".to_string()), None]
+        );
+        Ok(())
+    }
+}
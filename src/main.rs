@@ -1,39 +1,588 @@
 use anyhow::{Context, Result};
+use std::cell::Cell;
 use std::fmt;
 use std::fs;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::time::SystemTime;
 
+use clap::ValueEnum;
+
 use crate::configuration::Config;
+use crate::configuration::ConfigOptsOutputFormat;
+use crate::configuration::ConfigRetentionLimit;
 use crate::configuration::ConfigRetentionPeriod;
+use crate::error::{ErrorPhase, PirouetteError, phase};
 
+mod acl;
+mod archive;
+mod audit;
 mod clean;
+mod cli;
 mod configuration;
+mod consolidate;
 mod current_state;
+mod digest;
+mod entry_log;
+mod error;
+mod estimate;
+mod hook;
+mod in_progress;
+mod index;
+mod label;
+mod layout;
+mod lint;
+mod log_file;
+mod manifest;
+mod mirror;
+mod notify;
+mod pause;
+mod promote;
+mod prune;
+mod repair;
+mod run_id;
 mod snapshot;
+mod timezone;
+mod volume;
+mod working_copy;
+
+fn main() -> ExitCode {
+    let cli = cli::parse_cli();
+
+    match run(&cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => e.report(&cli.error_format),
+    }
+}
+
+fn run(cli: &cli::Cli) -> Result<(), PirouetteError> {
+    // Doesn't need a config, so it can diagnose a build before one is written
+    if cli.capabilities {
+        print_capabilities();
+        return Ok(());
+    }
+
+    let timezone_warning = timezone::fall_back_to_utc_if_missing();
+    if let Some(config_dir) = configuration::get_config_dir_path() {
+        return run_config_dir(cli, &config_dir, timezone_warning);
+    }
+
+    let mut config = configuration::parse_config().map_err(phase(ErrorPhase::Config))?;
+    apply_cli_options(cli, &mut config).map_err(phase(ErrorPhase::Config))?;
+    start_logging(&config, timezone_warning)?;
+    run_command(cli, config)
+}
+
+// Checked along with the config, so a conflict is refused before any work is done
+fn apply_cli_options(cli: &cli::Cli, config: &mut Config) -> Result<()> {
+    if cli.dry_run {
+        config.options.dry_run = true;
+    }
+    config.options.run_id = cli
+        .run_id
+        .clone()
+        .filter(|run_id| !run_id.trim().is_empty());
 
-fn main() -> Result<()> {
-    let config = configuration::parse_config()?;
+    if let Some(cli::Command::Snapshot { stdout: true, .. }) = &cli.command
+        && entry_log::is_stdout(config)
+    {
+        anyhow::bail!("entry_log can't be stdout while the snapshot is written to stdout");
+    }
+    Ok(())
+}
 
-    initialise_logger(&config);
+fn start_logging(config: &Config, timezone_warning: Option<String>) -> Result<(), PirouetteError> {
+    initialise_logger(config).map_err(phase(ErrorPhase::Config))?;
     log::info!("Logger initialised");
+    if let Some(timezone_warning) = timezone_warning {
+        log::warn!("{timezone_warning}");
+    }
+    Ok(())
+}
+
+// With `PIROUETTE_CONFIG_DIR`, each config file is a job of its own, and one failing doesn't
+// stop the rest. The first failure is returned once they've all finished.
+fn run_config_dir(
+    cli: &cli::Cli,
+    config_dir: &Path,
+    timezone_warning: Option<String>,
+) -> Result<(), PirouetteError> {
+    let config_files =
+        configuration::get_config_dir_files(config_dir).map_err(phase(ErrorPhase::Config))?;
+    let configs: Vec<(PathBuf, Result<Config, PirouetteError>)> = config_files
+        .into_iter()
+        .map(|config_file| {
+            let config = configuration::parse_config_file(&config_file)
+                .and_then(|mut config| {
+                    apply_cli_options(cli, &mut config)?;
+                    Ok(config)
+                })
+                .map_err(phase(ErrorPhase::Config));
+            (config_file, config)
+        })
+        .collect();
+
+    // There's only one logger for the whole process, so it's set up by the first valid config
+    if let Some(config) = configs
+        .iter()
+        .find_map(|(_, config)| config.as_ref().ok())
+    {
+        start_logging(config, timezone_warning)?;
+    }
+
+    let run_job = |config_file: &Path, config: Result<Config, PirouetteError>| {
+        log::info!("Running config file {config_file:?}");
+        config.and_then(|config| run_command(cli, config))
+    };
+    let job_results: Vec<(PathBuf, Result<(), PirouetteError>)> = match cli.parallel {
+        true => std::thread::scope(|scope| {
+            let jobs: Vec<_> = configs
+                .into_iter()
+                .map(|(config_file, config)| {
+                    let job = scope.spawn({
+                        let config_file = config_file.clone();
+                        move || run_job(&config_file, config)
+                    });
+                    (config_file, job)
+                })
+                .collect();
+            jobs.into_iter()
+                .map(|(config_file, job)| {
+                    let job_result = job.join().unwrap_or_else(|_| {
+                        Err(PirouetteError {
+                            phase: ErrorPhase::Rotate,
+                            error: anyhow::anyhow!("the job panicked"),
+                        })
+                    });
+                    (config_file, job_result)
+                })
+                .collect()
+        }),
+        false => configs
+            .into_iter()
+            .map(|(config_file, config)| {
+                let job_result = run_job(&config_file, config);
+                (config_file, job_result)
+            })
+            .collect(),
+    };
+
+    let job_count = job_results.len();
+    let failed_jobs: Vec<(PathBuf, PirouetteError)> = job_results
+        .into_iter()
+        .filter_map(|(config_file, job_result)| job_result.err().map(|e| (config_file, e)))
+        .collect();
+    for (config_file, e) in &failed_jobs {
+        log::error!("{config_file:?} failed to {}: {:#}", e.phase, e.error);
+    }
+
+    let failed_count = failed_jobs.len();
+    match failed_jobs.into_iter().next() {
+        Some((config_file, e)) => Err(PirouetteError {
+            phase: e.phase,
+            error: e.error.context(format!(
+                "{failed_count} of {job_count} config files failed, the first being {config_file:?}"
+            )),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn run_command(cli: &cli::Cli, mut config: Config) -> Result<(), PirouetteError> {
     log::debug!("Parsed config file:\n{config:#?}");
 
-    let all_targets: Vec<PirouetteRetentionTarget> = get_all_retention_targets(&config);
-    let rotation_targets = current_state::get_rotation_targets(&config, all_targets)?;
+    match &cli.command {
+        Some(cli::Command::Lint) => {
+            lint::print_config_warnings(&config).map_err(phase(ErrorPhase::Lint))
+        }
+        Some(cli::Command::Verify { snapshot }) => {
+            manifest::verify_snapshots(&get_all_retention_targets(&config), snapshot.as_deref())
+                .map_err(phase(ErrorPhase::Verify))
+        }
+        Some(cli::Command::Init) => {
+            current_state::initialise_target(&config, &get_all_retention_targets(&config))
+                .map_err(phase(ErrorPhase::Init))
+        }
+        Some(cli::Command::Targets { json }) => {
+            print_retention_targets(&get_all_retention_targets(&config), *json)
+                .map_err(phase(ErrorPhase::Targets))
+        }
+        Some(cli::Command::Consolidate { period, by }) => consolidate::consolidate_snapshots(
+            &config,
+            &get_all_retention_targets(&config),
+            period,
+            by,
+        )
+        .map_err(phase(ErrorPhase::Consolidate)),
+        Some(cli::Command::Prune { before }) => {
+            prune::prune_snapshots(&config, &get_all_retention_targets(&config), *before)
+                .map_err(phase(ErrorPhase::Prune))
+        }
+        Some(cli::Command::Estimate { period, sample }) => estimate::print_snapshot_estimate(
+            &config,
+            &get_all_retention_targets(&config),
+            period,
+            *sample,
+        )
+        .map_err(phase(ErrorPhase::Estimate)),
+        Some(cli::Command::Pause { period, until }) => {
+            pause::pause_period(&get_all_retention_targets(&config), period, *until)
+                .map_err(phase(ErrorPhase::Pause))
+        }
+        Some(cli::Command::Resume { period }) => {
+            pause::resume_period(&get_all_retention_targets(&config), period)
+                .map_err(phase(ErrorPhase::Resume))
+        }
+        Some(cli::Command::Repair) => {
+            repair::repair_targets(&config, &get_all_retention_targets(&config))
+                .map_err(phase(ErrorPhase::Repair))
+        }
+        Some(cli::Command::Reindex) => {
+            index::reindex_snapshots(&config, &get_all_retention_targets(&config))
+                .map_err(phase(ErrorPhase::Reindex))
+        }
+        Some(cli::Command::Snapshot {
+            period,
+            stdout,
+            label,
+            fail_if_unchanged,
+        }) => {
+            let is_unchanged = take_snapshot(
+                &config,
+                period,
+                *stdout,
+                label.as_deref(),
+                *fail_if_unchanged,
+            )
+            .map_err(phase(ErrorPhase::Snapshot))?;
+            match is_unchanged {
+                true => Err(PirouetteError {
+                    phase: ErrorPhase::Unchanged,
+                    error: anyhow::anyhow!(
+                        "the {period} snapshot is identical to the previous snapshot"
+                    ),
+                }),
+                false => Ok(()),
+            }
+        }
+        None => {
+            current_state::disable_unsupported_features(&mut config);
+            let rotate_result = rotate_snapshots(&config, cli);
+            notify::notify_run_result(&config, &rotate_result);
+            rotate_result.map_err(phase(ErrorPhase::Rotate))
+        }
+    }
+}
+
+// The period directories pirouette manages, for external tooling
+fn print_retention_targets(all_targets: &[PirouetteRetentionTarget], json: bool) -> Result<()> {
+    if json {
+        let targets_json = serde_json::to_string_pretty(all_targets)
+            .context("failed to serialise retention targets")?;
+        println!("{targets_json}");
+        return Ok(());
+    }
 
-    for retention_target in rotation_targets {
-        snapshot::copy_snapshot(&config, &retention_target)
+    for retention_target in all_targets {
+        println!(
+            "{:<8} {:<20} {}",
+            retention_target.to_string(),
+            retention_target.limit.to_string(),
+            retention_target.path.display()
+        );
+    }
+    Ok(())
+}
+
+fn print_capabilities() {
+    let output_formats: Vec<String> = ConfigOptsOutputFormat::value_variants()
+        .iter()
+        .filter_map(|format| format.to_possible_value())
+        .map(|value| value.get_name().to_string())
+        .collect();
+
+    println!("pirouette {}", env!("CARGO_PKG_VERSION"));
+    println!("output formats:   {}", output_formats.join(", "));
+    println!("compression:      gzip (tarball)");
+    println!("storage backends: local");
+    println!("encryption:       none");
+    let notify_backends: Vec<&str> = [
+        ("desktop", notify::is_desktop_supported()),
+        ("email", notify::is_email_supported()),
+    ]
+    .into_iter()
+    .filter(|(_, is_supported)| *is_supported)
+    .map(|(backend, _)| backend)
+    .collect();
+    println!(
+        "notifications:    {}",
+        match notify_backends.is_empty() {
+            true => "none".to_string(),
+            false => notify_backends.join(", "),
+        }
+    );
+    println!(
+        "snapshot index:   {}",
+        match index::is_supported() {
+            true => "sqlite",
+            false => "none",
+        }
+    );
+}
+
+// A one-off snapshot, outside of the usual rotation.
+// Returns whether it's unchanged from the previous snapshot, which is only checked if asked.
+fn take_snapshot(
+    config: &Config,
+    period: &ConfigRetentionPeriod,
+    stdout: bool,
+    label: Option<&str>,
+    check_unchanged: bool,
+) -> Result<bool> {
+    let retention_target = get_all_retention_targets(config)
+        .into_iter()
+        .find(|retention_target| retention_target.period == *period)
+        .with_context(|| format!("{period} is not a configured retention period"))?;
+    let stats = PirouetteStats::default();
+    let mut is_unchanged = false;
+
+    if stdout {
+        snapshot::write_snapshot_to_stdout(config, &stats)?;
+    } else {
+        current_state::check_target_writable(config)?;
+        current_state::create_target_directory(&retention_target)?;
+        if let Some(snapshot_path) = run_id::find_run_snapshot(config, &retention_target) {
+            log::info!(
+                "{retention_target} was already snapshotted by this run at {snapshot_path:?}"
+            );
+            return Ok(false);
+        }
+        let previous_snapshot = match check_unchanged {
+            true => current_state::get_newest_directory_entry(config, &retention_target),
+            false => None,
+        };
+        let snapshot_path = snapshot::copy_snapshot(config, &retention_target, &stats)
             .with_context(|| format!("failed to create snapshot for {retention_target}"))?;
 
-        clean::clean_snapshots(&config, &retention_target)?;
+        if let Some((previous_snapshot, _)) = previous_snapshot {
+            is_unchanged = is_snapshot_unchanged(
+                config,
+                &retention_target,
+                &previous_snapshot.path,
+                &snapshot_path,
+            )?;
+        }
+
+        if let Some(label) = label {
+            dry_run!(
+                retention_target.dry_run,
+                format!("snapshot will not be labelled {label:?}"),
+                { label::write_label(&snapshot_path, label) }
+            )?;
+        }
+    }
+
+    log_filter_summary(&stats);
+    snapshot::check_snapshot_stats(config, &retention_target, &stats)?;
+    Ok(is_unchanged)
+}
+
+// Compared by content, so a snapshot taken again from an unchanged source still matches
+fn is_snapshot_unchanged(
+    config: &Config,
+    retention_target: &PirouetteRetentionTarget,
+    previous_path: &Path,
+    snapshot_path: &Path,
+) -> Result<bool> {
+    if retention_target.dry_run {
+        log::debug!("[DRY RUN] snapshot will not be compared with {previous_path:?}");
+        return Ok(false);
+    }
+    // Already taken, eg: with `snapshot_time = "source_mtime"` and no new changes
+    if previous_path == snapshot_path {
+        return Ok(true);
+    }
+
+    let previous_digest = digest::get_snapshot_digest(config, previous_path)
+        .with_context(|| format!("failed to read previous snapshot {previous_path:?}"))?;
+    let snapshot_digest = digest::get_snapshot_digest(config, snapshot_path)
+        .with_context(|| format!("failed to read new snapshot {snapshot_path:?}"))?;
+    log::info!("Snapshot digest {snapshot_digest}, previously {previous_digest}");
+
+    Ok(snapshot_digest == previous_digest)
+}
+
+fn rotate_snapshots(config: &Config, cli: &cli::Cli) -> Result<()> {
+    current_state::check_target_writable(config)?;
+
+    let all_targets: Vec<PirouetteRetentionTarget> = get_all_retention_targets(config);
+    if cli.plan {
+        current_state::print_rotation_plan(config, &all_targets, cli.since);
+        // The plan is all a dry run would show, so there's nothing else worth doing
+        if config.options.dry_run {
+            return Ok(());
+        }
+    }
+    let all_targets = in_progress::check_in_progress_targets(config, all_targets)?;
+
+    let mut first_error: Option<anyhow::Error> = None;
+
+    // Periods whose directory couldn't be created are left out, but still fail the run
+    let (all_targets, directory_errors) =
+        current_state::create_target_directories(config, all_targets)?;
+    for e in directory_errors {
+        record_error(&mut first_error, e);
+    }
+
+    let rotation_targets =
+        current_state::get_rotation_targets(config, all_targets.clone(), cli.since)?;
+    let rotation_targets = run_id::skip_completed_targets(config, rotation_targets);
+
+    for snapshot_group in group_rotation_targets(config, rotation_targets) {
+        // Makes room for the new snapshot, but always leaves at least one behind
+        if config.options.clean_before_snapshot {
+            clean_snapshot_group(config, &all_targets, &snapshot_group, true)?;
+        }
+
+        // Otherwise, cleanup only ever follows a successful snapshot, so a period is never
+        // pruned without a fresh replacement. Other periods still get their chance.
+        if let Err(e) = create_snapshot_group(config, &snapshot_group) {
+            log::warn!(
+                "Skipping cleanup for {} because its snapshot failed",
+                snapshot_group.display_vec()
+            );
+            record_error(&mut first_error, e);
+            continue;
+        }
+
+        clean_snapshot_group(config, &all_targets, &snapshot_group, false)?;
+
+        // A failing mirror only warns, so it never holds back the primary `target`
+        mirror::mirror_snapshot_group(config, &snapshot_group);
+    }
+
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn clean_snapshot_group(
+    config: &Config,
+    all_targets: &[PirouetteRetentionTarget],
+    snapshot_group: &[PirouetteRetentionTarget],
+    is_before_snapshot: bool,
+) -> Result<()> {
+    for retention_target in snapshot_group {
+        let promotion_target = promote::get_promotion_target(config, all_targets, retention_target);
+        let deleted_paths = match is_before_snapshot {
+            true => clean::clean_before_snapshot(config, retention_target, promotion_target)?,
+            false => clean::clean_snapshots(config, retention_target, promotion_target)?,
+        };
+        hook::run_post_clean_hook(config, retention_target, &deleted_paths)?;
+    }
+
+    Ok(())
+}
+
+// The first error is returned at the end of the run, and any after it are logged
+fn record_error(first_error: &mut Option<anyhow::Error>, e: anyhow::Error) {
+    match first_error {
+        Some(_) => log::error!("{e:?}"),
+        None => *first_error = Some(e),
+    }
+}
+
+fn create_snapshot_group(
+    config: &Config,
+    snapshot_group: &[PirouetteRetentionTarget],
+) -> Result<()> {
+    let stats = PirouetteStats::default();
+
+    // Periods due at the same time can share one copy of the source.
+    // With `temp_dir`, every snapshot is written there first, then moved into place.
+    if snapshot_group.len() > 1 || config.options.temp_dir.is_some() {
+        snapshot::copy_staged_snapshot(config, snapshot_group, &stats).with_context(|| {
+            format!(
+                "failed to create shared snapshot for {}",
+                snapshot_group.display_vec()
+            )
+        })?;
+    } else {
+        snapshot::copy_snapshot(config, &snapshot_group[0], &stats)
+            .with_context(|| format!("failed to create snapshot for {}", snapshot_group[0]))?;
+    }
+
+    log_filter_summary(&stats);
+    for retention_target in snapshot_group {
+        snapshot::check_snapshot_stats(config, retention_target, &stats)?;
     }
 
     Ok(())
 }
 
-fn initialise_logger(config: &Config) {
+// One line for tuning `include` and `exclude`, rather than each path's decision at trace level
+fn log_filter_summary(stats: &PirouetteStats) {
+    log::info!(
+        "Filters included {} / excluded {} entries",
+        format_count(stats.included.get()),
+        format_count(stats.filtered.get())
+    );
+}
+
+// Group the thousands, eg: "4,210"
+fn format_count(count: usize) -> String {
+    let digits = count.to_string();
+    let mut formatted = String::new();
+
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
+
+    formatted
+}
+
+// With `staging`, targets which share an output format can share one snapshot.
+// Otherwise, every target gets its own.
+fn group_rotation_targets(
+    config: &Config,
+    rotation_targets: Vec<PirouetteRetentionTarget>,
+) -> Vec<Vec<PirouetteRetentionTarget>> {
+    if !config.options.staging {
+        return rotation_targets
+            .into_iter()
+            .map(|retention_target| vec![retention_target])
+            .collect();
+    }
+
+    let mut snapshot_groups: Vec<Vec<PirouetteRetentionTarget>> = vec![];
+    for retention_target in rotation_targets {
+        match snapshot_groups.iter_mut().find(|group| {
+            group[0].output_format == retention_target.output_format
+                && group[0].dry_run == retention_target.dry_run
+                && group[0].exclude_modified_within == retention_target.exclude_modified_within
+        }) {
+            Some(group) => group.push(retention_target),
+            None => snapshot_groups.push(vec![retention_target]),
+        }
+    }
+
+    snapshot_groups
+}
+
+fn initialise_logger(config: &Config) -> Result<()> {
+    // Keeps stdout clean for `snapshot --stdout`
+    let log_target = match &config.options.log_file {
+        Some(log_file) => {
+            env_logger::Target::Pipe(Box::new(log_file::open_log_file(config, log_file)?))
+        }
+        None => env_logger::Target::Stderr,
+    };
+
     env_logger::Builder::from_default_env()
         .format(|buf, record| {
             writeln!(
@@ -45,22 +594,49 @@ fn initialise_logger(config: &Config) {
             )
         })
         .filter_level(config.options.log_level)
+        .target(log_target)
         .init();
+    Ok(())
 }
 
 fn get_all_retention_targets(config: &Config) -> Vec<PirouetteRetentionTarget> {
+    get_retention_targets_in(config, &config.target.path)
+}
+
+// The same periods, under another target directory, eg: one of the `mirrors`
+fn get_retention_targets_in(config: &Config, target_path: &Path) -> Vec<PirouetteRetentionTarget> {
     let mut all_targets: Vec<PirouetteRetentionTarget> = vec![];
+    let now = chrono::Local::now();
 
-    for (retention_period, retention_value) in config.retention.iter() {
+    for (retention_period, retention) in config.retention.iter() {
         all_targets.push(PirouetteRetentionTarget {
             period: retention_period.clone(),
-            path: [
-                config.target.path.display().to_string(),
-                retention_period.to_string(),
-            ]
-            .iter()
-            .collect(),
-            max_count: *retention_value,
+            path: layout::render_layout(
+                target_path,
+                &config.options.layout,
+                retention_period,
+                &now,
+            ),
+            period_path: layout::render_period_path(
+                target_path,
+                &config.options.layout,
+                retention_period,
+                &now,
+            ),
+            snapshot_pattern: layout::get_layout_pattern(
+                target_path,
+                &config.options.layout,
+                retention_period,
+            ),
+            limit: retention.limit.clone(),
+            output_format: retention
+                .output_format
+                .clone()
+                .unwrap_or(config.options.output_format.clone()),
+            dry_run: config.options.dry_run || retention.dry_run,
+            exclude_modified_within: retention
+                .exclude_modified_within
+                .or(config.options.exclude_modified_within),
         });
     }
 
@@ -107,6 +683,15 @@ impl From<walkdir::DirEntry> for PirouetteDirEntry {
     }
 }
 
+impl From<PathBuf> for PirouetteDirEntry {
+    fn from(path: PathBuf) -> Self {
+        PirouetteDirEntry {
+            timestamp: parse_dir_entry_time(fs::symlink_metadata(&path)),
+            path,
+        }
+    }
+}
+
 fn parse_dir_entry_time<E>(entry_metadata: Result<fs::Metadata, E>) -> SystemTime
 where
     // either a std::io::Error or a walkdir::Error
@@ -133,11 +718,21 @@ impl fmt::Display for PirouetteDirEntry {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, serde::Serialize)]
 pub struct PirouetteRetentionTarget {
     pub period: ConfigRetentionPeriod,
+    // Where new snapshots go, and a glob matching all of this period's snapshots
     pub path: PathBuf,
-    pub max_count: usize,
+    // The period's own directory, which holds its markers, eg: while it's paused
+    #[serde(skip)]
+    pub period_path: PathBuf,
+    pub snapshot_pattern: String,
+    pub limit: ConfigRetentionLimit,
+    pub output_format: ConfigOptsOutputFormat,
+    // The global `dry_run`, or this period's own
+    pub dry_run: bool,
+    // This period's own `exclude_modified_within`, or else the global one
+    pub exclude_modified_within: Option<u64>,
 }
 
 impl fmt::Display for PirouetteRetentionTarget {
@@ -146,12 +741,66 @@ impl fmt::Display for PirouetteRetentionTarget {
     }
 }
 
-// This is just to pretty-print Vec<PirouetteRetentionTarget>
+// Tallies of what happened to source entries while creating a snapshot.
+// Cells let the counts be updated from inside the source iterator's closures.
+#[derive(Debug, Default)]
+pub struct PirouetteStats {
+    pub copied: Cell<usize>,
+    pub included: Cell<usize>,
+    pub filtered: Cell<usize>,
+    pub unreadable: Cell<usize>,
+    pub special_skipped: Cell<usize>,
+    pub empty_skipped: Cell<usize>,
+}
+
+impl PirouetteStats {
+    pub fn count_copied(&self) {
+        self.copied.set(self.copied.get() + 1);
+    }
+
+    pub fn count_included(&self) {
+        self.included.set(self.included.get() + 1);
+    }
+
+    pub fn count_filtered(&self) {
+        self.filtered.set(self.filtered.get() + 1);
+    }
+
+    pub fn count_unreadable(&self) {
+        self.unreadable.set(self.unreadable.get() + 1);
+    }
+
+    pub fn count_special_skipped(&self) {
+        self.special_skipped
+            .set(self.special_skipped.get() + 1);
+    }
+
+    pub fn count_empty_skipped(&self) {
+        self.empty_skipped
+            .set(self.empty_skipped.get() + 1);
+    }
+}
+
+impl fmt::Display for PirouetteStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} entries copied, {} filtered out, {} unreadable, {} special files skipped, {} empty files skipped",
+            self.copied.get(),
+            self.filtered.get(),
+            self.unreadable.get(),
+            self.special_skipped.get(),
+            self.empty_skipped.get()
+        )
+    }
+}
+
+// This is just to pretty-print Vec<PirouetteRetentionTarget> or &[PirouetteRetentionTarget]
 pub trait DisplayVec {
     fn display_vec(&self) -> String;
 }
 
-impl<T: std::fmt::Display> DisplayVec for Vec<T> {
+impl<T: std::fmt::Display> DisplayVec for [T] {
     fn display_vec(&self) -> String {
         format!(
             "[{}]",
@@ -162,3 +811,174 @@ impl<T: std::fmt::Display> DisplayVec for Vec<T> {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_retention_targets_are_ordered() {
+        let config: Config = toml::from_str(
+            r#"
+            source.path = "/source"
+            target.path = "/target"
+            retention = { years = 1, hours = 1, weeks = 1, days = 1, months = 1, minutes = 1 }
+            "#,
+        )
+        .unwrap();
+
+        let periods: Vec<ConfigRetentionPeriod> = get_all_retention_targets(&config)
+            .into_iter()
+            .map(|target| target.period)
+            .collect();
+
+        assert_eq!(
+            periods,
+            vec![
+                ConfigRetentionPeriod::Minutes,
+                ConfigRetentionPeriod::Hours,
+                ConfigRetentionPeriod::Days,
+                ConfigRetentionPeriod::Weeks,
+                ConfigRetentionPeriod::Months,
+                ConfigRetentionPeriod::Years,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_cli_options() -> Result<()> {
+        let parse_config = || -> Result<Config> {
+            Ok(toml::from_str(
+                "source.path = \"/source\"\ntarget.path = \"/target\"\nretention.days = 1\n\
+                [options]\nentry_log = \"-\"",
+            )?)
+        };
+        let snapshot_cli =
+            cli::Cli::try_parse_from(["pirouette", "--dry-run", "snapshot", "days"])?;
+        let stdout_cli = cli::Cli::try_parse_from(["pirouette", "snapshot", "--stdout", "days"])?;
+
+        let mut config = parse_config()?;
+        apply_cli_options(&snapshot_cli, &mut config)?;
+
+        assert!(config.options.dry_run);
+        // Both the tarball and its entries can't be written to stdout
+        assert!(apply_cli_options(&stdout_cli, &mut parse_config()?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_retention_targets_resolve_output_format() {
+        let config: Config = toml::from_str(
+            r#"
+            source.path = "/source"
+            target.path = "/target"
+            retention = { hours = 1, days = 1, years = { keep = 1, output_format = "tarball" } }
+            options.staging = true
+            "#,
+        )
+        .unwrap();
+
+        let all_targets = get_all_retention_targets(&config);
+        let formats: Vec<ConfigOptsOutputFormat> = all_targets
+            .iter()
+            .map(|target| target.output_format.clone())
+            .collect();
+        assert_eq!(
+            formats,
+            vec![
+                ConfigOptsOutputFormat::Directory,
+                ConfigOptsOutputFormat::Directory,
+                ConfigOptsOutputFormat::Tarball,
+            ]
+        );
+
+        // Only targets with the same format can share a staged snapshot
+        let group_sizes: Vec<usize> = group_rotation_targets(&config, all_targets)
+            .iter()
+            .map(|group| group.len())
+            .collect();
+        assert_eq!(group_sizes, vec![2, 1]);
+    }
+
+    #[test]
+    fn test_retention_targets_resolve_dry_run() {
+        let mut config: Config = toml::from_str(
+            r#"
+            source.path = "/source"
+            target.path = "/target"
+            retention = { hours = 1, days = 1, years = { keep = 1, dry_run = true } }
+            options.staging = true
+            "#,
+        )
+        .unwrap();
+
+        let all_targets = get_all_retention_targets(&config);
+        let dry_runs: Vec<bool> = all_targets
+            .iter()
+            .map(|target| target.dry_run)
+            .collect();
+        assert_eq!(dry_runs, vec![false, false, true]);
+
+        // A dry run period's snapshot is never shared with a real one
+        let group_sizes: Vec<usize> = group_rotation_targets(&config, all_targets)
+            .iter()
+            .map(|group| group.len())
+            .collect();
+        assert_eq!(group_sizes, vec![2, 1]);
+
+        // The global `dry_run`, eg: from `--dry-run`, covers every period
+        config.options.dry_run = true;
+        assert!(
+            get_all_retention_targets(&config)
+                .iter()
+                .all(|target| target.dry_run)
+        );
+    }
+
+    #[test]
+    fn test_retention_targets_json() {
+        let config: Config = toml::from_str(
+            r#"
+            source.path = "/source"
+            target.path = "/target"
+            retention = { days = 7, weeks = "1KB" }
+            "#,
+        )
+        .unwrap();
+
+        let targets_json = serde_json::to_value(get_all_retention_targets(&config)).unwrap();
+
+        assert_eq!(
+            targets_json,
+            serde_json::json!([
+                {
+                    "period": "days",
+                    "path": "/target/days",
+                    "snapshot_pattern": "/target/days/*",
+                    "limit": { "count": 7 },
+                    "output_format": "directory",
+                    "dry_run": false,
+                    "exclude_modified_within": null,
+                },
+                {
+                    "period": "weeks",
+                    "path": "/target/weeks",
+                    "snapshot_pattern": "/target/weeks/*",
+                    "limit": { "size": 1024 },
+                    "output_format": "directory",
+                    "dry_run": false,
+                    "exclude_modified_within": null,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_format_count() {
+        assert_eq!(format_count(0), "0");
+        assert_eq!(format_count(999), "999");
+        assert_eq!(format_count(4210), "4,210");
+        assert_eq!(format_count(1234567), "1,234,567");
+    }
+}
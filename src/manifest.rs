@@ -0,0 +1,250 @@
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::PirouetteRetentionTarget;
+use crate::layout;
+
+/*
+    A manifest is a gzipped sidecar next to a Directory snapshot, eg: "2024-01-01T00:00.manifest.gz".
+    Each line is in `sha256sum` format, "<hash>  <inner path>", so it can also be checked by hand.
+*/
+
+const MANIFEST_EXTENSION: &str = "manifest.gz";
+
+pub fn get_manifest_path(snapshot_path: &Path) -> PathBuf {
+    layout::get_sidecar_path(snapshot_path, MANIFEST_EXTENSION)
+}
+
+pub fn is_manifest_path(path: &Path) -> bool {
+    path.to_string_lossy()
+        .ends_with(&format!(".{MANIFEST_EXTENSION}"))
+}
+
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut f = fs::File::open(path).with_context(|| format!("failed to read file {path:?}"))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut f, &mut hasher).with_context(|| format!("failed to hash file {path:?}"))?;
+
+    Ok(format_hash(hasher))
+}
+
+// Copy a file and hash it in the same pass, so a large snapshot isn't read back a second time.
+// Permissions are carried over, just like `fs::copy`.
+pub fn copy_and_hash_file(source_path: &Path, target_path: &Path) -> Result<String> {
+    let source_file = fs::File::open(source_path)
+        .with_context(|| format!("failed to read file {source_path:?}"))?;
+    let permissions = source_file
+        .metadata()
+        .with_context(|| format!("failed to read metadata for {source_path:?}"))?
+        .permissions();
+    let mut target_file = fs::File::create(target_path)
+        .with_context(|| format!("failed to create file {target_path:?}"))?;
+
+    let mut hashing_reader = HashingReader {
+        inner: source_file,
+        hasher: Sha256::new(),
+    };
+    io::copy(&mut hashing_reader, &mut target_file)
+        .with_context(|| format!("failed to copy file {source_path:?}"))?;
+    fs::set_permissions(target_path, permissions)
+        .with_context(|| format!("failed to set permissions on {target_path:?}"))?;
+
+    Ok(format_hash(hashing_reader.hasher))
+}
+
+// Hashes every byte read through it
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+pub fn format_hash(hasher: Sha256) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+pub fn write_manifest(snapshot_path: &Path, hashes: &[(PathBuf, String)]) -> Result<()> {
+    let manifest_path = get_manifest_path(snapshot_path);
+    log::debug!(
+        "Writing manifest of {} files to {manifest_path:?}",
+        hashes.len()
+    );
+
+    let manifest_file = fs::File::create(&manifest_path)
+        .with_context(|| format!("failed to create manifest {manifest_path:?}"))?;
+    let mut manifest_writer = GzEncoder::new(manifest_file, flate2::Compression::best());
+
+    for (inner_path, hash) in hashes {
+        writeln!(manifest_writer, "{hash}  {}", inner_path.display())
+            .with_context(|| format!("failed to write manifest {manifest_path:?}"))?;
+    }
+
+    manifest_writer
+        .finish()
+        .with_context(|| format!("failed to close manifest {manifest_path:?}"))?;
+
+    Ok(())
+}
+
+fn read_manifest(manifest_path: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let manifest_file = fs::File::open(manifest_path)
+        .with_context(|| format!("failed to read manifest {manifest_path:?}"))?;
+
+    let mut hashes = vec![];
+    for line in BufReader::new(GzDecoder::new(manifest_file)).lines() {
+        let line = line.with_context(|| format!("failed to read manifest {manifest_path:?}"))?;
+        let (hash, inner_path) = line
+            .split_once("  ")
+            .with_context(|| format!("malformed line in manifest {manifest_path:?}: {line:?}"))?;
+        hashes.push((PathBuf::from(inner_path), hash.to_string()));
+    }
+
+    Ok(hashes)
+}
+
+/*
+    Verification
+*/
+
+// Re-hash every file in a Directory snapshot, and return a description of each mismatch
+pub fn verify_snapshot(snapshot_path: &Path) -> Result<Vec<String>> {
+    let hashes = read_manifest(&get_manifest_path(snapshot_path))?;
+    let mut mismatches = vec![];
+
+    for (inner_path, expected_hash) in hashes {
+        let entry_path = snapshot_path.join(&inner_path);
+        match hash_file(&entry_path) {
+            Ok(actual_hash) if actual_hash == expected_hash => {}
+            Ok(_) => mismatches.push(format!("{inner_path:?} has changed")),
+            Err(e) => mismatches.push(format!("{inner_path:?} could not be read: {e:#}")),
+        }
+    }
+
+    Ok(mismatches)
+}
+
+// Verify every snapshot with a manifest, or just the one given
+pub fn verify_snapshots(
+    retention_targets: &[PirouetteRetentionTarget],
+    snapshot_path: Option<&Path>,
+) -> Result<()> {
+    let snapshot_paths: Vec<PathBuf> = match snapshot_path {
+        Some(snapshot_path) => vec![snapshot_path.to_path_buf()],
+        None => retention_targets
+            .iter()
+            .flat_map(layout::get_snapshot_entries)
+            .map(|entry| entry.path)
+            .filter(|path| path.is_dir() && get_manifest_path(path).exists())
+            .collect(),
+    };
+
+    let mut failed_count = 0;
+    for snapshot_path in &snapshot_paths {
+        let mismatches = verify_snapshot(snapshot_path)
+            .with_context(|| format!("failed to verify snapshot {snapshot_path:?}"))?;
+
+        if mismatches.is_empty() {
+            println!("OK      {}", snapshot_path.display());
+        } else {
+            println!("FAILED  {}", snapshot_path.display());
+            for mismatch in mismatches {
+                println!("        {mismatch}");
+            }
+            failed_count += 1;
+        }
+    }
+
+    if failed_count > 0 {
+        anyhow::bail!(
+            "{failed_count} of {} snapshots failed verification",
+            snapshot_paths.len()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_verify_detects_changes() -> Result<()> {
+        let snapshot_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(snapshot_path.join("foo"))?;
+        fs::write(snapshot_path.join("foo/bar.txt"), "bar")?;
+        fs::write(snapshot_path.join("baz.txt"), "baz")?;
+
+        let hashes = vec![
+            (
+                PathBuf::from("foo/bar.txt"),
+                hash_file(&snapshot_path.join("foo/bar.txt"))?,
+            ),
+            (
+                PathBuf::from("baz.txt"),
+                hash_file(&snapshot_path.join("baz.txt"))?,
+            ),
+        ];
+        write_manifest(&snapshot_path, &hashes)?;
+        let clean_result = verify_snapshot(&snapshot_path)?;
+
+        // Simulate bitrot in one file, and loss of another
+        fs::write(snapshot_path.join("foo/bar.txt"), "bad")?;
+        fs::remove_file(snapshot_path.join("baz.txt"))?;
+        let dirty_result = verify_snapshot(&snapshot_path)?;
+
+        fs::remove_dir_all(&snapshot_path)?;
+        fs::remove_file(get_manifest_path(&snapshot_path))?;
+
+        assert!(clean_result.is_empty());
+        assert_eq!(dirty_result.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_file() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::write(&test_path, "foo")?;
+        let actual_hash = hash_file(&test_path);
+        fs::remove_file(&test_path)?;
+
+        assert_eq!(
+            actual_hash?,
+            "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_and_hash_file() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&test_path)?;
+        fs::write(test_path.join("source"), "foo")?;
+
+        let copied_hash = copy_and_hash_file(&test_path.join("source"), &test_path.join("copy"));
+        let copied_contents = fs::read_to_string(test_path.join("copy"));
+        let copied_file_hash = hash_file(&test_path.join("copy"));
+        fs::remove_dir_all(&test_path)?;
+
+        assert_eq!(copied_contents?, "foo");
+        assert_eq!(copied_hash?, copied_file_hash?);
+        Ok(())
+    }
+}
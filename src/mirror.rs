@@ -0,0 +1,181 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+use walkdir::WalkDir;
+
+use crate::DisplayVec;
+use crate::PirouetteRetentionTarget;
+use crate::clean;
+use crate::configuration::Config;
+use crate::current_state;
+use crate::dry_run;
+use crate::promote;
+use crate::snapshot;
+use crate::volume;
+
+/*
+    Copies of each new snapshot in `target.mirrors`, eg: a NAS alongside a local disk
+*/
+
+// Each mirror is then cleaned up independently, with the same limits as the `target`.
+// The snapshot is already safe in the `target`, so a failing mirror only warns.
+pub fn mirror_snapshot_group(config: &Config, snapshot_group: &[PirouetteRetentionTarget]) {
+    for mirror_path in &config.target.mirrors {
+        if let Err(e) = mirror_snapshots(config, snapshot_group, mirror_path) {
+            log::warn!(
+                "Failed to mirror {} to {mirror_path:?}: {e:?}",
+                snapshot_group.display_vec()
+            );
+        }
+    }
+}
+
+fn mirror_snapshots(
+    config: &Config,
+    snapshot_group: &[PirouetteRetentionTarget],
+    mirror_path: &Path,
+) -> Result<()> {
+    let mirror_targets = crate::get_retention_targets_in(config, mirror_path);
+
+    for retention_target in snapshot_group {
+        let mirror_target = mirror_targets
+            .iter()
+            .find(|mirror_target| mirror_target.period == retention_target.period)
+            .with_context(|| format!("no {retention_target} period in mirror {mirror_path:?}"))?;
+
+        // The snapshot just taken, or the one kept because it was already taken
+        let Some((snapshot, _)) =
+            current_state::get_newest_directory_entry(config, retention_target)
+        else {
+            continue;
+        };
+        // A split tarball is found by its first volume, but its parts are named from the base path
+        let snapshot_path = volume::get_base_path(&snapshot.path);
+        let mirror_snapshot_path = mirror_path.join(
+            snapshot_path
+                .strip_prefix(&config.target.path)
+                .with_context(|| format!("{snapshot_path:?} is outside the target"))?,
+        );
+
+        dry_run!(
+            mirror_target.dry_run,
+            format!("snapshot will not be mirrored to {mirror_snapshot_path:?}"),
+            { copy_to_mirror(&snapshot_path, &mirror_snapshot_path) }
+        )?;
+
+        let promotion_target =
+            promote::get_promotion_target(config, &mirror_targets, mirror_target);
+        clean::clean_snapshots(config, mirror_target, promotion_target)?;
+    }
+
+    Ok(())
+}
+
+fn copy_to_mirror(snapshot_path: &Path, mirror_snapshot_path: &Path) -> Result<()> {
+    if !volume::get_snapshot_parts(mirror_snapshot_path).is_empty() {
+        log::debug!("{mirror_snapshot_path:?} is already mirrored");
+        return Ok(());
+    }
+    log::info!("Mirroring {snapshot_path:?} to {mirror_snapshot_path:?}");
+
+    if let Some(parent) = mirror_snapshot_path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create directory {parent:?}"))?;
+    }
+
+    let copy_result =
+        snapshot::place_snapshot_parts(snapshot_path, mirror_snapshot_path, copy_snapshot_part);
+
+    // Otherwise, the next run would take a partial copy for a finished one
+    if copy_result.is_err()
+        && let Err(e) = snapshot::remove_snapshot_parts(mirror_snapshot_path)
+    {
+        log::warn!("Failed to remove partial mirror {mirror_snapshot_path:?}: {e}");
+    }
+
+    copy_result
+}
+
+// Always copies, as a hardlink would share storage with the `target`, defeating the mirror.
+// Modification times are kept, since the mirror's cleanup judges ages by them.
+fn copy_snapshot_part(source_path: &Path, target_path: &Path) -> Result<()> {
+    for entry in WalkDir::new(source_path) {
+        let entry = entry.with_context(|| format!("failed to read {source_path:?}"))?;
+        // A tarball is a single file, and joining its empty inner path would add a trailing "/"
+        let entry_target_path = match entry.path().strip_prefix(source_path) {
+            Ok(inner_path) if !inner_path.as_os_str().is_empty() => target_path.join(inner_path),
+            _ => target_path.to_path_buf(),
+        };
+
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&entry_target_path)
+                .with_context(|| format!("failed to create directory {entry_target_path:?}"))?;
+        } else if entry.file_type().is_symlink() {
+            let link_target = fs::read_link(entry.path())
+                .with_context(|| format!("failed to read symlink {:?}", entry.path()))?;
+            std::os::unix::fs::symlink(&link_target, &entry_target_path)
+                .with_context(|| format!("failed to create symlink {entry_target_path:?}"))?;
+        } else {
+            fs::copy(entry.path(), &entry_target_path)
+                .with_context(|| format!("failed to copy file {:?}", entry.path()))?;
+            snapshot::copy_modified_time(entry.path(), &entry_target_path)?;
+        }
+    }
+
+    // Copying its contents changed the directory's own time, so it's set last
+    snapshot::copy_modified_time(source_path, target_path)
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_mirror_snapshot_group() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let target_path = test_path.join("target");
+        let mirror_path = test_path.join("mirror");
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\n\
+            target.mirrors = [{mirror_path:?}]\nretention.hours = 2"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+
+        // Three snapshots an hour apart, of which the mirror only has the oldest two
+        let start_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        for hour in 0..3 {
+            let timestamp = start_time + Duration::from_secs(hour * 60 * 60);
+            let mut snapshot_paths = vec![target_path.join(format!("hours/snapshot_{hour}"))];
+            if hour < 2 {
+                snapshot_paths.push(mirror_path.join(format!("hours/snapshot_{hour}")));
+            }
+            for snapshot_path in snapshot_paths {
+                fs::create_dir_all(&snapshot_path)?;
+                fs::write(snapshot_path.join("foo"), "foo")?;
+                fs::File::open(&snapshot_path)?.set_modified(timestamp)?;
+            }
+        }
+
+        mirror_snapshot_group(&config, &all_targets);
+        let mut mirrored_names: Vec<String> = fs::read_dir(mirror_path.join("hours"))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect();
+        mirrored_names.sort();
+        let mirrored_time = fs::metadata(mirror_path.join("hours/snapshot_2"))?.modified()?;
+        let mirrored_contents = fs::read_to_string(mirror_path.join("hours/snapshot_2/foo"))?;
+
+        fs::remove_dir_all(&test_path)?;
+        // The primary wasn't cleaned here, but the mirror was, down to its own limit
+        assert_eq!(mirrored_names, vec!["snapshot_1", "snapshot_2"]);
+        assert_eq!(mirrored_time, start_time + Duration::from_secs(2 * 60 * 60));
+        assert_eq!(mirrored_contents, "foo");
+        Ok(())
+    }
+}
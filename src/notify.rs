@@ -0,0 +1,209 @@
+use anyhow::Result;
+
+use crate::configuration::Config;
+use crate::configuration::ConfigOptsNotify;
+use crate::dry_run;
+
+/*
+    A notification once a rotation run finishes, eg: for a laptop with nobody watching its logs.
+    Each backend is a cargo feature of its own, "notify-desktop" and "notify-email", and a
+    config which asks for one that isn't built in is refused when it's parsed.
+*/
+
+// A notification which can't be sent only warns, since the run itself is already over
+pub fn notify_run_result(config: &Config, run_result: &Result<()>) {
+    if config.options.notify == ConfigOptsNotify::None {
+        return;
+    }
+
+    let (summary, body) = format_notification(run_result);
+    let notify_result = dry_run!(
+        config.options.dry_run,
+        format!(
+            "{:?} notification will not be sent: {summary}",
+            config.options.notify
+        ),
+        {
+            match config.options.notify {
+                ConfigOptsNotify::None => Ok(()),
+                ConfigOptsNotify::Desktop => {
+                    desktop::send_notification(&summary, &body, run_result.is_err())
+                }
+                ConfigOptsNotify::Email => email::send_email(config, &summary, &body),
+            }
+        }
+    );
+
+    if let Err(e) = notify_result {
+        log::warn!("Failed to send a notification: {e:#}");
+    }
+}
+
+pub fn is_desktop_supported() -> bool {
+    cfg!(feature = "notify-desktop")
+}
+
+pub fn is_email_supported() -> bool {
+    cfg!(feature = "notify-email")
+}
+
+fn format_notification(run_result: &Result<()>) -> (String, String) {
+    match run_result {
+        Ok(()) => (
+            "pirouette backup succeeded".to_string(),
+            "Every period was rotated.".to_string(),
+        ),
+        Err(e) => ("pirouette backup failed".to_string(), format!("{e:#}")),
+    }
+}
+
+#[cfg(feature = "notify-desktop")]
+mod desktop {
+    use super::*;
+    use anyhow::Context;
+    use notify_rust::Notification;
+
+    #[cfg_attr(target_os = "macos", allow(unused_variables))]
+    pub fn send_notification(summary: &str, body: &str, is_failure: bool) -> Result<()> {
+        let mut notification = Notification::new();
+        notification
+            .appname("pirouette")
+            .summary(summary)
+            .body(body);
+        // Only freedesktop notification servers have an urgency
+        #[cfg(all(unix, not(target_os = "macos")))]
+        notification.urgency(match is_failure {
+            true => notify_rust::Urgency::Critical,
+            false => notify_rust::Urgency::Normal,
+        });
+
+        notification
+            .show()
+            .context("failed to show a desktop notification")?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "notify-email")]
+mod email {
+    use super::*;
+    use anyhow::Context;
+    use lettre::transport::smtp::authentication::Credentials;
+    use lettre::{Message, SmtpTransport, Transport};
+
+    pub fn send_email(config: &Config, summary: &str, body: &str) -> Result<()> {
+        let Some(smtp_host) = &config.options.notify_smtp_host else {
+            anyhow::bail!("notify = \"email\" needs a notify_smtp_host to send through");
+        };
+        let message = build_email(config, summary, body)?;
+
+        let mut transport = SmtpTransport::starttls_relay(smtp_host)
+            .with_context(|| format!("failed to connect to notify_smtp_host {smtp_host:?}"))?
+            .port(config.options.notify_smtp_port);
+        if let (Some(username), Some(password)) = (
+            &config.options.notify_smtp_username,
+            &config.options.notify_smtp_password,
+        ) {
+            transport = transport.credentials(Credentials::new(
+                username.clone(),
+                password.expose().to_string(),
+            ));
+        }
+
+        transport
+            .build()
+            .send(&message)
+            .with_context(|| format!("failed to send the email through {smtp_host:?}"))?;
+        Ok(())
+    }
+
+    // Sent from `notify_email_from`, or to itself without one
+    pub fn build_email(config: &Config, summary: &str, body: &str) -> Result<Message> {
+        let Some(notify_email) = &config.options.notify_email else {
+            anyhow::bail!("notify = \"email\" needs a notify_email address");
+        };
+        let notify_email_from = config
+            .options
+            .notify_email_from
+            .as_ref()
+            .unwrap_or(notify_email);
+
+        Message::builder()
+            .from(
+                notify_email_from
+                    .parse()
+                    .with_context(|| format!("invalid notify_email_from {notify_email_from:?}"))?,
+            )
+            .to(notify_email
+                .parse()
+                .with_context(|| format!("invalid notify_email {notify_email:?}"))?)
+            .subject(summary)
+            .body(body.to_string())
+            .context("failed to build the email")
+    }
+}
+
+// A config can't ask for a backend which isn't built in, so these never run
+#[cfg(not(feature = "notify-desktop"))]
+mod desktop {
+    use super::*;
+
+    pub fn send_notification(_summary: &str, _body: &str, _is_failure: bool) -> Result<()> {
+        anyhow::bail!(
+            "this build can't send desktop notifications, it needs the \"notify-desktop\" feature"
+        )
+    }
+}
+
+#[cfg(not(feature = "notify-email"))]
+mod email {
+    use super::*;
+
+    pub fn send_email(_config: &Config, _summary: &str, _body: &str) -> Result<()> {
+        anyhow::bail!("this build can't send email, it needs the \"notify-email\" feature")
+    }
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_notification() {
+        let (summary, body) = format_notification(&Err(
+            anyhow::anyhow!("target is full").context("failed to create snapshot")
+        ));
+
+        assert_eq!(summary, "pirouette backup failed");
+        assert_eq!(body, "failed to create snapshot: target is full");
+    }
+
+    #[cfg(feature = "notify-email")]
+    #[test]
+    fn test_build_email() -> Result<()> {
+        let config: Config = toml::from_str(
+            "source.path = \"/source\"\ntarget.path = \"/target\"\nretention.hours = 1\n\
+            [options]\nnotify = \"email\"\nnotify_email = \"me@example.com\"\n\
+            notify_email_from = \"pirouette@example.com\"\nnotify_smtp_host = \"smtp.example.com\"",
+        )?;
+        let invalid_config: Config = toml::from_str(
+            "source.path = \"/source\"\ntarget.path = \"/target\"\nretention.hours = 1\n\
+            [options]\nnotify = \"email\"\nnotify_email = \"not an address\"",
+        )?;
+
+        let email = String::from_utf8(
+            email::build_email(&config, "pirouette backup failed", "target is full")?.formatted(),
+        )?;
+
+        assert!(email.contains("From: pirouette@example.com\r\n"));
+        assert!(email.contains("To: me@example.com\r\n"));
+        assert!(email.contains("Subject: pirouette backup failed\r\n"));
+        assert!(email.ends_with("target is full"));
+        assert!(email::build_email(&invalid_config, "", "").is_err());
+        Ok(())
+    }
+}
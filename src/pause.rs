@@ -0,0 +1,182 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::PirouetteRetentionTarget;
+use crate::configuration::ConfigRetentionPeriod;
+use crate::dry_run;
+
+/*
+    A paused period takes no new snapshots, and so cleans up none either, until a set time,
+    eg: during a migration. The time is kept in a marker in the period's own directory, eg:
+    "<target>/hours/.pirouette-paused" even with a dated `layout`, so it stays put as the date
+    moves on. The marker is ignored once its time has passed.
+*/
+
+const PAUSE_MARKER: &str = ".pirouette-paused";
+
+pub fn is_pause_marker_path(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|file_name| file_name == PAUSE_MARKER)
+}
+
+fn get_pause_marker_path(retention_target: &PirouetteRetentionTarget) -> PathBuf {
+    retention_target.period_path.join(PAUSE_MARKER)
+}
+
+// When the period's pause ends, if it's still paused
+pub fn get_paused_until(retention_target: &PirouetteRetentionTarget) -> Option<SystemTime> {
+    let marker_path = get_pause_marker_path(retention_target);
+    let marker = fs::read_to_string(&marker_path).ok()?;
+
+    match chrono::DateTime::parse_from_rfc3339(marker.trim_end()) {
+        Ok(until) => Some(SystemTime::from(until)).filter(|until| *until > SystemTime::now()),
+        Err(e) => {
+            log::warn!("Ignoring unreadable pause marker {marker_path:?}: {e}");
+            None
+        }
+    }
+}
+
+fn find_target<'a>(
+    all_targets: &'a [PirouetteRetentionTarget],
+    period: &ConfigRetentionPeriod,
+) -> Result<&'a PirouetteRetentionTarget> {
+    all_targets
+        .iter()
+        .find(|retention_target| retention_target.period == *period)
+        .with_context(|| format!("{period} is not a configured retention period"))
+}
+
+pub fn pause_period(
+    all_targets: &[PirouetteRetentionTarget],
+    period: &ConfigRetentionPeriod,
+    until: SystemTime,
+) -> Result<()> {
+    if until <= SystemTime::now() {
+        anyhow::bail!("a pause must end in the future");
+    }
+
+    let retention_target = find_target(all_targets, period)?;
+    let until: chrono::DateTime<chrono::Local> = until.into();
+    log::info!("Pausing {retention_target} until {until}");
+
+    let marker_path = get_pause_marker_path(retention_target);
+    dry_run!(
+        retention_target.dry_run,
+        format!("{retention_target} will not be paused"),
+        {
+            fs::create_dir_all(&retention_target.period_path).with_context(|| {
+                format!(
+                    "failed to create directory {:?}",
+                    retention_target.period_path
+                )
+            })?;
+            fs::write(&marker_path, format!("{}\n", until.to_rfc3339()))
+                .with_context(|| format!("failed to write pause marker {marker_path:?}"))
+        }
+    )
+}
+
+pub fn resume_period(
+    all_targets: &[PirouetteRetentionTarget],
+    period: &ConfigRetentionPeriod,
+) -> Result<()> {
+    let retention_target = find_target(all_targets, period)?;
+    let marker_path = get_pause_marker_path(retention_target);
+    if !marker_path.exists() {
+        log::info!("{retention_target} isn't paused");
+        return Ok(());
+    }
+
+    log::info!("Resuming {retention_target}");
+    dry_run!(
+        retention_target.dry_run,
+        format!("{retention_target} will not be resumed"),
+        {
+            fs::remove_file(&marker_path)
+                .with_context(|| format!("failed to remove pause marker {marker_path:?}"))
+        }
+    )
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::Config;
+    use std::env;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pause_and_resume_period() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\n\
+            retention.hours = 1\nretention.days = 1"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+        let hours = ConfigRetentionPeriod::Hours;
+
+        let past_result = pause_period(&all_targets, &hours, SystemTime::now());
+        pause_period(
+            &all_targets,
+            &hours,
+            SystemTime::now() + Duration::from_secs(60),
+        )?;
+        let is_hours_paused = get_paused_until(&all_targets[0]).is_some();
+        let is_days_paused = get_paused_until(&all_targets[1]).is_some();
+        resume_period(&all_targets, &hours)?;
+        let is_hours_resumed = get_paused_until(&all_targets[0]).is_none();
+
+        // An expired pause is ignored, without needing to be resumed
+        fs::write(
+            get_pause_marker_path(&all_targets[0]),
+            "2000-01-01T00:00:00+00:00\n",
+        )?;
+        let is_expired = get_paused_until(&all_targets[0]).is_none();
+
+        fs::remove_dir_all(&target_path)?;
+        assert!(past_result.is_err());
+        assert!(is_hours_paused);
+        assert!(!is_days_paused);
+        assert!(is_hours_resumed);
+        assert!(is_expired);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pause_outlasts_dated_layout() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\nretention.hours = 1\n\
+            [options]\nlayout = \"{{period}}/%Y/%m\""
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+        let hours = ConfigRetentionPeriod::Hours;
+
+        pause_period(
+            &all_targets,
+            &hours,
+            SystemTime::now() + Duration::from_secs(60),
+        )?;
+        let marker_path = get_pause_marker_path(&all_targets[0]);
+
+        // Once the month rolls over, new snapshots go in another dated directory
+        let mut next_month_targets = all_targets.clone();
+        next_month_targets[0].path = target_path.join("hours/2999/12");
+        let is_still_paused = get_paused_until(&next_month_targets[0]).is_some();
+        resume_period(&next_month_targets, &hours)?;
+        let is_resumed = !marker_path.exists();
+
+        fs::remove_dir_all(&target_path)?;
+        assert_eq!(marker_path, target_path.join("hours/.pirouette-paused"));
+        assert!(is_still_paused);
+        assert!(is_resumed);
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::PirouetteDirEntry;
+use crate::PirouetteRetentionTarget;
+use crate::audit;
+use crate::clean;
+use crate::configuration::Config;
+use crate::configuration::ConfigOptsBoundary;
+use crate::configuration::ConfigRetentionPeriod;
+use crate::current_state;
+use crate::dry_run;
+use crate::index;
+use crate::layout;
+use crate::snapshot;
+use crate::volume;
+
+/*
+    Grandfather-father-son retention: with `promote`, a snapshot expiring from one period is
+    moved into the next coarser period instead of deleted, if that period is due a new one,
+    eg: the oldest daily snapshot becomes a weekly one once the newest weekly is a week older.
+*/
+
+// The next coarser period, which expired snapshots are promoted into
+pub fn get_promotion_target<'a>(
+    config: &Config,
+    all_targets: &'a [PirouetteRetentionTarget],
+    retention_target: &PirouetteRetentionTarget,
+) -> Option<&'a PirouetteRetentionTarget> {
+    if !config.options.promote {
+        return None;
+    }
+
+    // Retention targets are ordered from finest to coarsest
+    let promotion_target = all_targets
+        .iter()
+        .find(|target| target.period > retention_target.period)?;
+
+    if promotion_target.output_format != retention_target.output_format {
+        log::warn!(
+            "Not promoting {retention_target} snapshots into {promotion_target}, which has a different output_format"
+        );
+        return None;
+    }
+
+    Some(promotion_target)
+}
+
+// Returns the expired snapshots which weren't promoted, and so still need deleting
+pub fn promote_snapshots(
+    config: &Config,
+    retention_target: &PirouetteRetentionTarget,
+    promotion_target: &PirouetteRetentionTarget,
+    mut expired_snapshots: Vec<PirouetteDirEntry>,
+) -> Result<Vec<PirouetteDirEntry>> {
+    let mut newest_promoted_time = layout::get_snapshot_entries(promotion_target)
+        .into_iter()
+        .map(|entry| entry.timestamp)
+        .max();
+
+    // Oldest first, so each promotion is spaced a whole coarser period after the last
+    expired_snapshots.sort_by_key(|snapshot| snapshot.timestamp);
+    let mut remaining_snapshots = vec![];
+
+    for snapshot in expired_snapshots {
+        if !is_promotion_due(config, promotion_target, &snapshot, newest_promoted_time) {
+            remaining_snapshots.push(snapshot);
+            continue;
+        }
+
+        // A split tarball is promoted by its base path, so its volumes keep their numbering
+        let Some(snapshot_name) = volume::get_base_path(&snapshot.path)
+            .file_name()
+            .map(|snapshot_name| snapshot_name.to_owned())
+        else {
+            remaining_snapshots.push(snapshot);
+            continue;
+        };
+        let promoted_path = promotion_target.path.join(snapshot_name);
+        if !volume::get_snapshot_parts(&promoted_path).is_empty() {
+            log::warn!("Not promoting {snapshot}, because {promoted_path:?} already exists");
+            remaining_snapshots.push(snapshot);
+            continue;
+        }
+
+        log::info!("Promoting {snapshot} into {promotion_target} at {promoted_path:?}");
+        dry_run!(
+            retention_target.dry_run || promotion_target.dry_run,
+            format!("snapshot will not be promoted"),
+            { promote_snapshot(config, &snapshot, &promotion_target.period, &promoted_path) }
+        )?;
+        newest_promoted_time = Some(snapshot.timestamp);
+    }
+
+    Ok(remaining_snapshots)
+}
+
+// Whether a snapshot is a whole coarser period newer than the newest already there
+fn is_promotion_due(
+    config: &Config,
+    promotion_target: &PirouetteRetentionTarget,
+    snapshot: &PirouetteDirEntry,
+    newest_promoted_time: Option<SystemTime>,
+) -> bool {
+    let Some(newest_promoted_time) = newest_promoted_time else {
+        return true;
+    };
+
+    match config.options.boundary {
+        ConfigOptsBoundary::Rolling => {
+            let age_threshold = current_state::get_age_threshold(
+                &promotion_target.period,
+                config.options.age_grace,
+            );
+            snapshot
+                .timestamp
+                .duration_since(newest_promoted_time)
+                .is_ok_and(|age| {
+                    current_state::is_past_age_threshold(
+                        &config.options.age_comparison,
+                        age,
+                        age_threshold,
+                    )
+                })
+        }
+        ConfigOptsBoundary::Calendar => {
+            snapshot.timestamp > newest_promoted_time
+                && current_state::get_calendar_bucket(
+                    &promotion_target.period,
+                    &snapshot.timestamp.into(),
+                ) != current_state::get_calendar_bucket(
+                    &promotion_target.period,
+                    &newest_promoted_time.into(),
+                )
+        }
+    }
+}
+
+fn promote_snapshot(
+    config: &Config,
+    snapshot: &PirouetteDirEntry,
+    period: &ConfigRetentionPeriod,
+    promoted_path: &Path,
+) -> Result<()> {
+    if let Some(parent) = promoted_path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create directory {parent:?}"))?;
+    }
+    snapshot::place_snapshot_parts(
+        &volume::get_base_path(&snapshot.path),
+        promoted_path,
+        snapshot::move_snapshot,
+    )?;
+    audit::record_action(config, audit::AuditAction::Create, promoted_path)?;
+
+    // Anything left behind was copied across filesystems instead of moved
+    if snapshot.path.exists() {
+        clean::delete_snapshots(config, vec![snapshot.clone()]);
+    } else {
+        index::remove_snapshot(config, &snapshot.path);
+        audit::record_action(config, audit::AuditAction::Delete, &snapshot.path)?;
+    }
+
+    // Ages are judged by mtime, which a copy doesn't keep
+    for promoted_part in volume::get_snapshot_parts(promoted_path) {
+        fs::File::open(&promoted_part)
+            .and_then(|f| f.set_modified(snapshot.timestamp))
+            .with_context(|| format!("failed to set modification time of {promoted_part:?}"))?;
+    }
+    index::add_snapshot(config, period, promoted_path);
+    Ok(())
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+    use std::time::Duration;
+
+    #[test]
+    fn test_promote_snapshots() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\n\
+            retention.hours = 1\nretention.days = 7\noptions.promote = true"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+        let promotion_target = get_promotion_target(&config, &all_targets, &all_targets[0])
+            .context("expected days to be the promotion target")?;
+
+        // Snapshots at 0h, 1h, and 25h, of which only 0h and 25h are a day apart
+        let start_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let mut expired_snapshots = vec![];
+        for hour in [0, 1, 25] {
+            let snapshot_path = all_targets[0]
+                .path
+                .join(format!("snapshot_{hour}"));
+            fs::create_dir_all(&snapshot_path)?;
+            let timestamp = start_time + Duration::from_secs(hour * 60 * 60);
+            fs::File::open(&snapshot_path)?.set_modified(timestamp)?;
+            expired_snapshots.push(PirouetteDirEntry {
+                path: snapshot_path,
+                timestamp,
+            });
+        }
+
+        let remaining_snapshots = promote_snapshots(
+            &config,
+            &all_targets[0],
+            promotion_target,
+            expired_snapshots,
+        );
+        let mut promoted_names: Vec<String> = fs::read_dir(&promotion_target.path)?
+            .map(|entry| {
+                entry
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        promoted_names.sort();
+        fs::remove_dir_all(&target_path)?;
+
+        let remaining_names: Vec<_> = remaining_snapshots?
+            .iter()
+            .map(|snapshot| snapshot.path.file_name().unwrap().to_owned())
+            .collect();
+        assert_eq!(remaining_names, vec!["snapshot_1"]);
+        assert_eq!(promoted_names, vec!["snapshot_0", "snapshot_25"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_promote_split_snapshot() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\n\
+            retention.hours = 1\nretention.days = 7\n\
+            [options]\npromote = true\noutput_format = \"tarball\"\nsplit_size = 4"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+        let promotion_target = get_promotion_target(&config, &all_targets, &all_targets[0])
+            .context("expected days to be the promotion target")?;
+
+        // Listed by its first volume, as every split tarball is
+        let snapshot_path = all_targets[0]
+            .path
+            .join("2024-01-01T00:00:00.tgz");
+        fs::create_dir_all(&all_targets[0].path)?;
+        let mut split_writer =
+            volume::SplitWriter::new(&snapshot_path, config.options.split_size.unwrap());
+        split_writer.write_all(b"0123456789")?;
+        split_writer.flush()?;
+        let snapshot = PirouetteDirEntry {
+            path: volume::get_volume_path(&snapshot_path, 1),
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000),
+        };
+
+        let remaining_snapshots =
+            promote_snapshots(&config, &all_targets[0], promotion_target, vec![snapshot]);
+        let mut promoted_names: Vec<String> = fs::read_dir(&promotion_target.path)?
+            .map(|entry| {
+                entry
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        promoted_names.sort();
+        let unpromoted_count = fs::read_dir(&all_targets[0].path)?.count();
+        fs::remove_dir_all(&target_path)?;
+
+        assert!(remaining_snapshots?.is_empty());
+        assert_eq!(
+            promoted_names,
+            vec![
+                "2024-01-01T00:00:00.tgz.001",
+                "2024-01-01T00:00:00.tgz.002",
+                "2024-01-01T00:00:00.tgz.003",
+            ]
+        );
+        assert_eq!(unpromoted_count, 0);
+        Ok(())
+    }
+}
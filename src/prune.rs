@@ -0,0 +1,128 @@
+use anyhow::Result;
+use std::time::SystemTime;
+
+use crate::PirouetteDirEntry;
+use crate::PirouetteRetentionTarget;
+use crate::clean;
+use crate::configuration::Config;
+use crate::dry_run;
+use crate::layout;
+
+/*
+    A one-off cleanup of every period's snapshots from before a date, eg: "everything before 2023",
+    whatever each period's `keep` limit.
+*/
+
+pub fn prune_snapshots(
+    config: &Config,
+    all_targets: &[PirouetteRetentionTarget],
+    before: SystemTime,
+) -> Result<()> {
+    if config.options.append_only {
+        anyhow::bail!("pruning deletes snapshots, which append_only doesn't allow");
+    }
+
+    for retention_target in all_targets {
+        let pruned_snapshots = get_snapshots_before(retention_target, before);
+        log::info!(
+            "Pruning {} {retention_target} snapshots",
+            pruned_snapshots.len()
+        );
+        if pruned_snapshots.is_empty() {
+            continue;
+        }
+
+        dry_run!(
+            retention_target.dry_run,
+            format!("snapshots will not be pruned: {pruned_snapshots:?}"),
+            {
+                clean::delete_snapshots(config, pruned_snapshots);
+                // This function doesn't fail, but dry_run!() expects a Result<>
+                Ok::<(), anyhow::Error>(())
+            }
+        )?;
+    }
+
+    Ok(())
+}
+
+// Judged by the time in each snapshot's name, which a touched or copied snapshot still keeps.
+// Labelled snapshots are kept, just like in cleanup.
+fn get_snapshots_before(
+    retention_target: &PirouetteRetentionTarget,
+    before: SystemTime,
+) -> Vec<PirouetteDirEntry> {
+    clean::get_unlabelled_entries(layout::get_snapshot_entries(retention_target))
+        .into_iter()
+        .filter(|snapshot| {
+            let snapshot_time =
+                layout::parse_snapshot_name_time(&snapshot.path).unwrap_or_else(|| {
+                    log::debug!("{snapshot} has no time in its name, using its modification time");
+                    snapshot.timestamp
+                });
+            snapshot_time < before
+        })
+        .collect()
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_prune_snapshots() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\n\
+            retention.hours = 1\nretention.days = 1"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+
+        for snapshot_name in [
+            "hours/2022-12-31T23:00:00",
+            "hours/2023-01-01T00:00:00",
+            "days/2022-06-01T00:00:00.tgz",
+            "days/2022-07-01T00:00:00.tgz",
+        ] {
+            fs::create_dir_all(
+                target_path
+                    .join(snapshot_name)
+                    .parent()
+                    .context("no parent")?,
+            )?;
+            fs::write(target_path.join(snapshot_name), "")?;
+        }
+        crate::label::write_label(&target_path.join("days/2022-07-01T00:00:00.tgz"), "keep")?;
+
+        let before: SystemTime = chrono::NaiveDate::from_ymd_opt(2023, 1, 1)
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .and_then(|datetime| {
+                datetime
+                    .and_local_timezone(chrono::Local)
+                    .earliest()
+            })
+            .context("invalid date")?
+            .into();
+        prune_snapshots(&config, &all_targets, before)?;
+        let remaining_snapshots: Vec<bool> = [
+            "hours/2022-12-31T23:00:00",
+            "hours/2023-01-01T00:00:00",
+            "days/2022-06-01T00:00:00.tgz",
+            "days/2022-07-01T00:00:00.tgz",
+        ]
+        .iter()
+        .map(|snapshot_name| target_path.join(snapshot_name).exists())
+        .collect();
+
+        fs::remove_dir_all(&target_path)?;
+        assert_eq!(remaining_snapshots, vec![false, true, false, true]);
+        Ok(())
+    }
+}
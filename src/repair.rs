@@ -0,0 +1,191 @@
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::PirouetteDirEntry;
+use crate::PirouetteRetentionTarget;
+use crate::audit;
+use crate::configuration::Config;
+use crate::dry_run;
+use crate::index;
+use crate::layout;
+use crate::snapshot;
+use crate::volume;
+
+/*
+    Puts a target back in order after it's been changed by hand, eg: a snapshot deleted without
+    its sidecars, or renamed so its time can't be read from its name. Whatever can't be fixed
+    safely is left alone, and reported.
+*/
+
+pub fn repair_targets(config: &Config, all_targets: &[PirouetteRetentionTarget]) -> Result<()> {
+    let mut unrepaired_count = 0;
+
+    for retention_target in all_targets {
+        log::info!("Checking {retention_target} for anything to repair");
+        let snapshot_entries = layout::get_snapshot_entries(retention_target);
+
+        for sidecar_path in get_orphaned_sidecars(retention_target, &snapshot_entries) {
+            dry_run!(
+                retention_target.dry_run,
+                format!("orphaned sidecar will not be removed: {sidecar_path:?}"),
+                {
+                    log::info!("Removing orphaned sidecar {sidecar_path:?}");
+                    fs::remove_file(&sidecar_path)
+                        .with_context(|| format!("failed to remove {sidecar_path:?}"))
+                }
+            )?;
+        }
+
+        for snapshot in &snapshot_entries {
+            if layout::parse_snapshot_name_time(&snapshot.path).is_some() {
+                continue;
+            }
+
+            match get_renamed_snapshot_path(config, snapshot) {
+                Ok(renamed_path) => dry_run!(
+                    retention_target.dry_run,
+                    format!("{snapshot} will not be renamed to {renamed_path:?}"),
+                    {
+                        log::info!("Renaming {snapshot} to {renamed_path:?}");
+                        rename_snapshot(&snapshot.path, &renamed_path)?;
+                        index::remove_snapshot(config, &snapshot.path);
+                        index::add_snapshot(config, &retention_target.period, &renamed_path);
+                        audit::record_action(config, audit::AuditAction::Create, &renamed_path)?;
+                        audit::record_action(config, audit::AuditAction::Delete, &snapshot.path)
+                    }
+                )?,
+                Err(e) => {
+                    log::warn!("Can't repair {snapshot}: {e:#}");
+                    unrepaired_count += 1;
+                }
+            }
+        }
+    }
+
+    if unrepaired_count > 0 {
+        anyhow::bail!("{unrepaired_count} snapshots couldn't be repaired, and need fixing by hand");
+    }
+
+    Ok(())
+}
+
+// A sidecar whose snapshot is gone, eg: "<snapshot>.label" after "<snapshot>" was deleted
+fn get_orphaned_sidecars(
+    retention_target: &PirouetteRetentionTarget,
+    snapshot_entries: &[PirouetteDirEntry],
+) -> Vec<PathBuf> {
+    let sidecar_paths: HashSet<PathBuf> = snapshot_entries
+        .iter()
+        .flat_map(|snapshot| layout::get_sidecar_paths(&snapshot.path))
+        .collect();
+
+    let entry_paths = match glob::glob(&retention_target.snapshot_pattern) {
+        Ok(entry_paths) => entry_paths,
+        Err(e) => {
+            log::warn!("failed to read {retention_target} snapshots: {e}");
+            return vec![];
+        }
+    };
+    entry_paths
+        .filter_map(|entry_path| entry_path.ok())
+        .filter(|entry_path| entry_path.is_file() && layout::is_sidecar_path(entry_path))
+        .filter(|entry_path| !sidecar_paths.contains(entry_path))
+        .collect()
+}
+
+// The name the snapshot would have been given, from its modification time
+fn get_renamed_snapshot_path(config: &Config, snapshot: &PirouetteDirEntry) -> Result<PathBuf> {
+    let Some(snapshot_format) = layout::get_snapshot_format(config, &snapshot.path) else {
+        anyhow::bail!("it's neither a directory nor a tarball");
+    };
+    let directory = snapshot
+        .path
+        .parent()
+        .context("it has no parent directory")?;
+
+    let renamed_path = snapshot::format_snapshot_path(
+        config,
+        directory,
+        &snapshot_format,
+        &snapshot.timestamp.into(),
+    );
+    if renamed_path.exists() || volume::get_volume_path(&renamed_path, 1).exists() {
+        anyhow::bail!("{renamed_path:?}, named from its modification time, already exists");
+    }
+
+    Ok(renamed_path)
+}
+
+// Each of its volumes and sidecars are renamed along with it
+fn rename_snapshot(snapshot_path: &Path, renamed_path: &Path) -> Result<()> {
+    let base_path = volume::get_base_path(snapshot_path);
+    snapshot::place_snapshot_parts(&base_path, renamed_path, |from_path, to_path| {
+        fs::rename(from_path, to_path)
+            .with_context(|| format!("failed to rename {from_path:?} to {to_path:?}"))
+    })?;
+
+    for (from_path, to_path) in layout::get_sidecar_paths(&base_path)
+        .into_iter()
+        .zip(layout::get_sidecar_paths(renamed_path))
+    {
+        if from_path.exists() {
+            fs::rename(&from_path, &to_path)
+                .with_context(|| format!("failed to rename {from_path:?} to {to_path:?}"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_repair_targets() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\nretention.hours = 1"
+        ))?;
+        let all_targets = crate::get_all_retention_targets(&config);
+        let period_path = &all_targets[0].path;
+
+        // A renamed snapshot with a label, a deleted snapshot's run id, and a stray file
+        let renamed_snapshot = period_path.join("before-upgrade");
+        fs::create_dir_all(&renamed_snapshot)?;
+        fs::write(period_path.join("before-upgrade.label"), "upgrade\n")?;
+        let snapshot_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        fs::File::open(&renamed_snapshot)?.set_modified(snapshot_time)?;
+        fs::write(period_path.join("deleted.run-id"), "job-1\n")?;
+        fs::write(period_path.join("notes.txt"), "")?;
+
+        let repair_result = repair_targets(&config, &all_targets);
+        let renamed_path = snapshot::format_snapshot_path(
+            &config,
+            period_path,
+            &crate::configuration::ConfigOptsOutputFormat::Directory,
+            &snapshot_time.into(),
+        );
+        let is_renamed = renamed_path.is_dir() && !renamed_snapshot.exists();
+        let is_label_renamed = layout::get_sidecar_paths(&renamed_path)[1].exists();
+        let is_orphan_removed = !period_path.join("deleted.run-id").exists();
+        let is_stray_kept = period_path.join("notes.txt").exists();
+
+        fs::remove_dir_all(&target_path)?;
+        // Only the stray file couldn't be repaired
+        assert!(repair_result.is_err_and(|e| e.to_string().starts_with("1 snapshots")));
+        assert!(is_renamed);
+        assert!(is_label_renamed);
+        assert!(is_orphan_removed);
+        assert!(is_stray_kept);
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::PirouetteRetentionTarget;
+use crate::configuration::Config;
+use crate::layout;
+
+/*
+    A run id is a plain text sidecar next to a snapshot, eg: "2024-01-01T00:00.run-id".
+    A retried run with the same `--run-id` finds it, and doesn't take a second snapshot.
+*/
+
+const RUN_ID_EXTENSION: &str = "run-id";
+
+pub fn get_run_id_path(snapshot_path: &Path) -> PathBuf {
+    layout::get_sidecar_path(snapshot_path, RUN_ID_EXTENSION)
+}
+
+pub fn is_run_id_path(path: &Path) -> bool {
+    path.to_string_lossy()
+        .ends_with(&format!(".{RUN_ID_EXTENSION}"))
+}
+
+// The period's snapshot already taken by this run, if any
+pub fn find_run_snapshot(
+    config: &Config,
+    retention_target: &PirouetteRetentionTarget,
+) -> Option<PathBuf> {
+    let run_id = config.options.run_id.as_ref()?;
+
+    layout::get_snapshot_entries(retention_target)
+        .into_iter()
+        .map(|entry| entry.path)
+        .find(|snapshot_path| {
+            fs::read_to_string(get_run_id_path(snapshot_path))
+                .is_ok_and(|snapshot_run_id| snapshot_run_id.trim_end() == run_id)
+        })
+}
+
+// Periods this run has already snapshotted are left out, eg: when a scheduler retries it
+pub fn skip_completed_targets(
+    config: &Config,
+    rotation_targets: Vec<PirouetteRetentionTarget>,
+) -> Vec<PirouetteRetentionTarget> {
+    rotation_targets
+        .into_iter()
+        .filter(|retention_target| match find_run_snapshot(config, retention_target) {
+            Some(snapshot_path) => {
+                log::info!(
+                    "{retention_target} was already snapshotted by this run at {snapshot_path:?}, skipping it"
+                );
+                false
+            }
+            None => true,
+        })
+        .collect()
+}
+
+// Only written once the snapshot is complete, so a failed run can still be retried
+pub fn write_run_id(config: &Config, snapshot_path: &Path) -> Result<()> {
+    let Some(run_id) = &config.options.run_id else {
+        return Ok(());
+    };
+
+    let run_id_path = get_run_id_path(snapshot_path);
+    fs::write(&run_id_path, format!("{run_id}\n"))
+        .with_context(|| format!("failed to write run id {run_id_path:?}"))
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_skip_completed_targets() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\n\
+            retention.hours = 1\nretention.days = 1"
+        ))?;
+        config.options.run_id = Some("job-42".to_string());
+        let all_targets = crate::get_all_retention_targets(&config);
+
+        // Only hours was snapshotted by this run, and days by another one
+        let hours_snapshot = all_targets[0].path.join("snapshot");
+        let days_snapshot = all_targets[1].path.join("snapshot");
+        fs::create_dir_all(&hours_snapshot)?;
+        fs::create_dir_all(&days_snapshot)?;
+        write_run_id(&config, &hours_snapshot)?;
+        fs::write(get_run_id_path(&days_snapshot), "job-41\n")?;
+
+        let remaining_targets = skip_completed_targets(&config, all_targets);
+        let found_snapshot = find_run_snapshot(&config, &remaining_targets[0]);
+
+        fs::remove_dir_all(&target_path)?;
+        assert_eq!(remaining_targets.len(), 1);
+        assert_eq!(remaining_targets[0].period.to_string(), "days");
+        assert_eq!(found_snapshot, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_split_run_snapshot() -> Result<()> {
+        let target_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = \"/source\"\ntarget.path = {target_path:?}\nretention.hours = 1\n\
+            [options]\noutput_format = \"tarball\"\nsplit_size = 4"
+        ))?;
+        config.options.run_id = Some("job-42".to_string());
+        let all_targets = crate::get_all_retention_targets(&config);
+
+        // Written by the tarball's own path, but listed by its first volume
+        let snapshot_path = all_targets[0].path.join("snapshot.tgz");
+        let first_volume = crate::volume::get_volume_path(&snapshot_path, 1);
+        fs::create_dir_all(&all_targets[0].path)?;
+        fs::write(&first_volume, "")?;
+        write_run_id(&config, &snapshot_path)?;
+
+        let found_snapshot = find_run_snapshot(&config, &all_targets[0]);
+
+        fs::remove_dir_all(&target_path)?;
+        assert_eq!(found_snapshot, Some(first_volume));
+        Ok(())
+    }
+}
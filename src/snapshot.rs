@@ -1,166 +1,1829 @@
 use anyhow::{Context, Result};
 use glob::Pattern;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use walkdir::WalkDir;
 
+use crate::DisplayVec;
 use crate::PirouetteDirEntry;
 use crate::PirouetteRetentionTarget;
+use crate::PirouetteStats;
+use crate::acl;
+use crate::audit;
+use crate::clean;
 use crate::configuration::Config;
 use crate::configuration::ConfigOptsOutputFormat;
+use crate::configuration::ConfigOptsSnapshotTime;
+use crate::configuration::ConfigOptsSpecialFiles;
+use crate::configuration::ConfigOptsTarMtime;
 use crate::dry_run;
+use crate::entry_log::EntryLog;
+use crate::hook;
+use crate::in_progress;
+use crate::index;
+use crate::layout;
+use crate::manifest;
+use crate::run_id;
+use crate::volume;
+use crate::working_copy;
 
-pub fn copy_snapshot(config: &Config, retention_target: &PirouetteRetentionTarget) -> Result<()> {
-    let snapshot_output_format = &config.options.output_format;
+// Snapshots shared between several periods are written here first, then linked into place
+const STAGING_DIRECTORY: &str = ".staging";
 
-    let snapshot_path = format_snapshot_path(retention_target, snapshot_output_format);
+// Returns the snapshot's path, even if it was already taken
+pub fn copy_snapshot(
+    config: &Config,
+    retention_target: &PirouetteRetentionTarget,
+    stats: &PirouetteStats,
+) -> Result<PathBuf> {
+    let snapshot_output_format = &retention_target.output_format;
+
+    let snapshot_time = get_snapshot_time(config)?;
+    let snapshot_path = format_snapshot_path(
+        config,
+        &retention_target.path,
+        snapshot_output_format,
+        &snapshot_time,
+    );
+    if is_snapshot_taken(&snapshot_path) {
+        return Ok(snapshot_path);
+    }
     log::info!(
         "Creating a {snapshot_output_format:?} {:?} snapshot at {snapshot_path:?}",
         retention_target.period
     );
 
-    let source_contents = get_source_contents_iter(&config.source.path)
-        .filter(|entry| {
-            glob_includes(
-                &format_inner_entry_path(config, entry),
-                &config.options.include,
-            )
+    let _in_progress = in_progress::mark_in_progress(retention_target, &snapshot_path)?;
+    if config.options.resume_interrupted
+        && *snapshot_output_format == ConfigOptsOutputFormat::Directory
+    {
+        dry_run!(
+            retention_target.dry_run,
+            format!("interrupted snapshot will not be resumed"),
+            { in_progress::resume_interrupted_snapshot(retention_target, &snapshot_path) }
+        )?;
+    }
+    write_snapshot(
+        config,
+        &snapshot_path,
+        snapshot_output_format,
+        retention_target.dry_run,
+        retention_target.exclude_modified_within,
+        stats,
+    )?;
+    dry_run!(
+        retention_target.dry_run,
+        format!("snapshot will not be validated"),
+        { hook::run_validate_hook(config, &snapshot_path) }
+    )?;
+    dry_run!(
+        retention_target.dry_run,
+        format!("run id will not be written"),
+        {
+            run_id::write_run_id(config, &snapshot_path)?;
+            index::add_snapshot(config, &retention_target.period, &snapshot_path);
+            audit::record_action(config, audit::AuditAction::Create, &snapshot_path)
+        }
+    )?;
+    Ok(snapshot_path)
+}
+
+// Create a single snapshot in the staging directory, then move it into the first period,
+// and hardlink it from there into the rest. All the `retention_targets` must share the same
+// output format and `dry_run`.
+pub fn copy_staged_snapshot(
+    config: &Config,
+    retention_targets: &[PirouetteRetentionTarget],
+    stats: &PirouetteStats,
+) -> Result<()> {
+    let snapshot_output_format = &retention_targets[0].output_format;
+    let dry_run = retention_targets[0].dry_run;
+    let exclude_modified_within = retention_targets[0].exclude_modified_within;
+
+    let snapshot_time = get_snapshot_time(config)?;
+    let retention_targets: Vec<&PirouetteRetentionTarget> = retention_targets
+        .iter()
+        .filter(|retention_target| {
+            !is_snapshot_taken(&format_snapshot_path(
+                config,
+                &retention_target.path,
+                snapshot_output_format,
+                &snapshot_time,
+            ))
         })
-        .filter(|entry| {
-            glob_excludes(
-                &format_inner_entry_path(config, entry),
-                &config.options.exclude,
-            )
-        });
+        .collect();
+    if retention_targets.is_empty() {
+        return Ok(());
+    }
+
+    let _in_progress = retention_targets
+        .iter()
+        .map(|retention_target| {
+            let snapshot_path = format_snapshot_path(
+                config,
+                &retention_target.path,
+                snapshot_output_format,
+                &snapshot_time,
+            );
+            in_progress::mark_in_progress(retention_target, &snapshot_path)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let staging_directory = get_staging_directory(config);
+    let staging_path = format_snapshot_path(
+        config,
+        &staging_directory,
+        snapshot_output_format,
+        &snapshot_time,
+    );
+    log::info!(
+        "Creating a {snapshot_output_format:?} snapshot at {staging_path:?}, shared by {}",
+        retention_targets.display_vec()
+    );
 
     dry_run!(
-        config.options.dry_run,
-        format!("snapshot will not be created"),
+        dry_run,
+        format!("{staging_directory:?} directory will not be created"),
+        {
+            fs::create_dir_all(&staging_directory)
+                .with_context(|| format!("failed to create directory {staging_directory:?}"))
+        }
+    )?;
+
+    write_snapshot(
+        config,
+        &staging_path,
+        snapshot_output_format,
+        dry_run,
+        exclude_modified_within,
+        stats,
+    )?;
+
+    dry_run!(
+        dry_run,
+        format!("staged snapshot will not be moved into place"),
         {
-            match snapshot_output_format {
-                ConfigOptsOutputFormat::Directory => {
-                    copy_snapshot_to_dir(config, source_contents, &snapshot_path)
+            let mut placed_path: Option<PathBuf> = None;
+
+            for retention_target in retention_targets {
+                let snapshot_path = format_snapshot_path(
+                    config,
+                    &retention_target.path,
+                    snapshot_output_format,
+                    &snapshot_time,
+                );
+
+                match &placed_path {
+                    None => {
+                        log::info!("Moving staged snapshot to {snapshot_path:?}");
+                        place_snapshot_parts(&staging_path, &snapshot_path, move_snapshot)?;
+                        // Anything left behind was copied across filesystems instead of moved
+                        remove_snapshot_parts(&staging_path)?;
+                        // Validated once, before it's shared with the other periods
+                        hook::run_validate_hook(config, &snapshot_path)?;
+                        placed_path = Some(snapshot_path.clone());
+                    }
+                    Some(placed_path) => {
+                        log::info!("Linking {placed_path:?} to {snapshot_path:?}");
+                        place_snapshot_parts(placed_path, &snapshot_path, |from, to| {
+                            let copied_count = link_snapshot(from, to)?;
+                            // Copies don't share storage with the other periods,
+                            // which is the point of staging
+                            if copied_count > 0 {
+                                log::warn!(
+                                    "{copied_count} files couldn't be hardlinked into {to:?}, and were copied instead"
+                                );
+                            }
+                            Ok(())
+                        })?;
+                    }
                 }
-                ConfigOptsOutputFormat::Tarball => {
-                    copy_snapshot_to_tarball(config, source_contents, &snapshot_path)
+
+                if config.options.durable {
+                    sync_snapshot(&snapshot_path)?;
                 }
+                run_id::write_run_id(config, &snapshot_path)?;
+                index::add_snapshot(config, &retention_target.period, &snapshot_path);
+                audit::record_action(config, audit::AuditAction::Create, &snapshot_path)?;
             }
+
+            Ok(())
         }
     )
 }
 
-fn format_snapshot_path(
-    retention_target: &PirouetteRetentionTarget,
+// `temp_dir` can move the staging directory, eg: onto a faster disk than the target
+fn get_staging_directory(config: &Config) -> PathBuf {
+    match &config.options.temp_dir {
+        Some(temp_dir) => temp_dir.clone(),
+        None => config.target.path.join(STAGING_DIRECTORY),
+    }
+}
+
+// Apply `place_part` to each file or directory making up a snapshot, and its manifest.
+// Split tarball volumes keep their numbered suffix, eg: ".001"
+pub fn place_snapshot_parts<F>(from_path: &Path, to_path: &Path, place_part: F) -> Result<()>
+where
+    F: Fn(&Path, &Path) -> Result<()>,
+{
+    for (i, from_part) in volume::get_snapshot_parts(from_path)
+        .iter()
+        .enumerate()
+    {
+        let to_part = match from_part == from_path {
+            true => to_path.to_path_buf(),
+            false => volume::get_volume_path(to_path, i + 1),
+        };
+        place_part(from_part, &to_part)?;
+    }
+
+    let from_manifest_path = manifest::get_manifest_path(from_path);
+    if from_manifest_path.exists() {
+        place_part(&from_manifest_path, &manifest::get_manifest_path(to_path))?;
+    }
+
+    Ok(())
+}
+
+// Renames within a filesystem, and falls back to copying across them, eg: from `temp_dir`.
+// A copied source is left in place for the caller to remove.
+pub fn move_snapshot(source_path: &Path, target_path: &Path) -> Result<()> {
+    if let Err(e) = fs::rename(source_path, target_path) {
+        log::debug!("Failed to rename {source_path:?}, copying instead: {e}");
+        link_snapshot(source_path, target_path)?;
+    }
+
+    Ok(())
+}
+
+fn write_snapshot(
+    config: &Config,
+    snapshot_path: &PathBuf,
     snapshot_output_format: &ConfigOptsOutputFormat,
+    dry_run: bool,
+    exclude_modified_within: Option<u64>,
+    stats: &PirouetteStats,
+) -> Result<()> {
+    let source_contents =
+        get_filtered_source_contents_iter(config, exclude_modified_within, stats)?;
+
+    dry_run!(dry_run, format!("snapshot will not be created"), {
+        let start_time = Instant::now();
+
+        match snapshot_output_format {
+            ConfigOptsOutputFormat::Directory => {
+                if config.options.check_inodes {
+                    check_target_inodes(config, exclude_modified_within, snapshot_path)?;
+                }
+                copy_snapshot_to_dir(config, source_contents, snapshot_path, stats)?;
+            }
+            ConfigOptsOutputFormat::Tarball => {
+                copy_snapshot_to_tarball(config, source_contents, snapshot_path, stats)?;
+            }
+        }
+
+        if config.options.durable {
+            sync_snapshot(snapshot_path)?;
+        }
+
+        if config.options.log_throughput {
+            let snapshot_size = clean::get_snapshot_size(snapshot_path);
+            log::info!(
+                "Wrote {snapshot_path:?} at {}",
+                format_throughput(snapshot_size, start_time.elapsed())
+            );
+        }
+        Ok(())
+    })
+}
+
+// Bytes written to the `target` over the time taken, eg: "512.0 MB in 4.0s (128.0 MB/s)".
+// A falling rate can be the first sign of a degrading disk.
+fn format_throughput(bytes: u64, elapsed: Duration) -> String {
+    let megabytes = bytes as f64 / (1024.0 * 1024.0);
+    let seconds = elapsed.as_secs_f64();
+    let rate = match seconds > 0.0 {
+        true => megabytes / seconds,
+        false => 0.0,
+    };
+
+    format!("{megabytes:.1} MB in {seconds:.1}s ({rate:.1} MB/s)")
+}
+
+// Flush a finished snapshot, its manifest, and their directory entries to disk,
+// so it's never counted as complete (and older snapshots cleaned up) while still in the OS cache.
+// Contents are synced before the directories which hold them.
+fn sync_snapshot(snapshot_path: &Path) -> Result<()> {
+    log::debug!("Syncing {snapshot_path:?} to disk");
+
+    for snapshot_part in volume::get_snapshot_parts(snapshot_path) {
+        for entry in WalkDir::new(&snapshot_part).contents_first(true) {
+            let entry = entry.with_context(|| format!("failed to read {snapshot_part:?}"))?;
+            sync_path(entry.path())?;
+        }
+    }
+
+    let manifest_path = manifest::get_manifest_path(snapshot_path);
+    if manifest_path.exists() {
+        sync_path(&manifest_path)?;
+    }
+
+    match snapshot_path.parent() {
+        Some(parent) => sync_path(parent),
+        None => Ok(()),
+    }
+}
+
+// On unix, directories can be opened read-only and synced just like files
+fn sync_path(path: &Path) -> Result<()> {
+    fs::File::open(path)
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("failed to sync {path:?} to disk"))
+}
+
+// Errors are only yielded with `strict_walk`, and must pass through to abort the snapshot
+// `exclude_modified_within` is passed in, as it can be overridden per period
+pub fn get_filtered_source_contents_iter<'a>(
+    config: &'a Config,
+    exclude_modified_within: Option<u64>,
+    stats: &'a PirouetteStats,
+) -> Result<impl Iterator<Item = Result<PirouetteDirEntry>> + 'a> {
+    let over_budget_paths = match config.options.max_snapshot_size {
+        Some(max_snapshot_size) => {
+            get_over_budget_paths(config, exclude_modified_within, max_snapshot_size)?
+        }
+        None => HashSet::new(),
+    };
+
+    get_budgeted_source_contents_iter(config, exclude_modified_within, over_budget_paths, stats)
+}
+
+fn get_budgeted_source_contents_iter<'a>(
+    config: &'a Config,
+    exclude_modified_within: Option<u64>,
+    over_budget_paths: HashSet<PathBuf>,
+    stats: &'a PirouetteStats,
+) -> Result<impl Iterator<Item = Result<PirouetteDirEntry>> + 'a> {
+    // Fixed once per snapshot, so every entry is judged against the same instant
+    let modified_cutoff = exclude_modified_within.map(|seconds| {
+        SystemTime::now()
+            .checked_sub(Duration::from_secs(seconds))
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    });
+    let mut newest_files: HashMap<PathBuf, Option<PathBuf>> = HashMap::new();
+
+    Ok(
+        get_source_contents_iter(config, stats)?.filter(move |result| {
+            result.as_ref().map_or(true, |entry| {
+                let inner_entry_path = format_inner_entry_path(config, entry);
+                let is_included = is_path_included(config, &inner_entry_path)
+                    && glob_excludes(&inner_entry_path, &config.options.exclude)
+                    && !regex_matches(&inner_entry_path, &config.options.exclude_regex)
+                    && !is_modified_after(entry, modified_cutoff)
+                    && !(config.options.exclude_newest_per_dir
+                        && entry.path != config.source.path
+                        && is_newest_in_dir(&mut newest_files, &entry.path))
+                    && !over_budget_paths.contains(&entry.path)
+                    && (config.options.include_hidden
+                        || entry.path == config.source.path
+                        || !is_hidden_path(&inner_entry_path));
+
+                if !is_included {
+                    stats.count_filtered();
+                    return false;
+                }
+
+                if config.options.skip_empty_files && is_empty_file(&entry.path) {
+                    log::debug!("Skipping empty file {:?}", entry.path);
+                    stats.count_empty_skipped();
+                    return false;
+                }
+                stats.count_included();
+                true
+            })
+        }),
+    )
+}
+
+// With `max_snapshot_size`, the largest files are left out until the rest fit.
+// Found with a walk of their own, since every file's size must be known before the first is copied.
+fn get_over_budget_paths(
+    config: &Config,
+    exclude_modified_within: Option<u64>,
+    max_snapshot_size: u64,
+) -> Result<HashSet<PathBuf>> {
+    let mut file_sizes: Vec<(PathBuf, u64)> = vec![];
+    for entry in get_budgeted_source_contents_iter(
+        config,
+        exclude_modified_within,
+        HashSet::new(),
+        &PirouetteStats::default(),
+    )? {
+        let entry = entry?;
+        // Symlinks and special files take no space of their own
+        if let Ok(metadata) = fs::symlink_metadata(&entry.path)
+            && metadata.is_file()
+        {
+            file_sizes.push((entry.path, metadata.len()));
+        }
+    }
+
+    let mut total_size: u64 = file_sizes.iter().map(|(_, size)| size).sum();
+    file_sizes.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+
+    let mut over_budget_paths = HashSet::new();
+    for (path, size) in file_sizes {
+        if total_size <= max_snapshot_size {
+            break;
+        }
+        log::warn!("Excluding {path:?} ({size} bytes) to fit within max_snapshot_size");
+        total_size -= size;
+        over_budget_paths.insert(path);
+    }
+
+    if !over_budget_paths.is_empty() {
+        log::warn!(
+            "Excluded {} files to fit within max_snapshot_size, leaving {total_size} bytes",
+            crate::format_count(over_budget_paths.len())
+        );
+    }
+    Ok(over_budget_paths)
+}
+
+// Without `include_hidden`, skip anything in the source with a dotfile anywhere in its path,
+// eg: ".cache/foo". Only the path inside the source counts, so a hidden source is still copied.
+fn is_hidden_path(inner_entry_path: &Path) -> bool {
+    inner_entry_path.components().any(|component| {
+        component
+            .as_os_str()
+            .as_encoded_bytes()
+            .starts_with(b".")
+    })
+}
+
+// With `skip_empty_files`, skip zero-length regular files, eg: lock or marker files.
+// Symlinks and special files have no length of their own, so they're never skipped.
+fn is_empty_file(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.len() == 0)
+}
+
+// With `exclude_modified_within`, skip files which may still be being written
+fn is_modified_after(entry: &PirouetteDirEntry, cutoff: Option<SystemTime>) -> bool {
+    cutoff.is_some_and(|cutoff| entry.timestamp > cutoff)
+}
+
+// With `exclude_newest_per_dir`, skip the most recently modified file in each directory,
+// eg: a log file that's still being written. Each directory is only read once.
+fn is_newest_in_dir(newest_files: &mut HashMap<PathBuf, Option<PathBuf>>, path: &Path) -> bool {
+    let Some(directory) = path.parent() else {
+        return false;
+    };
+
+    newest_files
+        .entry(directory.to_path_buf())
+        .or_insert_with(|| get_newest_file(directory))
+        .as_deref()
+        == Some(path)
+}
+
+fn get_newest_file(directory: &Path) -> Option<PathBuf> {
+    fs::read_dir(directory)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            match metadata.is_file() {
+                true => Some((metadata.modified().ok()?, entry.path())),
+                false => None,
+            }
+        })
+        .max()
+        .map(|(_, path)| path)
+}
+
+// Every entry copied into a directory snapshot takes an inode on the target,
+// so refuse to start one that would run out partway through
+fn check_target_inodes(
+    config: &Config,
+    exclude_modified_within: Option<u64>,
+    snapshot_path: &Path,
+) -> Result<()> {
+    // Counted separately, so a snapshot's stats aren't counted twice
+    let projected_inodes = get_filtered_source_contents_iter(
+        config,
+        exclude_modified_within,
+        &PirouetteStats::default(),
+    )?
+    .filter(|result| result.is_ok())
+    .count() as u64;
+
+    let target_directory = snapshot_path
+        .ancestors()
+        .find(|path| path.exists())
+        .with_context(|| format!("no part of the snapshot path {snapshot_path:?} exists"))?;
+    let target_stats = nix::sys::statvfs::statvfs(target_directory)
+        .with_context(|| format!("failed to read filesystem stats for {target_directory:?}"))?;
+
+    // Filesystems with dynamic inodes, like btrfs, report zero in total
+    if target_stats.files() == 0 {
+        log::debug!("{target_directory:?} has no fixed inode limit, skipping inode check");
+        return Ok(());
+    }
+    let available_inodes = target_stats.files_available() as u64;
+
+    log::debug!(
+        "Snapshot needs {projected_inodes} inodes, {target_directory:?} has {available_inodes} available"
+    );
+    if projected_inodes > available_inodes {
+        anyhow::bail!(
+            "snapshot needs {projected_inodes} inodes, but {target_directory:?} only has {available_inodes} available"
+        );
+    }
+
+    Ok(())
+}
+
+// The time a snapshot is named after, which is the time of the run unless `snapshot_time`
+// asks for the newest source file's mtime, ie: the time the data is "as of"
+fn get_snapshot_time(config: &Config) -> Result<chrono::DateTime<chrono::Local>> {
+    if config.options.snapshot_time == ConfigOptsSnapshotTime::Now {
+        return Ok(chrono::Local::now());
+    }
+
+    // Counted separately, so a snapshot's stats aren't counted twice
+    let newest_mtime = get_filtered_source_contents_iter(
+        config,
+        config.options.exclude_modified_within,
+        &PirouetteStats::default(),
+    )?
+    .filter_map(|result| result.ok())
+    .map(|entry| entry.timestamp)
+    .max();
+
+    match newest_mtime {
+        Some(newest_mtime) => Ok(newest_mtime.into()),
+        None => {
+            log::warn!(
+                "The source has no files to take a modification time from, using the current time"
+            );
+            Ok(chrono::Local::now())
+        }
+    }
+}
+
+// With `snapshot_time = "source_mtime"`, an unchanged source gives the same name as last time.
+// The existing snapshot already holds the same data, so it's kept rather than overwritten.
+fn is_snapshot_taken(snapshot_path: &Path) -> bool {
+    let is_taken = snapshot_path.exists() || volume::get_volume_path(snapshot_path, 1).exists();
+    if is_taken {
+        log::warn!(
+            "{snapshot_path:?} already exists, keeping it rather than taking a new snapshot"
+        );
+    }
+    is_taken
+}
+
+pub fn format_snapshot_path(
+    config: &Config,
+    directory: &Path,
+    snapshot_output_format: &ConfigOptsOutputFormat,
+    snapshot_time: &chrono::DateTime<chrono::Local>,
 ) -> PathBuf {
-    let snapshot_timestamp = chrono::Local::now()
-        .format("%Y-%m-%dT%H:%M")
+    let snapshot_timestamp = snapshot_time
+        .format("%Y-%m-%dT%H:%M:%S")
         .to_string();
 
     match snapshot_output_format {
-        ConfigOptsOutputFormat::Directory => {
-            [retention_target.path.clone(), snapshot_timestamp.into()]
-                .iter()
-                .collect()
+        ConfigOptsOutputFormat::Directory => directory.join(snapshot_timestamp),
+        ConfigOptsOutputFormat::Tarball => {
+            layout::with_tarball_extension(config, &directory.join(snapshot_timestamp))
         }
+    }
+}
 
-        ConfigOptsOutputFormat::Tarball => [
-            retention_target.path.clone(),
-            format!("{snapshot_timestamp}.tgz").into(),
-        ]
-        .iter()
-        .collect(),
+// Hardlink a snapshot file, or every file in a snapshot directory.
+// Falls back to copying, eg: if the link would cross filesystems, and returns how many were copied.
+fn link_snapshot(source_path: &Path, target_path: &Path) -> Result<usize> {
+    let mut copied_count = 0;
+
+    for entry in WalkDir::new(source_path) {
+        let entry = entry.with_context(|| format!("failed to read {source_path:?}"))?;
+        // A tarball is a single file, and joining its empty inner path would add a trailing "/"
+        let entry_target_path = match entry.path().strip_prefix(source_path) {
+            Ok(inner_path) if !inner_path.as_os_str().is_empty() => target_path.join(inner_path),
+            _ => target_path.to_path_buf(),
+        };
+
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&entry_target_path)
+                .with_context(|| format!("failed to create directory {entry_target_path:?}"))?;
+        } else if let Err(e) = fs::hard_link(entry.path(), &entry_target_path) {
+            log::debug!(
+                "Failed to hardlink {:?}, copying instead: {e}",
+                entry.path()
+            );
+            fs::copy(entry.path(), &entry_target_path)
+                .with_context(|| format!("failed to copy file {:?}", entry.path()))?;
+            copied_count += 1;
+        }
+    }
+
+    Ok(copied_count)
+}
+
+// Removes every part of a snapshot, and its manifest, eg: once it's been moved from staging
+pub fn remove_snapshot_parts(snapshot_path: &Path) -> Result<()> {
+    let manifest_path = manifest::get_manifest_path(snapshot_path);
+    if manifest_path.exists() {
+        fs::remove_file(&manifest_path)
+            .with_context(|| format!("failed to remove {manifest_path:?}"))?;
+    }
+
+    for snapshot_part in volume::get_snapshot_parts(snapshot_path) {
+        if snapshot_part.is_dir() {
+            fs::remove_dir_all(&snapshot_part)
+        } else {
+            fs::remove_file(&snapshot_part)
+        }
+        .with_context(|| format!("failed to remove snapshot {snapshot_part:?}"))?;
     }
+
+    Ok(())
 }
 
 fn copy_snapshot_to_dir<I>(
     config: &Config,
     source_contents: I,
     snapshot_path: &PathBuf,
+    stats: &PirouetteStats,
 ) -> Result<()>
 where
-    I: Iterator<Item = PirouetteDirEntry>,
+    I: Iterator<Item = Result<PirouetteDirEntry>>,
 {
     fs::create_dir_all(snapshot_path)
         .with_context(|| format!("failed to create directory {snapshot_path:?}"))?;
 
+    // With `resume_interrupted`, the snapshot may have started from an interrupted one, so
+    // whatever this run stores is remembered, and anything else is removed afterwards
+    let mut stored_entry_paths = HashSet::new();
+    let source_contents = source_contents.inspect(|result| {
+        if let Ok(entry) = result
+            && config.options.resume_interrupted
+        {
+            stored_entry_paths.insert(format_stored_entry_path(config, entry));
+        }
+    });
+
+    // Links to an inode which was already copied are set aside, and recreated once it's in place
+    let mut hardlink_tracker = HardlinkTracker::default();
+    let mut hardlinks = vec![];
+    let source_contents = source_contents.filter(|result| {
+        let Ok(entry) = result else {
+            return true;
+        };
+        if !config.options.preserve_hardlinks {
+            return true;
+        }
+
+        let inner_entry_path = format_stored_entry_path(config, entry);
+        match hardlink_tracker.track(&entry.path, &inner_entry_path) {
+            Some(first_inner_path) => {
+                hardlinks.push((first_inner_path, inner_entry_path));
+                false
+            }
+            None => true,
+        }
+    });
+
+    let mut manifest_hashes = match config.options.copy_concurrency {
+        1 => copy_dir_entries(config, source_contents, snapshot_path, stats)?,
+        _ => copy_dir_entries_concurrently(config, source_contents, snapshot_path, stats)?,
+    };
+
+    if !hardlinks.is_empty() {
+        link_dir_entries(snapshot_path, &hardlinks, &mut manifest_hashes, stats)?;
+    }
+
+    if config.options.resume_interrupted {
+        remove_unstored_entries(snapshot_path, &stored_entry_paths)?;
+    }
+
+    if config.options.preserve_acls {
+        copy_dir_acls(config, snapshot_path)?;
+    }
+
+    if config.options.preserve_metadata {
+        copy_dir_metadata(config, snapshot_path)?;
+    }
+
+    if config.options.manifest {
+        manifest::write_manifest(snapshot_path, &manifest_hashes)?;
+    }
+
+    Ok(())
+}
+
+// Returns the manifest hash of each copied entry, if `manifest` is set
+fn copy_dir_entries<I>(
+    config: &Config,
+    source_contents: I,
+    snapshot_path: &Path,
+    stats: &PirouetteStats,
+) -> Result<Vec<(PathBuf, String)>>
+where
+    I: Iterator<Item = Result<PirouetteDirEntry>>,
+{
+    let mut manifest_hashes = vec![];
+
     for entry in source_contents {
-        let inner_entry_path = format_inner_entry_path(config, &entry);
-        let target_entry_path: PathBuf = [snapshot_path, &inner_entry_path]
-            .iter()
-            .collect();
-        log::debug!("Copying {:?} to {target_entry_path:?}", entry.path);
+        let entry = entry?;
+        let inner_entry_path = format_stored_entry_path(config, &entry);
+        let target_entry_path = snapshot_path.join(&inner_entry_path);
+
+        if let Some(hash) = copy_dir_entry(config, &entry.path, &target_entry_path)? {
+            manifest_hashes.push((inner_entry_path, hash));
+        }
+        stats.count_copied();
+    }
+
+    Ok(manifest_hashes)
+}
+
+// Like `copy_dir_entries`, with `copy_concurrency` worker threads copying at once.
+// The source is still walked on this thread, so stats are only ever counted here, and the
+// manifest is sorted so it doesn't depend on which worker finished first.
+fn copy_dir_entries_concurrently<I>(
+    config: &Config,
+    source_contents: I,
+    snapshot_path: &Path,
+    stats: &PirouetteStats,
+) -> Result<Vec<(PathBuf, String)>>
+where
+    I: Iterator<Item = Result<PirouetteDirEntry>>,
+{
+    let copy_concurrency = config.options.copy_concurrency;
+    log::debug!("Copying with {copy_concurrency} concurrent workers");
+
+    // Bounded, so the walk never gets far ahead of the copies
+    let (job_sender, job_receiver) = mpsc::sync_channel::<(PathBuf, PathBuf)>(copy_concurrency);
+    let job_receiver = Mutex::new(job_receiver);
+    let (result_sender, result_receiver) = mpsc::channel();
+
+    let mut manifest_hashes = vec![];
+    let mut errors: Vec<anyhow::Error> = vec![];
+    // Returns whether the copy failed
+    let mut handle_result = |result: Result<(PathBuf, Option<String>)>| match result {
+        Ok((inner_entry_path, hash)) => {
+            if let Some(hash) = hash {
+                manifest_hashes.push((inner_entry_path, hash));
+            }
+            stats.count_copied();
+            false
+        }
+        Err(e) => {
+            errors.push(e);
+            true
+        }
+    };
+
+    thread::scope(|scope| {
+        for _ in 0..copy_concurrency {
+            let job_receiver = &job_receiver;
+            let result_sender = result_sender.clone();
+            scope.spawn(move || {
+                loop {
+                    // A poisoned lock means another worker panicked, so stop taking jobs
+                    let Ok(job) = job_receiver
+                        .lock()
+                        .map(|receiver| receiver.recv())
+                    else {
+                        break;
+                    };
+                    let Ok((entry_path, inner_entry_path)) = job else {
+                        break;
+                    };
+
+                    let target_entry_path = snapshot_path.join(&inner_entry_path);
+                    let result = copy_dir_entry(config, &entry_path, &target_entry_path)
+                        .map(|hash| (inner_entry_path, hash));
+                    if result_sender.send(result).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(result_sender);
+
+        // Stop handing out copies after the first failure, but let those in flight finish
+        let mut has_failed = false;
+        for entry in source_contents {
+            match entry {
+                Ok(entry) => {
+                    let inner_entry_path = format_stored_entry_path(config, &entry);
+                    if job_sender
+                        .send((entry.path, inner_entry_path))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(e) => has_failed |= handle_result(Err(e)),
+            }
+
+            while let Ok(result) = result_receiver.try_recv() {
+                has_failed |= handle_result(result);
+            }
+            if has_failed {
+                break;
+            }
+        }
+        drop(job_sender);
+
+        for result in result_receiver {
+            handle_result(result);
+        }
+    });
+
+    let mut errors = errors.into_iter();
+    if let Some(first_error) = errors.next() {
+        for error in errors {
+            log::error!("{error:?}");
+        }
+        return Err(first_error);
+    }
+
+    manifest_hashes.sort();
+    Ok(manifest_hashes)
+}
+
+// Directories aren't copied themselves, only created to hold files, so their ACLs are copied
+// over once the snapshot is complete
+fn copy_dir_acls(config: &Config, snapshot_path: &Path) -> Result<()> {
+    for entry in WalkDir::new(snapshot_path).min_depth(1) {
+        let entry = entry.with_context(|| format!("failed to read {snapshot_path:?}"))?;
+        if !entry.file_type().is_dir() {
+            continue;
+        }
+
+        let stored_entry_path = strip_source_prefix(snapshot_path, entry.path());
+        let source_entry_path = config
+            .source
+            .path
+            .join(strip_source_basename(config, &stored_entry_path));
+        if source_entry_path.is_dir() {
+            acl::copy_acls_or_warn(&source_entry_path, entry.path());
+        }
+    }
+
+    Ok(())
+}
+
+// Like `copy_dir_acls`, for each directory's mode and modification time. The snapshot's own
+// directory is left alone, as its modification time is the snapshot's timestamp.
+fn copy_dir_metadata(config: &Config, snapshot_path: &Path) -> Result<()> {
+    for entry in WalkDir::new(snapshot_path).min_depth(1) {
+        let entry = entry.with_context(|| format!("failed to read {snapshot_path:?}"))?;
+        if !entry.file_type().is_dir() {
+            continue;
+        }
+
+        let stored_entry_path = strip_source_prefix(snapshot_path, entry.path());
+        let source_entry_path = config
+            .source
+            .path
+            .join(strip_source_basename(config, &stored_entry_path));
+        let Ok(source_metadata) = fs::metadata(&source_entry_path) else {
+            continue;
+        };
+        if !source_metadata.is_dir() {
+            continue;
+        }
+
+        // The owner can always list and write, or the snapshot couldn't be restored or cleaned up
+        let mode = (source_metadata.permissions().mode() & 0o7777) | 0o700;
+        fs::set_permissions(entry.path(), fs::Permissions::from_mode(mode))
+            .with_context(|| format!("failed to set permissions of {:?}", entry.path()))?;
+        copy_modified_time(&source_entry_path, entry.path())?;
+    }
+
+    Ok(())
+}
+
+pub fn copy_modified_time(source_path: &Path, target_path: &Path) -> Result<()> {
+    let modified_time = fs::metadata(source_path)
+        .and_then(|metadata| metadata.modified())
+        .with_context(|| format!("failed to read modification time of {source_path:?}"))?;
+
+    fs::File::open(target_path)
+        .and_then(|f| f.set_modified(modified_time))
+        .with_context(|| format!("failed to set modification time of {target_path:?}"))
+}
+
+// Recreate each hardlink from the source, between entries already copied into the snapshot
+fn link_dir_entries(
+    snapshot_path: &Path,
+    hardlinks: &[(PathBuf, PathBuf)],
+    manifest_hashes: &mut Vec<(PathBuf, String)>,
+    stats: &PirouetteStats,
+) -> Result<()> {
+    let first_hashes: HashMap<PathBuf, String> = manifest_hashes.iter().cloned().collect();
+
+    for (first_inner_path, inner_entry_path) in hardlinks {
+        let first_entry_path = snapshot_path.join(first_inner_path);
+        let target_entry_path = snapshot_path.join(inner_entry_path);
+        log::debug!("Linking {first_entry_path:?} to {target_entry_path:?}");
+
+        if let Some(parent) = target_entry_path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create directory {parent:?}"))?;
+        }
+
+        if let Err(e) = fs::hard_link(&first_entry_path, &target_entry_path) {
+            log::warn!("Failed to link {target_entry_path:?}, copying it instead: {e}");
+            fs::copy(&first_entry_path, &target_entry_path)
+                .with_context(|| format!("failed to copy file {first_entry_path:?}"))?;
+        }
+
+        if let Some(hash) = first_hashes.get(first_inner_path) {
+            manifest_hashes.push((inner_entry_path.clone(), hash.clone()));
+        }
+        stats.count_copied();
+    }
+
+    manifest_hashes.sort();
+    Ok(())
+}
+
+// Remembers where each inode with several links was first stored in a snapshot, so later
+// links to it can be recreated instead of stored again
+#[derive(Default)]
+struct HardlinkTracker {
+    stored_inodes: HashMap<(u64, u64), PathBuf>,
+}
+
+impl HardlinkTracker {
+    // Returns where the entry's inode was first stored, if it's been seen before
+    fn track(&mut self, entry_path: &Path, inner_entry_path: &Path) -> Option<PathBuf> {
+        let metadata = fs::symlink_metadata(entry_path).ok()?;
+        if !metadata.is_file() || metadata.nlink() < 2 {
+            return None;
+        }
+
+        let inode = (metadata.dev(), metadata.ino());
+        if let Some(first_inner_path) = self.stored_inodes.get(&inode) {
+            return Some(first_inner_path.clone());
+        }
+        self.stored_inodes
+            .insert(inode, inner_entry_path.to_path_buf());
+        None
+    }
+}
+
+// Copy one file into a directory snapshot, and return its hash if `manifest` is set
+fn copy_dir_entry(
+    config: &Config,
+    entry_path: &Path,
+    target_entry_path: &Path,
+) -> Result<Option<String>> {
+    if config.options.resume_interrupted && is_already_copied(entry_path, target_entry_path) {
+        log::debug!("{target_entry_path:?} was already copied before the interruption");
+        return match config.options.manifest && !is_archived_special_file(config, entry_path) {
+            true => Ok(Some(manifest::hash_file(target_entry_path)?)),
+            false => Ok(None),
+        };
+    }
+    log::debug!("Copying {entry_path:?} to {target_entry_path:?}");
+
+    if let Some(parent) = target_entry_path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create directory {parent:?}"))?;
+    }
+
+    // Special files have no contents to copy or hash, so they're recreated instead
+    if is_archived_special_file(config, entry_path) {
+        copy_special_file(entry_path, target_entry_path)?;
+        return Ok(None);
+    }
+
+    let hash = match config.options.manifest || config.options.verify_after_copy {
+        true => Some(manifest::copy_and_hash_file(entry_path, target_entry_path)?),
+        false => {
+            fs::copy(entry_path, target_entry_path)
+                .with_context(|| format!("failed to copy file {entry_path:?}"))?;
+            None
+        }
+    };
+
+    if config.options.preserve_acls && !entry_path.is_symlink() {
+        acl::copy_acls_or_warn(entry_path, target_entry_path);
+    }
+
+    // `fs::copy` already keeps the file's mode, but not its modification time
+    if config.options.preserve_metadata && !entry_path.is_symlink() {
+        copy_modified_time(entry_path, target_entry_path)?;
+    }
+
+    let Some(hash) = hash else {
+        return Ok(None);
+    };
+
+    // Read the copy back, to catch anything corrupted on the way to the target
+    if config.options.verify_after_copy {
+        let copied_hash = manifest::hash_file(target_entry_path)?;
+        if copied_hash != hash {
+            anyhow::bail!(
+                "{target_entry_path:?} doesn't match its source {entry_path:?} after copying"
+            );
+        }
+    }
+
+    Ok(config.options.manifest.then_some(hash))
+}
+
+// A resumed snapshot still holds whatever the interrupted one copied, including entries
+// since deleted from, or excluded from, the source. Only this run's entries, and the
+// directories which hold them, are kept.
+fn remove_unstored_entries(
+    snapshot_path: &Path,
+    stored_entry_paths: &HashSet<PathBuf>,
+) -> Result<()> {
+    let kept_paths: HashSet<&Path> = stored_entry_paths
+        .iter()
+        .flat_map(|stored_entry_path| stored_entry_path.ancestors())
+        .collect();
+
+    let mut entries = WalkDir::new(snapshot_path)
+        .min_depth(1)
+        .into_iter();
+    while let Some(entry) = entries.next() {
+        let entry = entry.with_context(|| format!("failed to read {snapshot_path:?}"))?;
+        let inner_entry_path = entry
+            .path()
+            .strip_prefix(snapshot_path)
+            .unwrap_or(entry.path());
+        if kept_paths.contains(inner_entry_path) {
+            continue;
+        }
+
+        log::debug!(
+            "Removing {:?}, which is no longer in the source",
+            entry.path()
+        );
+        if entry.file_type().is_dir() {
+            fs::remove_dir_all(entry.path())
+                .with_context(|| format!("failed to remove {:?}", entry.path()))?;
+            entries.skip_current_dir();
+        } else {
+            fs::remove_file(entry.path())
+                .with_context(|| format!("failed to remove {:?}", entry.path()))?;
+        }
+    }
+
+    Ok(())
+}
+
+// With `resume_interrupted`, a file the interrupted snapshot already holds is kept if it's
+// still current, ie: the same size, and copied since the source was last modified
+fn is_already_copied(entry_path: &Path, target_entry_path: &Path) -> bool {
+    let Ok(target_metadata) = fs::symlink_metadata(target_entry_path) else {
+        return false;
+    };
+    let Ok(source_metadata) = fs::symlink_metadata(entry_path) else {
+        return false;
+    };
+    if is_special_file_type(&source_metadata.file_type()) {
+        return source_metadata.file_type() == target_metadata.file_type();
+    }
+
+    // Copies follow symlinks, so they're compared with what the source points to
+    let Ok(source_metadata) = fs::metadata(entry_path) else {
+        return false;
+    };
+    target_metadata.is_file()
+        && target_metadata.len() == source_metadata.len()
+        && target_metadata.modified().ok() >= source_metadata.modified().ok()
+}
+
+fn copy_snapshot_to_tarball<I>(
+    config: &Config,
+    source_contents: I,
+    snapshot_path: &PathBuf,
+    stats: &PirouetteStats,
+) -> Result<()>
+where
+    I: Iterator<Item = Result<PirouetteDirEntry>>,
+{
+    let tarball_name = format!("{snapshot_path:?}");
+
+    // Remember what was archived, to mirror it into the working copy afterwards
+    let mut archived_entries = vec![];
+    let source_contents = with_stored_entry_paths(config, source_contents).inspect(|result| {
+        if let Ok((entry, inner_entry_path)) = result
+            && config.options.maintain_working_copy
+            && !is_archived_special_file(config, &entry.path)
+        {
+            archived_entries.push((entry.clone(), inner_entry_path.clone()));
+        }
+    });
+
+    if let Some(split_size) = config.options.split_size {
+        let split_writer = volume::SplitWriter::new(snapshot_path, split_size);
+        write_tarball(config, source_contents, split_writer, &tarball_name, stats)?
+            .flush()
+            .with_context(|| format!("failed to close tarball {tarball_name}"))?;
+    } else {
+        let snapshot_file = fs::File::create(snapshot_path)
+            .with_context(|| format!("failed to create tarball {tarball_name}"))?;
+        write_tarball(
+            config,
+            source_contents,
+            &snapshot_file,
+            &tarball_name,
+            stats,
+        )?;
+    }
+
+    if config.options.maintain_working_copy {
+        working_copy::update_working_copy(config, &archived_entries)
+            .context("failed to update working copy")?;
+    }
+
+    Ok(())
+}
+
+// Archive an existing Directory snapshot as a single tarball, eg: to consolidate old snapshots.
+// The tarball keeps the snapshot's mtime, so it isn't mistaken for a newer snapshot.
+pub fn archive_directory_snapshot(
+    config: &Config,
+    snapshot: &PirouetteDirEntry,
+    tarball_path: &Path,
+) -> Result<()> {
+    let tarball_name = format!("{tarball_path:?}");
+    let snapshot_contents = WalkDir::new(&snapshot.path)
+        .into_iter()
+        .filter(|result| {
+            result.as_ref().map_or(true, |entry| {
+                let ft = entry.file_type();
+                !ft.is_dir()
+            })
+        })
+        .map(|result| {
+            let entry = result.with_context(|| format!("failed to read {:?}", snapshot.path))?;
+            let inner_entry_path = strip_source_prefix(&snapshot.path, entry.path());
+            Ok((entry.into(), inner_entry_path))
+        });
+
+    let tarball_file = fs::File::create(tarball_path)
+        .with_context(|| format!("failed to create tarball {tarball_name}"))?;
+    let tarball_file = write_tarball(
+        config,
+        snapshot_contents,
+        tarball_file,
+        &tarball_name,
+        &PirouetteStats::default(),
+    )?;
+
+    tarball_file
+        .set_modified(snapshot.timestamp)
+        .with_context(|| format!("failed to set modification time of {tarball_name}"))
+}
+
+// Stream a tarball of the source to stdout, for piping elsewhere. Nothing is written to the target.
+pub fn write_snapshot_to_stdout(config: &Config, stats: &PirouetteStats) -> Result<()> {
+    let source_contents =
+        get_filtered_source_contents_iter(config, config.options.exclude_modified_within, stats)?;
+    log::info!("Writing a Tarball snapshot to stdout");
+
+    dry_run!(
+        config.options.dry_run,
+        format!("snapshot will not be written to stdout"),
+        {
+            let stdout = io::BufWriter::new(io::stdout().lock());
+            let source_contents = with_stored_entry_paths(config, source_contents);
+            write_tarball(config, source_contents, stdout, "stdout", stats)?
+                .flush()
+                .context("failed to close tarball stdout")
+        }
+    )
+}
+
+// Pair each source entry with the path it's stored at inside a snapshot
+fn with_stored_entry_paths<'a, I>(
+    config: &'a Config,
+    source_contents: I,
+) -> impl Iterator<Item = Result<(PirouetteDirEntry, PathBuf)>> + 'a
+where
+    I: Iterator<Item = Result<PirouetteDirEntry>> + 'a,
+{
+    source_contents.map(|result| {
+        result.map(|entry| {
+            let inner_entry_path = format_stored_entry_path(config, &entry);
+            (entry, inner_entry_path)
+        })
+    })
+}
+
+// Write the gzipped tarball to any writer, returning it once the archive is finished.
+// Each entry is stored at the path it's paired with.
+fn write_tarball<I, W>(
+    config: &Config,
+    source_contents: I,
+    writer: W,
+    tarball_name: &str,
+    stats: &PirouetteStats,
+) -> Result<W>
+where
+    I: Iterator<Item = Result<(PirouetteDirEntry, PathBuf)>>,
+    W: Write,
+{
+    let snapshot_writer = create_tarball_encoder(writer, config.options.compression_time_budget);
+    let mut snapshot_archive = tar::Builder::new(CountingWriter::new(snapshot_writer));
+    let mut hardlink_tracker = HardlinkTracker::default();
+    let mut entry_log = EntryLog::open(config)?;
+
+    for entry in source_contents {
+        let (entry, inner_entry_path) = entry?;
+        log::debug!("Copying {:?} to {inner_entry_path:?}", entry.path);
+
+        // A later link to an inode already in the tarball is stored as a link to its first path
+        if config.options.preserve_hardlinks
+            && let Some(first_inner_path) = hardlink_tracker.track(&entry.path, &inner_entry_path)
+        {
+            let mut header = get_hardlink_header(&entry.path)?;
+            snapshot_archive
+                .append_link(&mut header, &inner_entry_path, first_inner_path)
+                .with_context(|| format!("Failed to write tarball {tarball_name}"))?;
+            entry_log.record(&inner_entry_path);
+            stats.count_copied();
+            continue;
+        }
+
+        // Opening a fifo would block, and devices would be read from, so only a header is written
+        if is_archived_special_file(config, &entry.path) {
+            let mut header = get_special_file_header(&entry.path)?;
+            snapshot_archive
+                .append_data(&mut header, &inner_entry_path, io::empty())
+                .with_context(|| format!("Failed to write tarball {tarball_name}"))?;
+            entry_log.record(&inner_entry_path);
+            stats.count_copied();
+            continue;
+        }
+
+        let mut f = fs::File::open(&entry.path)
+            .with_context(|| format!("Failed to read file {:?}", &entry.path))?;
+
+        match config.options.tar_mtime {
+            ConfigOptsTarMtime::File => snapshot_archive.append_file(&inner_entry_path, &mut f),
+            ConfigOptsTarMtime::Walk => {
+                let mut header = get_walk_time_header(&f, &entry)?;
+                snapshot_archive.append_data(&mut header, &inner_entry_path, &mut f)
+            }
+        }
+        .with_context(|| format!("Failed to write tarball {tarball_name}"))?;
+
+        entry_log.record(&inner_entry_path);
+        stats.count_copied();
+    }
+
+    let mut snapshot_writer = snapshot_archive
+        .into_inner()
+        .with_context(|| format!("failed to close tarball {tarball_name}"))?;
+
+    // Pad the end-of-archive marker out to a whole record, like `tar --record-size`
+    let padding_size = get_tar_padding_size(snapshot_writer.count, config.options.tar_block_size);
+    snapshot_writer
+        .write_all(&vec![0; padding_size])
+        .with_context(|| format!("failed to close tarball {tarball_name}"))?;
+
+    snapshot_writer
+        .inner
+        .finish()
+        .with_context(|| format!("failed to close tarball {tarball_name}"))
+}
+
+/*
+    Special files, ie: fifos, sockets, and device nodes
+*/
+
+fn is_archived_special_file(config: &Config, path: &Path) -> bool {
+    config.options.special_files == ConfigOptsSpecialFiles::Archive
+        && fs::symlink_metadata(path)
+            .is_ok_and(|metadata| is_special_file_type(&metadata.file_type()))
+}
+
+fn is_special_file_type(file_type: &fs::FileType) -> bool {
+    file_type.is_fifo()
+        || file_type.is_socket()
+        || file_type.is_char_device()
+        || file_type.is_block_device()
+}
+
+// A tar header alone describes a fifo or device node. Sockets can't be archived at all.
+fn get_special_file_header(path: &Path) -> Result<tar::Header> {
+    let metadata =
+        fs::symlink_metadata(path).with_context(|| format!("Failed to read file {path:?}"))?;
+    let file_type = metadata.file_type();
+
+    let entry_type = if file_type.is_fifo() {
+        tar::EntryType::Fifo
+    } else if file_type.is_char_device() {
+        tar::EntryType::Char
+    } else if file_type.is_block_device() {
+        tar::EntryType::Block
+    } else {
+        anyhow::bail!("{path:?} is not a fifo or device node, so it can't be archived");
+    };
+
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata(&metadata);
+    header.set_entry_type(entry_type);
+    header.set_size(0);
+    header
+        .set_device_major(nix::sys::stat::major(metadata.rdev()) as u32)
+        .and_then(|_| header.set_device_minor(nix::sys::stat::minor(metadata.rdev()) as u32))
+        .with_context(|| format!("Failed to set device numbers for {path:?}"))?;
+    Ok(header)
+}
+
+// Recreate a fifo or device node with the same type, permissions, and device numbers.
+// Device nodes need root, or CAP_MKNOD.
+fn copy_special_file(source_path: &Path, target_path: &Path) -> Result<()> {
+    use nix::sys::stat::{Mode, SFlag};
+
+    let metadata = fs::symlink_metadata(source_path)
+        .with_context(|| format!("failed to read file {source_path:?}"))?;
+    let kind = SFlag::from_bits_truncate(metadata.mode()) & SFlag::S_IFMT;
+    let permissions = Mode::from_bits_truncate(metadata.mode());
+
+    nix::sys::stat::mknod(target_path, kind, permissions, metadata.rdev())
+        .with_context(|| format!("failed to recreate special file {source_path:?}"))
+}
+
+// Like `append_file`'s header, but stamped with the time seen during the walk,
+// so a file modified since then doesn't get a newer time than the rest of the snapshot
+fn get_walk_time_header(f: &fs::File, entry: &PirouetteDirEntry) -> Result<tar::Header> {
+    let metadata = f
+        .metadata()
+        .with_context(|| format!("Failed to read file {:?}", &entry.path))?;
+    let walk_time = entry
+        .timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata(&metadata);
+    header.set_mtime(walk_time.as_secs());
+    Ok(header)
+}
+
+fn get_hardlink_header(path: &Path) -> Result<tar::Header> {
+    let metadata = fs::symlink_metadata(path)
+        .with_context(|| format!("failed to read metadata for {path:?}"))?;
+
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata(&metadata);
+    header.set_entry_type(tar::EntryType::Link);
+    header.set_size(0);
+    Ok(header)
+}
+
+// The gzip header mtime is pinned to zero, so identical contents give byte-identical
+// tarballs which can be deduplicated by the backup storage
+fn create_gzip_member<W: Write>(
+    writer: W,
+    level: flate2::Compression,
+) -> flate2::write::GzEncoder<W> {
+    flate2::GzBuilder::new()
+        .mtime(0)
+        .write(writer, level)
+}
+
+fn create_tarball_encoder<W: Write>(writer: W, time_budget: Option<u64>) -> TarballEncoder<W> {
+    TarballEncoder {
+        encoder: Some(create_gzip_member(writer, flate2::Compression::best())),
+        level: flate2::Compression::best(),
+        start_time: Instant::now(),
+        time_budget: time_budget.map(Duration::from_secs),
+    }
+}
+
+// With `compression_time_budget`, a tarball that's compressing too slowly finishes its gzip
+// member, and carries on in a new one at a lower level: fast once over the budget, and
+// uncompressed once over twice the budget. Concatenated members are still one valid gzip file.
+struct TarballEncoder<W: Write> {
+    // Only ever taken to finish the current member
+    encoder: Option<flate2::write::GzEncoder<W>>,
+    level: flate2::Compression,
+    start_time: Instant,
+    time_budget: Option<Duration>,
+}
+
+impl<W: Write> TarballEncoder<W> {
+    fn get_budget_level(&self) -> flate2::Compression {
+        let elapsed = self.start_time.elapsed();
+        match self.time_budget {
+            Some(time_budget) if elapsed > time_budget * 2 => flate2::Compression::none(),
+            Some(time_budget) if elapsed > time_budget => flate2::Compression::fast(),
+            _ => self.level,
+        }
+    }
+
+    fn get_encoder(&mut self) -> io::Result<&mut flate2::write::GzEncoder<W>> {
+        self.encoder
+            .as_mut()
+            .ok_or_else(|| io::Error::other("tarball encoder is already finished"))
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        match self.encoder.take() {
+            Some(encoder) => encoder.finish(),
+            None => Err(io::Error::other("tarball encoder is already finished")),
+        }
+    }
+}
+
+impl<W: Write> Write for TarballEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let level = self.get_budget_level();
+        if level != self.level
+            && let Some(encoder) = self.encoder.take()
+        {
+            log::info!(
+                "Compression is over its time budget, continuing at level {}",
+                level.level()
+            );
+            self.encoder = Some(create_gzip_member(encoder.finish()?, level));
+            self.level = level;
+        }
+
+        self.get_encoder()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_encoder()?.flush()
+    }
+}
+
+fn get_tar_padding_size(archive_size: u64, block_size: u64) -> usize {
+    ((block_size - archive_size % block_size) % block_size) as usize
+}
+
+// Tracks how many bytes of uncompressed tar stream have been written
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        CountingWriter { inner, count: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub fn format_inner_entry_path(config: &Config, entry: &PirouetteDirEntry) -> PathBuf {
+    // A single-file source is stored under its own name, rather than an empty path
+    if entry.path == config.source.path {
+        return entry.path.file_name().unwrap_or_default().into();
+    }
+    strip_source_prefix(&config.source.path, &entry.path)
+}
+
+// Where an entry is stored inside a snapshot. Unlike the include/exclude patterns, which
+// always match relative to the source, this can keep the source's own name as a top-level folder.
+fn format_stored_entry_path(config: &Config, entry: &PirouetteDirEntry) -> PathBuf {
+    let inner_entry_path = format_inner_entry_path(config, entry);
+
+    // A single-file source is already stored under its own name
+    match config.options.include_source_basename && entry.path != config.source.path {
+        true => prefix_source_basename(&config.source.path, &inner_entry_path),
+        false => inner_entry_path,
+    }
+}
+
+// The inverse of `format_stored_entry_path`, for a directory in the snapshot
+fn strip_source_basename(config: &Config, stored_entry_path: &Path) -> PathBuf {
+    match config.options.include_source_basename {
+        true => stored_entry_path.components().skip(1).collect(),
+        false => stored_entry_path.to_path_buf(),
+    }
+}
+
+fn prefix_source_basename(source_path: &Path, inner_entry_path: &Path) -> PathBuf {
+    // For the source "/data/project", return "project/foo/bar.txt". A root source has no name.
+    match source_path.file_name() {
+        Some(source_basename) if inner_entry_path.as_os_str().is_empty() => source_basename.into(),
+        Some(source_basename) => Path::new(source_basename).join(inner_entry_path),
+        None => inner_entry_path.to_path_buf(),
+    }
+}
+
+fn strip_source_prefix(source_path: &Path, entry_path: &Path) -> PathBuf {
+    // For some entry "/path/to/source/foo/bar.txt", return the inner path "foo/bar.txt"
+    match entry_path.strip_prefix(source_path) {
+        Ok(inner_path) => inner_path.into(),
+        // Shouldn't happen, but don't crash the whole run over one odd path
+        Err(_) => {
+            let fallback_path: PathBuf = entry_path.file_name().unwrap_or_default().into();
+            log::warn!(
+                "{entry_path:?} is not under the source {source_path:?}, storing it as {fallback_path:?}"
+            );
+            fallback_path
+        }
+    }
+}
+
+pub fn get_source_contents_iter<'a>(
+    config: &'a Config,
+    stats: &'a PirouetteStats,
+) -> Result<Box<dyn Iterator<Item = Result<PirouetteDirEntry>> + 'a>> {
+    // It existed when the config was read, but a network mount may have gone since
+    if !config.source.path.exists() {
+        anyhow::bail!(
+            "source path {:?} is no longer available",
+            config.source.path
+        );
+    }
+
+    match &config.options.source_list {
+        Some(source_list) => Ok(Box::new(
+            get_source_list_iter(&config.source.path, source_list, stats)?
+                .filter(|entry| {
+                    let inner_entry_path = format_inner_entry_path(config, entry);
+                    let is_excluded = inner_entry_path.components().any(|component| {
+                        is_name_excluded(component.as_os_str(), &config.options.exclude_names)
+                    });
+
+                    if is_excluded {
+                        stats.count_filtered();
+                    }
+                    !is_excluded
+                })
+                .map(Ok),
+        )),
+        None => Ok(Box::new(get_source_walk_iter(config, stats))),
+    }
+}
+
+fn get_source_walk_iter<'a>(
+    config: &'a Config,
+    stats: &'a PirouetteStats,
+) -> impl Iterator<Item = Result<PirouetteDirEntry>> + 'a {
+    let source_device = fs::metadata(&config.source.path)
+        .map(|metadata| metadata.dev())
+        .ok();
+
+    WalkDir::new(&config.source.path)
+        .follow_links(config.options.follow_symlinks)
+        .into_iter()
+        .filter_entry(|entry| {
+            // Excluded names prune their whole subtree, so it's never walked
+            if entry.depth() > 0
+                && is_name_excluded(entry.file_name(), &config.options.exclude_names)
+            {
+                stats.count_filtered();
+                return false;
+            }
+            !is_dir_oversized(entry, config.options.max_dir_entries)
+        })
+        .filter_map(move |result| match result {
+            Ok(entry) => Some(Ok(entry)),
+            // Otherwise, an unmounted source would be a flood of unreadable entries, and the
+            // near-empty snapshot left behind would push good ones out in cleanup
+            Err(e) if is_source_vanished(&config.source.path, source_device) => {
+                Some(Err(anyhow::anyhow!(
+                    "source path {:?} disappeared while reading {}",
+                    config.source.path,
+                    format_walk_error(&e)
+                )))
+            }
+            // Nothing is missed by skipping a loop, since its ancestor is already being walked
+            Err(e) if e.loop_ancestor().is_some() => {
+                log::warn!(
+                    "Skipping symlink loop at {:?}, which leads back to {:?}",
+                    e.path().unwrap_or(Path::new("")),
+                    e.loop_ancestor().unwrap_or(Path::new(""))
+                );
+                None
+            }
+            Err(e) if config.options.strict_walk => Some(Err(anyhow::anyhow!(
+                "failed to read source entry {}",
+                format_walk_error(&e)
+            ))),
+            Err(e) => {
+                log::warn!("Error reading source entry {}", format_walk_error(&e));
+                stats.count_unreadable();
+                None
+            }
+        })
+        .filter(|result| {
+            result.as_ref().map_or(true, |entry| {
+                let ft = entry.file_type();
+                if !is_special_file_type(&ft) {
+                    return ft.is_file() || ft.is_symlink();
+                }
+
+                // Sockets only exist while their process is running, so they're always skipped
+                if config.options.special_files == ConfigOptsSpecialFiles::Archive
+                    && !ft.is_socket()
+                {
+                    return true;
+                }
+                log::debug!("Skipping special file {:?}", entry.path());
+                stats.count_special_skipped();
+                false
+            })
+        })
+        .map(|result| result.map(|x| x.into()))
+}
+
+// Gone entirely, or swapped for another filesystem, eg: the empty mount point of a network share.
+// A source which was never there to begin with is reported by the walk itself.
+fn is_source_vanished(source_path: &Path, source_device: Option<u64>) -> bool {
+    let Some(source_device) = source_device else {
+        return false;
+    };
+
+    fs::metadata(source_path).map_or(true, |metadata| metadata.dev() != source_device)
+}
+
+// Always name the offending path, which walkdir's own message may leave out
+fn format_walk_error(e: &walkdir::Error) -> String {
+    match (e.path(), e.io_error()) {
+        (Some(path), Some(io_error)) => format!("{path:?}: {io_error}"),
+        (Some(path), None) => format!("{path:?}: {e}"),
+        (None, _) => e.to_string(),
+    }
+}
+
+fn is_name_excluded(name: &std::ffi::OsStr, exclude_names: &[String]) -> bool {
+    exclude_names
+        .iter()
+        .any(|exclude_name| name == exclude_name.as_str())
+}
+
+// Prune runaway directories, eg: millions of session files, but never the source itself
+fn is_dir_oversized(entry: &walkdir::DirEntry, max_dir_entries: Option<usize>) -> bool {
+    let Some(max_dir_entries) = max_dir_entries else {
+        return false;
+    };
+    if entry.depth() == 0 || !entry.file_type().is_dir() {
+        return false;
+    }
+
+    let dir_entry_count = match fs::read_dir(entry.path()) {
+        Ok(dir_entries) => dir_entries.take(max_dir_entries + 1).count(),
+        Err(_) => return false,
+    };
+
+    if dir_entry_count > max_dir_entries {
+        log::warn!(
+            "Skipping {:?}, it has more than {max_dir_entries} entries",
+            entry.path()
+        );
+        return true;
+    }
+
+    false
+}
+
+// Summarise what went into a snapshot, and fail if too much of the source was unreadable
+pub fn check_snapshot_stats(
+    config: &Config,
+    retention_target: &PirouetteRetentionTarget,
+    stats: &PirouetteStats,
+) -> Result<()> {
+    log::info!("Snapshot summary for {retention_target}: {stats}");
 
-        if let Some(parent) = target_entry_path.parent() {
-            fs::create_dir_all(parent)
-                .with_context(|| format!("failed to create directory {parent:?}"))?;
-        }
+    let special_skipped_count = stats.special_skipped.get();
+    if special_skipped_count > 0 {
+        log::warn!(
+            "{special_skipped_count} special files (fifos, sockets, or device nodes) were skipped for {retention_target}"
+        );
+    }
 
-        fs::copy(&entry.path, &target_entry_path)
-            .with_context(|| format!("failed to copy file {:?}", &entry.path))?;
+    let unreadable_count = stats.unreadable.get();
+    if unreadable_count == 0 {
+        return Ok(());
     }
+    log::warn!("{unreadable_count} source entries could not be read for {retention_target}");
 
-    Ok(())
+    match config.options.max_unreadable_entries {
+        Some(max_unreadable_entries) if unreadable_count > max_unreadable_entries => {
+            anyhow::bail!(
+                "{unreadable_count} source entries could not be read, more than max_unreadable_entries ({max_unreadable_entries})"
+            )
+        }
+        _ => Ok(()),
+    }
 }
 
-fn copy_snapshot_to_tarball<I>(
-    config: &Config,
-    source_contents: I,
-    snapshot_path: &PathBuf,
-) -> Result<()>
-where
-    I: Iterator<Item = PirouetteDirEntry>,
-{
-    let snapshot_file = fs::File::create(snapshot_path)
-        .with_context(|| format!("failed to create tarball {snapshot_path:?}"))?;
+fn get_source_list_iter(
+    source_path: &Path,
+    source_list: &PathBuf,
+    stats: &PirouetteStats,
+) -> Result<impl Iterator<Item = PirouetteDirEntry>> {
+    let source_list_str = fs::read_to_string(source_list)
+        .with_context(|| format!("failed to read source_list {source_list:?}"))?;
 
-    let snapshot_writer =
-        flate2::write::GzEncoder::new(&snapshot_file, flate2::Compression::best());
-    let mut snapshot_archive = tar::Builder::new(snapshot_writer);
+    Ok(parse_source_list(source_path, &source_list_str, stats).into_iter())
+}
 
-    for entry in source_contents {
-        let inner_entry_path = format_inner_entry_path(config, &entry);
-        log::debug!("Copying {:?} to {inner_entry_path:?}", entry.path);
+fn parse_source_list(
+    source_path: &Path,
+    source_list_str: &str,
+    stats: &PirouetteStats,
+) -> Vec<PirouetteDirEntry> {
+    source_list_str
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        // Relative entries are relative to the source root
+        .map(|line| source_path.join(line.trim()))
+        .filter(|path| {
+            // `starts_with` is lexical, so "sub/../../etc/passwd" would otherwise pass
+            if !path.starts_with(source_path)
+                || path
+                    .components()
+                    .any(|component| component == std::path::Component::ParentDir)
+            {
+                log::warn!(
+                    "Skipping listed path {path:?}, it is not under the source {source_path:?}"
+                );
+                return false;
+            }
+            match fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.is_file() || metadata.is_symlink() => true,
+                Ok(_) => {
+                    log::warn!("Skipping listed path {path:?}, it is not a file");
+                    false
+                }
+                Err(e) => {
+                    log::warn!("Skipping listed path {path:?}: {e}");
+                    stats.count_unreadable();
+                    false
+                }
+            }
+        })
+        .map(|path| path.into())
+        .collect()
+}
 
-        let mut f = fs::File::open(&entry.path)
-            .with_context(|| format!("Failed to read file {:?}", &entry.path))?;
+// `include`, `include_extensions`, and `include_regex` are a union, so an entry only needs to
+// match one of those which are set. With none set, everything is included.
+fn is_path_included(config: &Config, path: &PathBuf) -> bool {
+    let patterns = &config.options.include;
+    let extensions = &config.options.include_extensions;
+    let regexes = &config.options.include_regex;
 
-        snapshot_archive
-            .append_file(inner_entry_path, &mut f)
-            .with_context(|| format!("Failed to write tarball {snapshot_path:?}"))?;
+    if patterns.is_empty() && extensions.is_empty() && regexes.is_empty() {
+        return true;
     }
 
-    snapshot_archive
-        .into_inner()
-        .with_context(|| format!("failed to close tarball {snapshot_path:?}"))?;
-
-    Ok(())
+    (!patterns.is_empty() && glob_includes(path, patterns))
+        || extension_includes(path, extensions)
+        || regex_matches(path, regexes)
 }
 
-fn format_inner_entry_path(config: &Config, entry: &PirouetteDirEntry) -> PathBuf {
-    // For some entry "/path/to/source/foo/bar.txt", return the inner path "foo/bar.txt"
-    entry
-        .path
-        .strip_prefix(&config.source.path)
-        .unwrap()
-        .into()
+// Matched against the whole inner path, so "^" and "$" anchor to its start and end
+fn regex_matches(path: &Path, regexes: &[Regex]) -> bool {
+    let path_str = path.to_string_lossy();
+    let result = regexes
+        .iter()
+        .any(|regex| regex.is_match(&path_str));
+
+    log::trace!("Testing if {path:?} matches {regexes:?}: result={result}");
+
+    result
 }
 
-fn get_source_contents_iter(source_path: &PathBuf) -> impl Iterator<Item = PirouetteDirEntry> {
-    WalkDir::new(source_path)
-        .into_iter()
-        .filter_map(|result| match result {
-            Ok(entry) => Some(entry),
-            Err(e) => {
-                log::warn!("Error reading some source contents: {e}");
-                None
-            }
-        })
-        .filter(|entry| {
-            let ft = entry.file_type();
-            ft.is_file() || ft.is_symlink()
-        })
-        .map(|x| x.into())
+fn extension_includes(path: &Path, extensions: &[String]) -> bool {
+    let result = path.extension().is_some_and(|path_extension| {
+        extensions
+            .iter()
+            .any(|extension| path_extension == extension.trim_start_matches('.'))
+    });
+
+    log::trace!("Testing if {path:?} has an extension in {extensions:?}: result={result}");
+
+    result
 }
 
 fn glob_includes(path: &PathBuf, patterns: &[Pattern]) -> bool {
@@ -169,7 +1832,7 @@ fn glob_includes(path: &PathBuf, patterns: &[Pattern]) -> bool {
         false => patterns.iter().any(|pat| pat.matches_path(path)),
     };
 
-    log::debug!("Testing if {path:?} include-matches {patterns:?}: result={result}");
+    log::trace!("Testing if {path:?} include-matches {patterns:?}: result={result}");
 
     result
 }
@@ -181,7 +1844,7 @@ fn glob_excludes(path: &PathBuf, patterns: &[Pattern]) -> bool {
         false => !patterns.iter().any(|pat| pat.matches_path(path)),
     };
 
-    log::debug!("Testing if {path:?} exclude-matches {patterns:?}: result={result}");
+    log::trace!("Testing if {path:?} exclude-matches {patterns:?}: result={result}");
 
     result
 }
@@ -190,6 +1853,7 @@ fn glob_excludes(path: &PathBuf, patterns: &[Pattern]) -> bool {
 mod tests {
     use super::*;
     use crate::PirouetteDirEntry;
+    use std::env;
     use std::time::SystemTime;
 
     fn create_test_entries(paths: Vec<&str>) -> Vec<PirouetteDirEntry> {
@@ -250,4 +1914,993 @@ mod tests {
 
         assert_eq!(result_data, expected_data);
     }
+
+    #[test]
+    fn test_include_extensions() -> Result<()> {
+        let mut config: Config = toml::from_str(
+            "source.path = \"/source\"\ntarget.path = \"/target\"\nretention.hours = 1",
+        )?;
+        config.options.include_extensions = vec!["rs".to_string(), ".toml".to_string()];
+        let included_paths = |config: &Config| -> Vec<PirouetteDirEntry> {
+            create_test_entries(vec!["main.rs", "Cargo.toml", "README.md", "rs"])
+                .into_iter()
+                .filter(|entry| is_path_included(config, &entry.path))
+                .collect()
+        };
+
+        let extension_result = included_paths(&config);
+        config.options.include = vec![Pattern::new("*.md")?];
+        let union_result = included_paths(&config);
+
+        assert_eq!(
+            extension_result,
+            create_test_entries(vec!["main.rs", "Cargo.toml"])
+        );
+        assert_eq!(
+            union_result,
+            create_test_entries(vec!["main.rs", "Cargo.toml", "README.md"])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_regex_filters() -> Result<()> {
+        let mut config: Config = toml::from_str(
+            "source.path = \"/source\"\ntarget.path = \"/target\"\nretention.hours = 1\n\
+            options.include_regex = [\"\\\\.(tmp|bak)\\\\.\\\\d+$\"]\n\
+            options.exclude_regex = [\"^cache/\"]",
+        )?;
+        let filtered_paths = |config: &Config| -> Vec<PirouetteDirEntry> {
+            create_test_entries(vec![
+                "foo.tmp.1",
+                "foo.bak.22",
+                "foo.tmp",
+                "cache/foo.tmp.1",
+            ])
+            .into_iter()
+            .filter(|entry| {
+                is_path_included(config, &entry.path)
+                    && !regex_matches(&entry.path, &config.options.exclude_regex)
+            })
+            .collect()
+        };
+
+        let regex_result = filtered_paths(&config);
+        config.options.include = vec![Pattern::new("*.tmp")?];
+        let union_result = filtered_paths(&config);
+
+        assert_eq!(
+            regex_result,
+            create_test_entries(vec!["foo.tmp.1", "foo.bak.22"])
+        );
+        assert_eq!(
+            union_result,
+            create_test_entries(vec!["foo.tmp.1", "foo.bak.22", "foo.tmp"])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_empty_files() -> Result<()> {
+        let source_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&source_path)?;
+        fs::write(source_path.join("foo.lock"), "")?;
+        fs::write(source_path.join("bar.txt"), "bar")?;
+        std::os::unix::fs::symlink("foo.lock", source_path.join("baz"))?;
+
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = \"/target\"\nretention.hours = 1"
+        ))?;
+        config.options.skip_empty_files = true;
+        let stats = PirouetteStats::default();
+        let mut result_paths: Vec<PathBuf> =
+            get_filtered_source_contents_iter(&config, None, &stats)?
+                .filter_map(|result| result.ok())
+                .map(|entry| entry.path)
+                .collect();
+        result_paths.sort();
+
+        fs::remove_dir_all(&source_path)?;
+        assert_eq!(
+            result_paths,
+            vec![source_path.join("bar.txt"), source_path.join("baz")]
+        );
+        // A skipped file isn't also counted as included
+        assert_eq!(stats.included.get(), 2);
+        assert_eq!(stats.empty_skipped.get(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_modified_within_per_period() -> Result<()> {
+        let source_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&source_path)?;
+        fs::write(source_path.join("old.txt"), "old")?;
+        fs::write(source_path.join("new.tmp"), "new")?;
+        let old_time = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        fs::File::open(source_path.join("old.txt"))?.set_modified(old_time)?;
+
+        let config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = \"/target\"\n\
+            retention.hours = 1\nretention.years = {{ keep = 1, exclude_modified_within = \"1h\" }}"
+        ))?;
+        let included_names = |retention_target: &PirouetteRetentionTarget| -> Result<Vec<String>> {
+            let mut names: Vec<String> = get_filtered_source_contents_iter(
+                &config,
+                retention_target.exclude_modified_within,
+                &PirouetteStats::default(),
+            )?
+            .filter_map(|result| result.ok())
+            .map(|entry| {
+                entry
+                    .path
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+            names.sort();
+            Ok(names)
+        };
+        let all_targets = crate::get_all_retention_targets(&config);
+        let hours_result = included_names(&all_targets[0]);
+        let years_result = included_names(&all_targets[1]);
+
+        fs::remove_dir_all(&source_path)?;
+        assert_eq!(hours_result?, vec!["new.tmp", "old.txt"]);
+        assert_eq!(years_result?, vec!["old.txt"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_newest_per_dir() -> Result<()> {
+        let source_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(source_path.join("logs"))?;
+        let start_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        for (hour, name) in ["logs/old.log", "logs/live.log", "old.txt", "new.txt"]
+            .iter()
+            .enumerate()
+        {
+            fs::write(source_path.join(name), name)?;
+            fs::File::open(source_path.join(name))?
+                .set_modified(start_time + Duration::from_secs(hour as u64 * 60 * 60))?;
+        }
+
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = \"/target\"\nretention.hours = 1"
+        ))?;
+        config.options.exclude_newest_per_dir = true;
+        let stats = PirouetteStats::default();
+        let mut result_paths: Vec<PathBuf> =
+            get_filtered_source_contents_iter(&config, None, &stats)?
+                .filter_map(|result| result.ok())
+                .map(|entry| entry.path)
+                .collect();
+        result_paths.sort();
+
+        fs::remove_dir_all(&source_path)?;
+        assert_eq!(
+            result_paths,
+            vec![
+                source_path.join("logs/old.log"),
+                source_path.join("old.txt")
+            ]
+        );
+        assert_eq!(stats.filtered.get(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_hidden() -> Result<()> {
+        let source_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(source_path.join(".cache"))?;
+        fs::create_dir_all(source_path.join("docs"))?;
+        for name in [".cache/foo", ".bashrc", "docs/.notes", "docs/report.txt"] {
+            fs::write(source_path.join(name), name)?;
+        }
+
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = \"/target\"\nretention.hours = 1"
+        ))?;
+        let get_result_paths = |config: &Config| -> Result<Vec<PathBuf>> {
+            let mut result_paths: Vec<PathBuf> =
+                get_filtered_source_contents_iter(config, None, &PirouetteStats::default())?
+                    .filter_map(|result| result.ok())
+                    .map(|entry| entry.path)
+                    .filter(|path| path.is_file())
+                    .collect();
+            result_paths.sort();
+            Ok(result_paths)
+        };
+        let default_paths = get_result_paths(&config);
+        config.options.include_hidden = false;
+        let visible_paths = get_result_paths(&config);
+
+        fs::remove_dir_all(&source_path)?;
+        assert_eq!(default_paths?.len(), 4);
+        assert_eq!(visible_paths?, vec![source_path.join("docs/report.txt")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_snapshot_size() -> Result<()> {
+        let source_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(source_path.join("sub"))?;
+        fs::write(source_path.join("large"), vec![0; 1000])?;
+        fs::write(source_path.join("sub/medium"), vec![0; 500])?;
+        fs::write(source_path.join("small"), vec![0; 100])?;
+
+        let config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = \"/target\"\nretention.hours = 1\n\
+            [options]\nmax_snapshot_size = 1000"
+        ))?;
+        let stats = PirouetteStats::default();
+        let mut result_paths: Vec<PathBuf> =
+            get_filtered_source_contents_iter(&config, None, &stats)?
+                .filter_map(|result| result.ok())
+                .map(|entry| entry.path)
+                .collect();
+        result_paths.sort();
+
+        fs::remove_dir_all(&source_path)?;
+        // Only the largest needs leaving out, even though it would fit on its own
+        assert_eq!(
+            result_paths,
+            vec![source_path.join("small"), source_path.join("sub/medium")]
+        );
+        assert_eq!(stats.filtered.get(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_list_skips_invalid_paths() -> Result<()> {
+        let source_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(source_path.join("foo"))?;
+        fs::write(source_path.join("foo/bar.txt"), "bar")?;
+        fs::write(source_path.join("baz.txt"), "baz")?;
+
+        let source_list_str = format!(
+            "foo/bar.txt\n\n{}\nmissing.txt\nfoo\n/etc/hostname\nfoo/../../etc/hostname\n",
+            source_path.join("baz.txt").display()
+        );
+        let stats = PirouetteStats::default();
+        let result_paths: Vec<PathBuf> = parse_source_list(&source_path, &source_list_str, &stats)
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+
+        fs::remove_dir_all(&source_path)?;
+
+        assert_eq!(
+            result_paths,
+            vec![source_path.join("foo/bar.txt"), source_path.join("baz.txt")]
+        );
+        assert_eq!(stats.unreadable.get(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_source_prefix() {
+        let source_path = PathBuf::from("/path/to/source");
+
+        assert_eq!(
+            strip_source_prefix(&source_path, &PathBuf::from("/path/to/source/foo/bar.txt")),
+            PathBuf::from("foo/bar.txt")
+        );
+
+        // Entries outside the source fall back to their file name
+        assert_eq!(
+            strip_source_prefix(&source_path, &PathBuf::from("/elsewhere/baz.txt")),
+            PathBuf::from("baz.txt")
+        );
+    }
+
+    #[test]
+    fn test_prefix_source_basename() {
+        let source_path = PathBuf::from("/data/project");
+
+        assert_eq!(
+            prefix_source_basename(&source_path, Path::new("src/main.rs")),
+            PathBuf::from("project/src/main.rs")
+        );
+        // A single file source is stored under its own name
+        assert_eq!(
+            prefix_source_basename(Path::new("/data/file.txt"), Path::new("")),
+            PathBuf::from("file.txt")
+        );
+        assert_eq!(
+            prefix_source_basename(Path::new("/"), Path::new("etc/hosts")),
+            PathBuf::from("etc/hosts")
+        );
+    }
+
+    #[test]
+    fn test_tar_padding_size() {
+        // The default 512 byte blocks never need any extra padding
+        assert_eq!(get_tar_padding_size(1536, 512), 0);
+        // A traditional 10240 byte record, with a 3 block archive
+        assert_eq!(get_tar_padding_size(1536, 10240), 8704);
+        assert_eq!(get_tar_padding_size(10240, 10240), 0);
+    }
+
+    #[test]
+    fn test_tarball_encoder_has_no_timestamp() -> Result<()> {
+        let mut encoder = create_tarball_encoder(vec![], None);
+        encoder.write_all(b"foo")?;
+        let tarball_bytes = encoder.finish()?;
+
+        // Bytes 4-7 of a gzip header are the little-endian mtime
+        assert_eq!(tarball_bytes[4..8], [0, 0, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tarball_encoder_time_budget() -> Result<()> {
+        let contents = vec![0; 100_000];
+        let mut compressed_sizes = vec![];
+        for time_budget in [None, Some(0)] {
+            let mut encoder = create_tarball_encoder(vec![], time_budget);
+            encoder.write_all(&contents)?;
+            let tarball_bytes = encoder.finish()?;
+
+            let mut decompressed = vec![];
+            io::Read::read_to_end(
+                &mut flate2::read::MultiGzDecoder::new(&tarball_bytes[..]),
+                &mut decompressed,
+            )?;
+            assert_eq!(decompressed, contents);
+            compressed_sizes.push(tarball_bytes.len());
+        }
+
+        // With no time to spare, the contents are stored uncompressed
+        assert!(compressed_sizes[0] < 1_000);
+        assert!(compressed_sizes[1] > contents.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tarball_walk_mtime() -> Result<()> {
+        let source_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&source_path)?;
+        fs::write(source_path.join("foo"), "foo")?;
+
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = {:?}\ntarget.path = \"/target\"\nretention.hours = 1",
+            source_path
+        ))?;
+        config.options.tar_mtime = ConfigOptsTarMtime::Walk;
+
+        // A walk time older than the file's real mtime
+        let walk_entry = PirouetteDirEntry {
+            path: source_path.join("foo"),
+            timestamp: SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1000),
+        };
+        let stats = PirouetteStats::default();
+        let tarball_bytes = write_tarball(
+            &config,
+            [Ok((walk_entry, PathBuf::from("foo")))].into_iter(),
+            vec![],
+            "test",
+            &stats,
+        );
+        fs::remove_dir_all(&source_path)?;
+
+        let tarball_bytes = tarball_bytes?;
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&tarball_bytes[..]));
+        let archive_mtimes: Vec<u64> = archive
+            .entries()?
+            .map(|entry| entry.unwrap().header().mtime().unwrap())
+            .collect();
+        assert_eq!(archive_mtimes, vec![1000]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_snapshot_parts() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let staging_path = test_path.join(".staging/snapshot.tgz");
+        fs::create_dir_all(test_path.join(".staging"))?;
+        fs::create_dir_all(test_path.join("hours"))?;
+        fs::write(volume::get_volume_path(&staging_path, 1), "01")?;
+        fs::write(volume::get_volume_path(&staging_path, 2), "02")?;
+        fs::write(manifest::get_manifest_path(&staging_path), "manifest")?;
+
+        let snapshot_path = test_path.join("hours/snapshot.tgz");
+        place_snapshot_parts(&staging_path, &snapshot_path, move_snapshot)?;
+        let moved_parts = volume::get_snapshot_parts(&snapshot_path);
+        let has_moved_manifest = manifest::get_manifest_path(&snapshot_path).exists();
+        let staged_parts = volume::get_snapshot_parts(&staging_path);
+
+        fs::remove_dir_all(&test_path)?;
+        assert_eq!(moved_parts.len(), 2);
+        assert!(has_moved_manifest);
+        assert!(staged_parts.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_link_snapshot() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let staging_path = test_path.join(".staging/snapshot");
+        fs::create_dir_all(staging_path.join("foo"))?;
+        fs::write(staging_path.join("foo/bar.txt"), "bar")?;
+        fs::write(staging_path.join("baz.txt"), "baz")?;
+
+        let snapshot_path = test_path.join("hours/snapshot");
+        fs::create_dir_all(test_path.join("hours"))?;
+        link_snapshot(&staging_path, &snapshot_path)?;
+        let link_count = fs::metadata(snapshot_path.join("baz.txt"))?.nlink();
+        remove_snapshot_parts(&staging_path)?;
+
+        let linked_contents = fs::read_to_string(snapshot_path.join("foo/bar.txt"));
+        let staging_exists = staging_path.exists();
+
+        fs::remove_dir_all(&test_path)?;
+
+        assert_eq!(linked_contents?, "bar");
+        assert_eq!(link_count, 2);
+        assert!(!staging_exists);
+        Ok(())
+    }
+
+    #[test]
+    fn test_excluded_names_are_pruned() -> Result<()> {
+        let source_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(source_path.join("a/.cache/deep"))?;
+        fs::write(source_path.join("a/.cache/deep/foo"), "")?;
+        fs::write(source_path.join("a/bar"), "")?;
+        fs::write(source_path.join("node_modules"), "")?;
+
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = {:?}\ntarget.path = \"/target\"\nretention.hours = 1",
+            source_path
+        ))?;
+        config.options.exclude_names = vec![".cache".to_string(), "node_modules".to_string()];
+
+        let stats = PirouetteStats::default();
+        let result_paths: Vec<PathBuf> = get_source_walk_iter(&config, &stats)
+            .map(|entry| format_inner_entry_path(&config, &entry.unwrap()))
+            .collect();
+
+        fs::remove_dir_all(&source_path)?;
+
+        // The pruned ".cache" directory counts once, rather than once per file inside it
+        assert_eq!(result_paths, vec![PathBuf::from("a/bar")]);
+        assert_eq!(stats.filtered.get(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_copy_matches_serial() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let source_path = test_path.join("source");
+        fs::create_dir_all(source_path.join("sub"))?;
+        for i in 0..20 {
+            fs::write(source_path.join(format!("sub/{i}")), i.to_string())?;
+        }
+
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = {test_path:?}\nretention.hours = 1"
+        ))?;
+        config.options.manifest = true;
+        let mut copy_snapshot =
+            |copy_concurrency: usize| -> Result<(Vec<(PathBuf, String)>, usize)> {
+                config.options.copy_concurrency = copy_concurrency;
+                let stats = PirouetteStats::default();
+                let snapshot_path = test_path.join(format!("snapshot_{copy_concurrency}"));
+                let source_contents = get_filtered_source_contents_iter(&config, None, &stats)?;
+                let mut manifest_hashes = match copy_concurrency {
+                    1 => copy_dir_entries(&config, source_contents, &snapshot_path, &stats)?,
+                    _ => copy_dir_entries_concurrently(
+                        &config,
+                        source_contents,
+                        &snapshot_path,
+                        &stats,
+                    )?,
+                };
+                manifest_hashes.sort();
+                Ok((manifest_hashes, stats.copied.get()))
+            };
+
+        let serial_result = copy_snapshot(1);
+        let concurrent_result = copy_snapshot(4);
+        fs::remove_dir_all(&test_path)?;
+
+        let (serial_hashes, serial_count) = serial_result?;
+        assert_eq!(serial_count, 20);
+        assert_eq!(concurrent_result?, (serial_hashes, serial_count));
+        Ok(())
+    }
+
+    #[test]
+    fn test_preserve_hardlinks() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let source_path = test_path.join("source");
+        fs::create_dir_all(source_path.join("sub"))?;
+        fs::write(source_path.join("foo"), "foo")?;
+        fs::hard_link(source_path.join("foo"), source_path.join("sub/bar"))?;
+
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = {test_path:?}\nretention.hours = 1"
+        ))?;
+        config.options.preserve_hardlinks = true;
+        let get_source_contents = || {
+            get_filtered_source_contents_iter(&config, None, &PirouetteStats::default())
+                .map(|source_contents| source_contents.collect::<Vec<_>>().into_iter())
+        };
+
+        let snapshot_path = test_path.join("snapshot");
+        copy_snapshot_to_dir(
+            &config,
+            get_source_contents()?,
+            &snapshot_path,
+            &Default::default(),
+        )?;
+        let snapshot_foo = fs::metadata(snapshot_path.join("foo"))?;
+        let snapshot_bar = fs::metadata(snapshot_path.join("sub/bar"))?;
+
+        let tarball_path = test_path.join("snapshot.tgz");
+        let source_contents = with_stored_entry_paths(&config, get_source_contents()?);
+        let tarball_file = fs::File::create(&tarball_path)?;
+        write_tarball(
+            &config,
+            source_contents,
+            tarball_file,
+            "test",
+            &Default::default(),
+        )?;
+        let mut tarball =
+            tar::Archive::new(flate2::read::GzDecoder::new(fs::File::open(&tarball_path)?));
+        let tarball_types: Vec<(PathBuf, tar::EntryType)> = tarball
+            .entries()?
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (
+                    entry.path().unwrap().into_owned(),
+                    entry.header().entry_type(),
+                )
+            })
+            .collect();
+        fs::remove_dir_all(&test_path)?;
+
+        assert_eq!(snapshot_foo.ino(), snapshot_bar.ino());
+        assert_eq!(tarball_types.len(), 2);
+        assert_eq!(
+            tarball_types
+                .iter()
+                .filter(|(_, entry_type)| *entry_type == tar::EntryType::Link)
+                .count(),
+            1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_special_files() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let source_path = test_path.join("source");
+        fs::create_dir_all(&source_path)?;
+        fs::write(source_path.join("foo"), "foo")?;
+        nix::unistd::mkfifo(&source_path.join("fifo"), nix::sys::stat::Mode::S_IRWXU)?;
+
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = {test_path:?}\nretention.hours = 1"
+        ))?;
+        let skipped_stats = PirouetteStats::default();
+        let skipped_count = get_source_walk_iter(&config, &skipped_stats).count();
+
+        config.options.special_files = ConfigOptsSpecialFiles::Archive;
+        let stats = PirouetteStats::default();
+        let directory_path = test_path.join("snapshot");
+        let tarball_path = test_path.join("snapshot.tgz");
+        write_snapshot(
+            &config,
+            &directory_path,
+            &ConfigOptsOutputFormat::Directory,
+            config.options.dry_run,
+            None,
+            &stats,
+        )?;
+        write_snapshot(
+            &config,
+            &tarball_path,
+            &ConfigOptsOutputFormat::Tarball,
+            config.options.dry_run,
+            None,
+            &stats,
+        )?;
+
+        let is_directory_fifo = fs::symlink_metadata(directory_path.join("fifo"))
+            .map(|metadata| metadata.file_type().is_fifo());
+        let tarball_file = fs::File::open(&tarball_path)?;
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(tarball_file));
+        let mut archive_entries: Vec<(PathBuf, tar::EntryType)> = archive
+            .entries()?
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (
+                    entry.path().unwrap().into_owned(),
+                    entry.header().entry_type(),
+                )
+            })
+            .collect();
+        archive_entries.sort_by_key(|(path, _)| path.clone());
+
+        fs::remove_dir_all(&test_path)?;
+        assert_eq!(skipped_count, 1);
+        assert_eq!(skipped_stats.special_skipped.get(), 1);
+        assert!(is_directory_fifo?);
+        assert_eq!(
+            archive_entries,
+            vec![
+                (PathBuf::from("fifo"), tar::EntryType::Fifo),
+                (PathBuf::from("foo"), tar::EntryType::Regular),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_after_copy() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&test_path)?;
+        fs::write(test_path.join("foo"), "foo")?;
+
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = {test_path:?}\ntarget.path = {test_path:?}\nretention.hours = 1"
+        ))?;
+        config.options.verify_after_copy = true;
+        let unrecorded_hash = copy_dir_entry(&config, &test_path.join("foo"), &test_path.join("a"));
+        config.options.manifest = true;
+        let recorded_hash = copy_dir_entry(&config, &test_path.join("foo"), &test_path.join("b"));
+        let copied_contents = fs::read_to_string(test_path.join("a"));
+
+        fs::remove_dir_all(&test_path)?;
+        // Verification alone doesn't add anything to the manifest
+        assert_eq!(unrecorded_hash?, None);
+        assert!(recorded_hash?.is_some());
+        assert_eq!(copied_contents?, "foo");
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_time_from_source_mtime() -> Result<()> {
+        let source_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&source_path)?;
+        let newest_mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(2_000_000_000);
+        for (name, mtime) in [("old", SystemTime::UNIX_EPOCH), ("new", newest_mtime)] {
+            fs::File::create(source_path.join(name))?.set_modified(mtime)?;
+        }
+
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = \"/target\"\nretention.hours = 1"
+        ))?;
+        config.options.snapshot_time = ConfigOptsSnapshotTime::SourceMtime;
+        let snapshot_time = get_snapshot_time(&config);
+
+        fs::remove_dir_all(&source_path)?;
+        assert_eq!(SystemTime::from(snapshot_time?), newest_mtime);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_throughput() {
+        assert_eq!(
+            format_throughput(512 * 1024 * 1024, Duration::from_secs(4)),
+            "512.0 MB in 4.0s (128.0 MB/s)"
+        );
+        assert_eq!(
+            format_throughput(1024 * 1024, Duration::ZERO),
+            "1.0 MB in 0.0s (0.0 MB/s)"
+        );
+    }
+
+    #[test]
+    fn test_is_modified_after() {
+        let cutoff = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let get_entry = |seconds: u64| PirouetteDirEntry {
+            path: PathBuf::from("/tmp/fake"),
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(seconds),
+        };
+
+        // A file modified exactly at the cutoff is old enough to keep
+        assert!(!is_modified_after(&get_entry(999), Some(cutoff)));
+        assert!(!is_modified_after(&get_entry(1000), Some(cutoff)));
+        assert!(is_modified_after(&get_entry(1001), Some(cutoff)));
+        assert!(!is_modified_after(&get_entry(1001), None));
+    }
+
+    #[test]
+    fn test_single_file_source() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let source_path = test_path.join("app.log");
+        fs::create_dir_all(&test_path)?;
+        fs::write(&source_path, "foo")?;
+
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = {test_path:?}\nretention.hours = 1"
+        ))?;
+        config.options.include_source_basename = true;
+
+        let directory_path = test_path.join("snapshot");
+        let tarball_path = test_path.join("snapshot.tgz");
+        let stats = PirouetteStats::default();
+        write_snapshot(
+            &config,
+            &directory_path,
+            &ConfigOptsOutputFormat::Directory,
+            config.options.dry_run,
+            None,
+            &stats,
+        )?;
+        write_snapshot(
+            &config,
+            &tarball_path,
+            &ConfigOptsOutputFormat::Tarball,
+            config.options.dry_run,
+            None,
+            &stats,
+        )?;
+
+        let directory_contents = fs::read_to_string(directory_path.join("app.log"));
+        let tarball_file = fs::File::open(&tarball_path)?;
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(tarball_file));
+        let archive_paths: Vec<PathBuf> = archive
+            .entries()?
+            .map(|entry| entry.unwrap().path().unwrap().into_owned())
+            .collect();
+
+        fs::remove_dir_all(&test_path)?;
+        assert_eq!(directory_contents?, "foo");
+        assert_eq!(archive_paths, vec![PathBuf::from("app.log")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_oversized_dirs_are_skipped() -> Result<()> {
+        let source_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(source_path.join("few"))?;
+        fs::create_dir_all(source_path.join("many"))?;
+        for i in 0..5 {
+            fs::write(source_path.join(format!("many/{i}")), "")?;
+            fs::write(source_path.join(format!("{i}")), "")?;
+        }
+        fs::write(source_path.join("few/foo"), "")?;
+
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = {:?}\ntarget.path = \"/target\"\nretention.hours = 1",
+            source_path
+        ))?;
+        config.options.max_dir_entries = Some(3);
+
+        let mut result_paths: Vec<PathBuf> =
+            get_source_walk_iter(&config, &PirouetteStats::default())
+                .map(|entry| format_inner_entry_path(&config, &entry.unwrap()))
+                .collect();
+        result_paths.sort();
+
+        fs::remove_dir_all(&source_path)?;
+
+        // The source root itself is never skipped, even though it's over the limit
+        assert_eq!(
+            result_paths,
+            ["0", "1", "2", "3", "4", "few/foo"]
+                .iter()
+                .map(PathBuf::from)
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_walk_yields_errors() -> Result<()> {
+        // The walk fails immediately on a source which doesn't exist
+        let source_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = {:?}\ntarget.path = \"/target\"\nretention.hours = 1",
+            source_path
+        ))?;
+
+        let stats = PirouetteStats::default();
+        let lenient_count = get_source_walk_iter(&config, &stats).count();
+        config.options.strict_walk = true;
+        let strict_results: Vec<_> =
+            get_source_walk_iter(&config, &PirouetteStats::default()).collect();
+
+        assert_eq!(lenient_count, 0);
+        assert_eq!(stats.unreadable.get(), 1);
+        assert_eq!(strict_results.len(), 1);
+        let strict_error = strict_results[0]
+            .as_ref()
+            .unwrap_err()
+            .to_string();
+        assert!(strict_error.contains(&source_path.display().to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_follow_symlinks_skips_loops() -> Result<()> {
+        let source_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(source_path.join("sub"))?;
+        fs::write(source_path.join("sub/a.txt"), "a")?;
+        std::os::unix::fs::symlink("..", source_path.join("sub/loop"))?;
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = \"/target\"\nretention.hours = 1"
+        ))?;
+        config.options.follow_symlinks = true;
+        config.options.strict_walk = true;
+
+        // Even with `strict_walk`, the loop is skipped rather than failing the walk
+        let stats = PirouetteStats::default();
+        let walk_results: Vec<PathBuf> = get_source_walk_iter(&config, &stats)
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+
+        fs::remove_dir_all(&source_path)?;
+        assert_eq!(walk_results, vec![source_path.join("sub/a.txt")]);
+        assert_eq!(stats.unreadable.get(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_vanished() -> Result<()> {
+        let source_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&source_path)?;
+        let source_device = fs::metadata(&source_path)?.dev();
+
+        let unchanged = is_source_vanished(&source_path, Some(source_device));
+        let remounted = is_source_vanished(&source_path, Some(source_device + 1));
+        fs::remove_dir_all(&source_path)?;
+        let removed = is_source_vanished(&source_path, Some(source_device));
+
+        let config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = \"/target\"\nretention.hours = 1"
+        ))?;
+        let stats = PirouetteStats::default();
+        let removed_result = get_source_contents_iter(&config, &stats);
+
+        assert!(!unchanged);
+        assert!(remounted);
+        assert!(removed);
+        assert!(removed_result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_target_inodes_checked() -> Result<()> {
+        let source_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(source_path.join("sub"))?;
+        fs::write(source_path.join("sub/a.txt"), "a")?;
+        let config: Config = toml::from_str(&format!(
+            "source.path = {:?}\ntarget.path = \"/target\"\nretention.hours = 1",
+            source_path
+        ))?;
+
+        // The snapshot path doesn't exist yet, so its nearest existing parent is checked
+        let snapshot_path = env::temp_dir().join("pirouette_missing/2025-01-01T00:00");
+        let inode_result = check_target_inodes(&config, None, &snapshot_path);
+
+        fs::remove_dir_all(&source_path)?;
+        inode_result
+    }
+
+    #[test]
+    fn test_preserve_metadata() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let source_path = test_path.join("source");
+        fs::create_dir_all(source_path.join("sub"))?;
+        fs::write(source_path.join("sub/a.txt"), "a")?;
+        let old_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        fs::File::open(source_path.join("sub/a.txt"))?.set_modified(old_time)?;
+        fs::set_permissions(source_path.join("sub"), fs::Permissions::from_mode(0o550))?;
+        fs::File::open(source_path.join("sub"))?.set_modified(old_time)?;
+
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = {test_path:?}\nretention.hours = 1"
+        ))?;
+        config.options.preserve_metadata = true;
+        let snapshot_path = test_path.join("snapshot");
+        let snapshot_result = write_snapshot(
+            &config,
+            &snapshot_path,
+            &ConfigOptsOutputFormat::Directory,
+            false,
+            None,
+            &PirouetteStats::default(),
+        );
+        let dir_metadata = fs::metadata(snapshot_path.join("sub"));
+        let file_metadata = fs::metadata(snapshot_path.join("sub/a.txt"));
+
+        fs::set_permissions(source_path.join("sub"), fs::Permissions::from_mode(0o750))?;
+        fs::remove_dir_all(&test_path)?;
+        snapshot_result?;
+        let dir_metadata = dir_metadata?;
+        // Owner write is kept, so the snapshot can still be deleted
+        assert_eq!(dir_metadata.permissions().mode() & 0o7777, 0o750);
+        assert_eq!(dir_metadata.modified()?, old_time);
+        assert_eq!(file_metadata?.modified()?, old_time);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preserve_read_only_directory() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let source_path = test_path.join("source");
+        fs::create_dir_all(source_path.join("sub"))?;
+        fs::write(source_path.join("sub/a.txt"), "a")?;
+        fs::set_permissions(source_path.join("sub"), fs::Permissions::from_mode(0o400))?;
+
+        let mut config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = {test_path:?}\nretention.hours = 1"
+        ))?;
+        config.options.preserve_metadata = true;
+        let snapshot_path = test_path.join("snapshot");
+        let snapshot_result = write_snapshot(
+            &config,
+            &snapshot_path,
+            &ConfigOptsOutputFormat::Directory,
+            false,
+            None,
+            &PirouetteStats::default(),
+        );
+        let dir_metadata = fs::metadata(snapshot_path.join("sub"));
+        let is_restorable = fs::read_to_string(snapshot_path.join("sub/a.txt")).is_ok();
+
+        fs::set_permissions(source_path.join("sub"), fs::Permissions::from_mode(0o700))?;
+        fs::remove_dir_all(&test_path)?;
+        snapshot_result?;
+        assert!(is_restorable);
+        // The owner can still traverse it, so it can be restored from, and deleted
+        assert_eq!(dir_metadata?.permissions().mode() & 0o7777, 0o700);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_already_copied() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&test_path)?;
+        let old_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        for name in ["source", "copied", "stale", "partial"] {
+            fs::write(test_path.join(name), "foo")?;
+        }
+        fs::write(test_path.join("partial"), "f")?;
+        fs::File::open(test_path.join("stale"))?.set_modified(old_time)?;
+
+        let is_copied =
+            |name: &str| is_already_copied(&test_path.join("source"), &test_path.join(name));
+        let results = [
+            is_copied("copied"),
+            is_copied("stale"),
+            is_copied("partial"),
+            is_copied("missing"),
+        ];
+
+        fs::remove_dir_all(&test_path)?;
+        assert_eq!(results, [true, false, false, false]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_snapshot() -> Result<()> {
+        let snapshot_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(snapshot_path.join("sub"))?;
+        fs::write(snapshot_path.join("sub/a.txt"), "a")?;
+        let tarball_path = snapshot_path.with_extension("tgz");
+        fs::write(&tarball_path, "")?;
+
+        // Both directory and single-file snapshots can be synced
+        let sync_results = [sync_snapshot(&snapshot_path), sync_snapshot(&tarball_path)];
+
+        fs::remove_dir_all(&snapshot_path)?;
+        fs::remove_file(&tarball_path)?;
+        for sync_result in sync_results {
+            sync_result?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,99 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+/*
+    Local time comes from `TZ`, or else "/etc/localtime". Stripped-down images, eg: distroless,
+    may have neither, or no zoneinfo for the zone `TZ` names.
+*/
+
+const LOCALTIME_PATH: &str = "/etc/localtime";
+const ZONEINFO_DIRECTORIES: [&str; 3] = ["/usr/share/zoneinfo", "/share/zoneinfo", "/etc/zoneinfo"];
+
+// Without timezone data, snapshot names and log times would silently drift from local time,
+// so UTC is made explicit instead. Must run before anything reads the local time, ie: the logger.
+// Returns a warning to log once the logger is initialised.
+pub fn fall_back_to_utc_if_missing() -> Option<String> {
+    let tz = env::var("TZ").ok();
+    let mut zoneinfo_directories: Vec<PathBuf> = env::var_os("TZDIR")
+        .map(PathBuf::from)
+        .into_iter()
+        .collect();
+    zoneinfo_directories.extend(ZONEINFO_DIRECTORIES.iter().map(PathBuf::from));
+
+    let missing_reason = find_missing_timezone_data(
+        tz.as_deref(),
+        Path::new(LOCALTIME_PATH),
+        &zoneinfo_directories,
+    )?;
+
+    // SAFETY: called at startup, before any other threads are spawned
+    unsafe { env::set_var("TZ", "UTC") };
+    Some(format!("{missing_reason}, so all times are in UTC"))
+}
+
+fn find_missing_timezone_data(
+    tz: Option<&str>,
+    localtime_path: &Path,
+    zoneinfo_directories: &[PathBuf],
+) -> Option<String> {
+    let Some(tz) = tz.filter(|tz| !tz.is_empty()) else {
+        return match localtime_path.exists() {
+            true => None,
+            false => Some(format!("TZ isn't set and {localtime_path:?} doesn't exist")),
+        };
+    };
+
+    let zone = tz.strip_prefix(':').unwrap_or(tz);
+    // A POSIX rule, eg: "EST5EDT", carries its own offsets, and UTC needs no data at all
+    if zone.chars().any(|c| c.is_ascii_digit()) || matches!(zone, "UTC" | "GMT") {
+        return None;
+    }
+
+    let has_zoneinfo = match Path::new(zone).is_absolute() {
+        true => Path::new(zone).exists(),
+        false => zoneinfo_directories
+            .iter()
+            .any(|directory| directory.join(zone).exists()),
+    };
+    match has_zoneinfo {
+        true => None,
+        false => Some(format!("No timezone data was found for TZ={tz:?}")),
+    }
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_find_missing_timezone_data() -> anyhow::Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let zoneinfo_directory = test_path.join("zoneinfo");
+        fs::create_dir_all(zoneinfo_directory.join("Europe"))?;
+        fs::write(zoneinfo_directory.join("Europe/London"), "")?;
+        fs::write(test_path.join("localtime"), "")?;
+        let zoneinfo_directories = vec![zoneinfo_directory];
+
+        let find_missing = |tz: Option<&str>, localtime_name: &str| {
+            find_missing_timezone_data(tz, &test_path.join(localtime_name), &zoneinfo_directories)
+        };
+        let localtime_result = find_missing(None, "localtime");
+        let no_localtime_result = find_missing(Some(""), "missing");
+        let zone_result = find_missing(Some(":Europe/London"), "missing");
+        let missing_zone_result = find_missing(Some("Europe/Paris"), "localtime");
+        let posix_result = find_missing(Some("EST5EDT"), "missing");
+
+        fs::remove_dir_all(&test_path)?;
+        assert_eq!(localtime_result, None);
+        assert!(no_localtime_result.is_some_and(|reason| reason.starts_with("TZ isn't set")));
+        assert_eq!(zone_result, None);
+        assert!(missing_zone_result.is_some_and(|reason| reason.contains("Europe/Paris")));
+        assert_eq!(posix_result, None);
+        Ok(())
+    }
+}
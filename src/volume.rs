@@ -0,0 +1,166 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/*
+    With `split_size`, a Tarball snapshot is written as numbered volumes, eg: "2024-01-01T00:00.tgz.001".
+    Concatenating them in order gives back the original tarball, eg: `cat <snapshot>.tgz.* | tar xz`.
+    The first volume stands in for the whole snapshot, and the rest follow it around.
+*/
+
+const FIRST_VOLUME: usize = 1;
+
+pub fn get_volume_path(snapshot_path: &Path, volume_number: usize) -> PathBuf {
+    let mut volume_path = snapshot_path.as_os_str().to_owned();
+    volume_path.push(format!(".{volume_number:03}"));
+    volume_path.into()
+}
+
+// The volume number, if `path` looks like "<snapshot>.<tarball_extension>.NNN". Any extension
+// is accepted here, so old volumes still rotate out together after `tarball_extension` changes,
+// but as for a whole tarball, `layout::get_snapshot_format` only counts the current one.
+fn parse_volume_number(path: &Path) -> Option<usize> {
+    let extension = path.extension()?.to_str()?;
+    let is_tarball_volume = path
+        .file_stem()
+        .and_then(|stem| Path::new(stem).extension())
+        .is_some();
+
+    match is_tarball_volume && extension.chars().all(|c| c.is_ascii_digit()) {
+        true => extension.parse().ok(),
+        false => None,
+    }
+}
+
+// Every volume after the first is hidden from listings, so a snapshot is only counted once
+pub fn is_extra_volume_path(path: &Path) -> bool {
+    parse_volume_number(path).is_some_and(|volume_number| volume_number != FIRST_VOLUME)
+}
+
+// A split tarball is named by its first volume, eg: "<snapshot>.tgz.001" -> "<snapshot>.tgz"
+pub fn get_base_path(snapshot_path: &Path) -> PathBuf {
+    match parse_volume_number(snapshot_path) {
+        Some(FIRST_VOLUME) => snapshot_path.with_extension(""),
+        _ => snapshot_path.to_path_buf(),
+    }
+}
+
+// Every file or directory which makes up a snapshot, given its path or its first volume
+pub fn get_snapshot_parts(snapshot_path: &Path) -> Vec<PathBuf> {
+    let base_path = get_base_path(snapshot_path);
+    if base_path.exists() {
+        return vec![base_path];
+    }
+
+    (FIRST_VOLUME..)
+        .map(|volume_number| get_volume_path(&base_path, volume_number))
+        .take_while(|volume_path| volume_path.exists())
+        .collect()
+}
+
+// Starts a new volume file whenever the current one reaches `split_size` bytes
+pub struct SplitWriter {
+    snapshot_path: PathBuf,
+    split_size: u64,
+    volume_count: usize,
+    volume: Option<fs::File>,
+    volume_written: u64,
+}
+
+impl SplitWriter {
+    pub fn new(snapshot_path: &Path, split_size: u64) -> Self {
+        SplitWriter {
+            snapshot_path: snapshot_path.to_path_buf(),
+            split_size,
+            volume_count: 0,
+            volume: None,
+            volume_written: 0,
+        }
+    }
+
+    fn create_next_volume(&mut self) -> io::Result<fs::File> {
+        self.volume_count += 1;
+        self.volume_written = 0;
+        let volume_path = get_volume_path(&self.snapshot_path, self.volume_count);
+        log::debug!("Starting tarball volume {volume_path:?}");
+
+        fs::File::create(volume_path)
+    }
+}
+
+impl Write for SplitWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut volume = match self.volume.take() {
+            Some(volume) if self.volume_written < self.split_size => volume,
+            _ => self.create_next_volume()?,
+        };
+
+        let volume_remaining = (self.split_size - self.volume_written) as usize;
+        let written = volume.write(&buf[..buf.len().min(volume_remaining)]);
+        self.volume = Some(volume);
+
+        let written = written?;
+        self.volume_written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.volume {
+            Some(ref mut volume) => volume.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::env;
+
+    #[test]
+    fn test_volume_paths() {
+        let snapshot_path = Path::new("/target/days/2024-01-01T00:00.tgz");
+        let first_volume = get_volume_path(snapshot_path, 1);
+        let second_volume = get_volume_path(snapshot_path, 2);
+
+        assert_eq!(
+            first_volume,
+            PathBuf::from("/target/days/2024-01-01T00:00.tgz.001")
+        );
+        assert!(!is_extra_volume_path(&first_volume));
+        assert!(is_extra_volume_path(&second_volume));
+        assert!(!is_extra_volume_path(snapshot_path));
+        assert_eq!(get_base_path(&first_volume), snapshot_path);
+        assert_eq!(get_base_path(snapshot_path), snapshot_path);
+        assert!(!is_extra_volume_path(Path::new("/target/days/backup.002")));
+    }
+
+    #[test]
+    fn test_split_writer() -> Result<()> {
+        let directory = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        fs::create_dir_all(&directory)?;
+        let snapshot_path = directory.join("2024-01-01T00:00.tgz");
+
+        let mut split_writer = SplitWriter::new(&snapshot_path, 4);
+        split_writer.write_all(b"0123456789")?;
+        split_writer.flush()?;
+
+        // Parts can be found from either the snapshot path, or its first volume
+        let parts = get_snapshot_parts(&snapshot_path);
+        let parts_from_volume = get_snapshot_parts(&get_volume_path(&snapshot_path, 1));
+        let contents: Vec<String> = parts
+            .iter()
+            .map(|part| fs::read_to_string(part).unwrap())
+            .collect();
+
+        fs::remove_dir_all(&directory)?;
+        assert_eq!(parts, parts_from_volume);
+        assert_eq!(contents, vec!["0123", "4567", "89"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::PirouetteDirEntry;
+use crate::configuration::Config;
+use crate::manifest;
+
+/*
+    With `maintain_working_copy`, an extracted mirror of the newest Tarball snapshot is kept
+    in "<target>/.working", for quick restores without unpacking an archive.
+*/
+
+const WORKING_COPY_DIRECTORY: &str = ".working";
+
+// Bring the working copy in line with the entries just archived. Files are compared by hash,
+// so only changed files are rewritten, and anything no longer in the snapshot is removed.
+pub fn update_working_copy(
+    config: &Config,
+    archived_entries: &[(PirouetteDirEntry, PathBuf)],
+) -> Result<()> {
+    let working_copy_path = config.target.path.join(WORKING_COPY_DIRECTORY);
+    let mut working_paths = HashSet::new();
+    let mut changed_count = 0;
+
+    for (entry, inner_entry_path) in archived_entries {
+        let working_path = working_copy_path.join(inner_entry_path);
+        if !is_working_file_current(&entry.path, &working_path)? {
+            log::debug!("Updating working copy of {:?}", entry.path);
+            if let Some(parent) = working_path.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to create directory {parent:?}"))?;
+            }
+            fs::copy(&entry.path, &working_path)
+                .with_context(|| format!("failed to copy file {:?}", &entry.path))?;
+            changed_count += 1;
+        }
+        working_paths.insert(working_path);
+    }
+
+    let removed_count = remove_stale_working_files(&working_copy_path, &working_paths)?;
+    log::info!(
+        "Working copy {working_copy_path:?} updated: {changed_count} files changed, {removed_count} removed"
+    );
+    Ok(())
+}
+
+fn is_working_file_current(source_path: &Path, working_path: &Path) -> Result<bool> {
+    if !working_path.is_file() {
+        return Ok(false);
+    }
+
+    Ok(manifest::hash_file(source_path)? == manifest::hash_file(working_path)?)
+}
+
+fn remove_stale_working_files(
+    working_copy_path: &Path,
+    working_paths: &HashSet<PathBuf>,
+) -> Result<usize> {
+    let stale_paths: Vec<PathBuf> = WalkDir::new(working_copy_path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| !entry.file_type().is_dir())
+        .map(|entry| entry.into_path())
+        .filter(|path| !working_paths.contains(path))
+        .collect();
+
+    for stale_path in &stale_paths {
+        log::debug!("Removing {stale_path:?} from the working copy");
+        fs::remove_file(stale_path).with_context(|| format!("failed to remove {stale_path:?}"))?;
+    }
+
+    Ok(stale_paths.len())
+}
+
+/*
+    Unit tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_update_working_copy() -> Result<()> {
+        let test_path = env::temp_dir().join(format!("pirouette_{}", rand::random::<u32>()));
+        let source_path = test_path.join("source");
+        let target_path = test_path.join("target");
+        fs::create_dir_all(&source_path)?;
+        fs::write(source_path.join("foo"), "foo")?;
+        fs::write(source_path.join("bar"), "bar")?;
+
+        let config: Config = toml::from_str(&format!(
+            "source.path = {source_path:?}\ntarget.path = {target_path:?}\nretention.hours = 1"
+        ))?;
+        let get_archived_entries = |names: &[&str]| -> Vec<(PirouetteDirEntry, PathBuf)> {
+            names
+                .iter()
+                .map(|name| (source_path.join(name).into(), PathBuf::from(name)))
+                .collect()
+        };
+
+        update_working_copy(&config, &get_archived_entries(&["foo", "bar"]))?;
+        fs::write(source_path.join("foo"), "changed")?;
+        update_working_copy(&config, &get_archived_entries(&["foo"]))?;
+
+        let working_copy_path = target_path.join(WORKING_COPY_DIRECTORY);
+        let working_foo = fs::read_to_string(working_copy_path.join("foo"));
+        let has_working_bar = working_copy_path.join("bar").exists();
+
+        fs::remove_dir_all(&test_path)?;
+        assert_eq!(working_foo?, "changed");
+        assert!(!has_working_bar);
+        Ok(())
+    }
+}